@@ -0,0 +1,50 @@
+extern crate codecophony;
+
+use codecophony::*;
+use codecophony::resampling::HIGH_QUALITY_ZERO_CROSSINGS;
+
+// Resamples sine tones from one sample rate to another with each kind of resampling,
+// and reports how loud each one comes out, relative to how loud it should be.
+// Tones below both Nyquist frequencies should come out at 0 dB.
+// Tones above the output's Nyquist frequency can't be represented, so anything that comes out is aliasing.
+
+type Frame = [f32; 1];
+
+fn output_level (frequency: f64, source_hz: f64, sample_hz: f64, resampling: Resampling)->f64 {
+  let tone = SineWave::new (0.0, 0.3, frequency, 0.5);
+  let source = PositionedSequence::<Frame, Vec<Frame>>::rendered_from (&tone, source_hz).with_resampling (resampling);
+  let output = PositionedSequence::<Frame, Vec<Frame>>::rendered_from (&source, sample_hz);
+
+  // stay away from the fades at the ends
+  let first = (0.1*sample_hz) as usize;
+  let last = (0.2*sample_hz) as usize;
+  let power: f64 = output.frames [first..last].iter().map (| frame | (frame [0] as f64)*(frame [0] as f64)).sum::<f64>()/(last - first) as f64;
+  let expected_power = 0.5*0.5/2.0;
+  10.0*(power/expected_power).log10()
+}
+
+fn sweep (source_hz: f64, sample_hz: f64) {
+  let methods = [
+    ("linear", Resampling::Linear),
+    ("windowed sinc", Resampling::WindowedSinc {zero_crossings: HIGH_QUALITY_ZERO_CROSSINGS}),
+    ("polyphase", Resampling::Polyphase {zero_crossings: HIGH_QUALITY_ZERO_CROSSINGS}),
+  ];
+  println!("{} Hz to {} Hz", source_hz, sample_hz);
+  println!("{:>10} {:>15} {:>15} {:>15}", "tone (Hz)", methods [0].0, methods [1].0, methods [2].0);
+  let nyquist = source_hz.min (sample_hz)/2.0;
+  let mut frequency = 1000.0;
+  while frequency < source_hz/2.0 {
+    let levels: Vec<f64> = methods.iter().map (| &(_, resampling) | output_level (frequency, source_hz, sample_hz, resampling)).collect();
+    println!("{:>10} {:>12.2} dB {:>12.2} dB {:>12.2} dB{}", frequency, levels [0], levels [1], levels [2],
+      if frequency > nyquist { "  (should be silent)" } else { "" });
+    frequency += if frequency < 18000.0 { 3000.0 } else { 500.0 };
+  }
+  println!();
+}
+
+fn main() {
+  sweep (48000.0, 44100.0);
+  sweep (44100.0, 48000.0);
+  // an irrational-looking ratio, where polyphase falls back to windowed sinc
+  sweep (48000.0, 44099.5);
+}
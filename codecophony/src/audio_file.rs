@@ -0,0 +1,344 @@
+use super::*;
+
+use std::io::{self, Write, BufWriter};
+use std::fs::File;
+use std::path::Path;
+use rand::{Rng, SeedableRng};
+use dsp::sample::{ToSample, FromSample};
+
+
+#[derive (Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum WavFormat {
+  Int16,
+  Int24,
+  Float32,
+}
+
+impl WavFormat {
+  fn bytes_per_sample (&self)->usize {
+    match *self {
+      WavFormat::Int16 => 2,
+      WavFormat::Int24 => 3,
+      WavFormat::Float32 => 4,
+    }
+  }
+}
+
+/// Stored in the file's LIST/INFO chunk, which most players understand.
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug, Default)]
+pub struct WavMetadata {
+  pub title: Option<String>,
+  pub artist: Option<String>,
+  pub album: Option<String>,
+  pub date: Option<String>,
+  pub comment: Option<String>,
+}
+
+impl WavMetadata {
+  fn entries (&self)->Vec<(&'static [u8; 4], &str)> {
+    let fields = [(b"INAM", &self.title), (b"IART", &self.artist), (b"IPRD", &self.album), (b"ICRD", &self.date), (b"ICMT", &self.comment)];
+    fields.iter().filter_map (| &(id, value) | value.as_ref().map (| value | (id, &value [..]))).collect()
+  }
+}
+
+#[derive (Clone, Debug)]
+pub struct WavParameters {
+  pub format: WavFormat,
+  /// Adds triangular (TPDF) dither before rounding to integers, which turns quantization distortion into a little steady noise.
+  /// It has no effect on float files.
+  pub dither: bool,
+  pub seed: u32,
+  pub metadata: WavMetadata,
+  // how much audio to render and write at a time
+  pub chunk_duration: NoteTime,
+}
+
+impl Default for WavParameters {
+  fn default()->Self {
+    WavParameters {
+      format: WavFormat::Int16,
+      dither: true,
+      seed: 0,
+      metadata: WavMetadata::default(),
+      chunk_duration: 10.0,
+    }
+  }
+}
+
+fn push_u16 (bytes: &mut Vec<u8>, value: u16) {
+  bytes.push (value as u8);
+  bytes.push ((value >> 8) as u8);
+}
+fn push_u32 (bytes: &mut Vec<u8>, value: u32) {
+  push_u16 (bytes, value as u16);
+  push_u16 (bytes, (value >> 16) as u16);
+}
+
+fn info_chunk (metadata: &WavMetadata)->Vec<u8> {
+  let entries = metadata.entries();
+  if entries.is_empty() { return Vec::new(); }
+  let mut info = b"INFO".to_vec();
+  for (id, value) in entries {
+    // null-terminated, padded to an even length
+    let size = value.len() + 1;
+    info.extend_from_slice (id);
+    push_u32 (&mut info, size as u32);
+    info.extend_from_slice (value.as_bytes());
+    info.push (0);
+    if size % 2 == 1 { info.push (0); }
+  }
+  let mut chunk = b"LIST".to_vec();
+  push_u32 (&mut chunk, info.len() as u32);
+  chunk.extend (info);
+  chunk
+}
+
+fn header (channels: usize, sample_hz: u32, length: usize, parameters: &WavParameters)->io::Result<Vec<u8>> {
+  let bytes_per_sample = parameters.format.bytes_per_sample();
+  let float = parameters.format == WavFormat::Float32;
+  let data_size = length as u64*channels as u64*bytes_per_sample as u64;
+  let info = info_chunk (&parameters.metadata);
+  let format_size = if float {18} else {16};
+  let fact_size = if float {12} else {0};
+  let riff_size = 4 + 8 + format_size + fact_size + info.len() as u64 + 8 + data_size + data_size % 2;
+  if riff_size > u32::max_value() as u64 {
+    return Err(io::Error::new (io::ErrorKind::InvalidInput, "too much audio for a WAV file"));
+  }
+
+  let mut bytes = b"RIFF".to_vec();
+  push_u32 (&mut bytes, riff_size as u32);
+  bytes.extend_from_slice (b"WAVEfmt ");
+  push_u32 (&mut bytes, format_size as u32);
+  push_u16 (&mut bytes, if float {3} else {1});
+  push_u16 (&mut bytes, channels as u16);
+  push_u32 (&mut bytes, sample_hz);
+  push_u32 (&mut bytes, sample_hz*(channels*bytes_per_sample) as u32);
+  push_u16 (&mut bytes, (channels*bytes_per_sample) as u16);
+  push_u16 (&mut bytes, (bytes_per_sample*8) as u16);
+  if float {
+    push_u16 (&mut bytes, 0);
+    bytes.extend_from_slice (b"fact");
+    push_u32 (&mut bytes, 4);
+    push_u32 (&mut bytes, length as u32);
+  }
+  bytes.extend (info);
+  bytes.extend_from_slice (b"data");
+  push_u32 (&mut bytes, data_size as u32);
+  Ok(bytes)
+}
+
+// Turns frames into bytes, keeping the dither going from one chunk to the next.
+struct Encoder {
+  format: WavFormat,
+  dither: Option<rand::chacha::ChaChaRng>,
+  clipped: usize,
+}
+
+impl Encoder {
+  fn new (parameters: &WavParameters)->Encoder {
+    Encoder {
+      format: parameters.format,
+      dither: if parameters.dither {Some(rand::chacha::ChaChaRng::from_seed(&[parameters.seed]))} else {None},
+      clipped: 0,
+    }
+  }
+
+  fn integer (&mut self, sample: f64, maximum: f64)->i32 {
+    let dither = match self.dither {
+      Some(ref mut generator) => generator.gen::<f64>() - generator.gen::<f64>(),
+      None => 0.0,
+    };
+    let value = (sample*maximum + dither).round();
+    if value > maximum || value < -maximum - 1.0 {
+      self.clipped += 1;
+    }
+    value.max (-maximum - 1.0).min (maximum) as i32
+  }
+
+  fn encode <Frame: dsp::Frame> (&mut self, frames: &[Frame], bytes: &mut Vec<u8>)
+      where Frame::Sample: ToSample<f64> {
+    for frame in frames {
+      for sample in frame.channels() {
+        let sample: f64 = sample.to_sample();
+        match self.format {
+          WavFormat::Int16 => push_u16 (bytes, self.integer (sample, 32767.0) as u16),
+          WavFormat::Int24 => {
+            let value = self.integer (sample, 8388607.0);
+            bytes.extend_from_slice (&[value as u8, (value >> 8) as u8, (value >> 16) as u8]);
+          },
+          WavFormat::Float32 => push_u32 (bytes, (sample as f32).to_bits()),
+        }
+      }
+    }
+  }
+
+  fn finish <W: Write> (&self, output: &mut W, data_bytes: usize)->io::Result<()> {
+    if data_bytes % 2 == 1 {
+      output.write_all (&[0])?;
+    }
+    output.flush()?;
+    if self.clipped > 0 {
+      printlnerr!("codecophony: {} samples clipped while writing WAV; consider mastering::master() first", self.clipped);
+    }
+    Ok(())
+  }
+}
+
+/// Writes the frames as a WAV file.
+pub fn write_wav <Frame: dsp::Frame, W: Write> (output: &mut W, frames: &[Frame], sample_hz: f64, parameters: &WavParameters)->io::Result<()>
+    where Frame::Sample: ToSample<f64> {
+  output.write_all (&header (Frame::n_channels(), sample_hz.round() as u32, frames.len(), parameters)?)?;
+  let mut encoder = Encoder::new (parameters);
+  let chunk_length = max(1, (parameters.chunk_duration*sample_hz) as usize);
+  let mut bytes = Vec::new();
+  for chunk in frames.chunks (chunk_length) {
+    bytes.clear();
+    encoder.encode (chunk, &mut bytes);
+    output.write_all (&bytes)?;
+  }
+  encoder.finish (output, frames.len()*Frame::n_channels()*parameters.format.bytes_per_sample())
+}
+
+/// Renders the notes a chunk at a time and writes them as a WAV file, so long pieces never have to be in memory all at once.
+/// Like `PositionedSequence::rendered_from`, the file starts when the notes do, not at time 0.
+pub fn export_wav <Frame: dsp::Frame, N: Renderable<Frame> + ?Sized, P: AsRef<Path>> (notes: &N, sample_hz: f64, path: P, parameters: &WavParameters)->io::Result<()>
+    where Frame::Sample: ToSample<f64> {
+  let earliest = (notes.start()*sample_hz).ceil() as FrameTime;
+  let latest = (notes.end()*sample_hz).floor() as FrameTime;
+  let length = max(0,latest+1-earliest) as usize;
+  let mut output = BufWriter::new (File::create (path)?);
+  output.write_all (&header (Frame::n_channels(), sample_hz.round() as u32, length, parameters)?)?;
+
+  let mut encoder = Encoder::new (parameters);
+  let chunk_length = max(1, (parameters.chunk_duration*sample_hz) as usize);
+  let mut frames = Vec::with_capacity (min(length, chunk_length));
+  let mut bytes = Vec::new();
+  let mut chunk_start = 0;
+  while chunk_start < length {
+    frames.clear();
+    frames.resize (min(length - chunk_start, chunk_length), Frame::equilibrium());
+    notes.render (&mut frames, earliest + chunk_start as FrameTime, sample_hz);
+    bytes.clear();
+    encoder.encode (&frames, &mut bytes);
+    output.write_all (&bytes)?;
+    chunk_start += frames.len();
+  }
+  encoder.finish (&mut output, length*Frame::n_channels()*parameters.format.bytes_per_sample())
+}
+
+impl<Frame: dsp::Frame, Frames: Borrow<[Frame]>> PositionedSequence<Frame, Frames>
+    where Frame::Sample: ToSample<f64> {
+  /// Writes the frames as a WAV file. The file starts at the first frame, not at time 0.
+  pub fn write_wav <P: AsRef<Path>> (&self, path: P, parameters: &WavParameters)->io::Result<()> {
+    write_wav (&mut BufWriter::new (File::create (path)?), self.frames.borrow(), self.sample_hz, parameters)
+  }
+}
+
+
+fn invalid_data <E: ::std::fmt::Display> (error: E)->io::Error {
+  io::Error::new (io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Builds frames from interleaved samples with `channels` channels.
+/// Mono becomes every channel, and anything becomes mono by averaging.
+/// Otherwise, channels map to the channels with the same index; extra ones are dropped and missing ones are silent.
+fn sequence_from_interleaved <Frame: dsp::Frame, I: Iterator<Item = io::Result<f64>>> (samples: I, channels: usize, sample_hz: f64, start: NoteTime)->io::Result<PositionedSequence<Frame, Vec<Frame>>>
+    where Frame::Sample: FromSample<f64> {
+  if channels == 0 || sample_hz <= 0.0 {
+    return Err(invalid_data (format!("{} channels at {} Hz", channels, sample_hz)));
+  }
+  let mut frames = Vec::new();
+  let mut source = Vec::with_capacity (channels);
+  for sample in samples {
+    source.push (sample?);
+    if source.len() == channels {
+      frames.push (if channels == 1 {
+        let value = Frame::Sample::from_sample (source [0]);
+        Frame::from_fn (|_| value)
+      }
+      else if Frame::n_channels() == 1 {
+        let value = Frame::Sample::from_sample (source.iter().sum::<f64>()/channels as f64);
+        Frame::from_fn (|_| value)
+      }
+      else {
+        Frame::from_fn (| channel | Frame::Sample::from_sample (source.get (channel).cloned().unwrap_or (0.0)))
+      });
+      source.clear();
+    }
+  }
+  Ok(PositionedSequence {
+    start: (start*sample_hz).round() as FrameTime,
+    sample_hz,
+    frames,
+    resampling: Resampling::default(),
+    _marker: PhantomData,
+  })
+}
+
+// what integer samples get multiplied by, so that full scale is 1.0; samples are read as i32s, so 32 bits is the most
+fn integer_scale (bits_per_sample: u32)->io::Result<f64> {
+  if bits_per_sample == 0 || bits_per_sample > 32 {
+    return Err(invalid_data (format!("{} bits per sample isn't supported", bits_per_sample)));
+  }
+  Ok(1.0/(1u64 << (bits_per_sample - 1)) as f64)
+}
+
+/// Reads a WAV file (integer or float), to be played starting at `start`.
+pub fn load_wav <Frame: dsp::Frame, P: AsRef<Path>> (path: P, start: NoteTime)->io::Result<PositionedSequence<Frame, Vec<Frame>>>
+    where Frame::Sample: FromSample<f64> {
+  let mut reader = hound::WavReader::open (path).map_err (| error | match error {
+    hound::Error::IoError(error) => error,
+    error => invalid_data (error),
+  })?;
+  let spec = reader.spec();
+  let channels = spec.channels as usize;
+  let sample_hz = spec.sample_rate as f64;
+  match spec.sample_format {
+    hound::SampleFormat::Float => sequence_from_interleaved (reader.samples::<f32>().map (| sample | sample.map (| sample | sample as f64).map_err (invalid_data)), channels, sample_hz, start),
+    hound::SampleFormat::Int => {
+      let scale = integer_scale (spec.bits_per_sample as u32)?;
+      sequence_from_interleaved (reader.samples::<i32>().map (| sample | sample.map (| sample | sample as f64*scale).map_err (invalid_data)), channels, sample_hz, start)
+    },
+  }
+}
+
+/// Reads a FLAC file, to be played starting at `start`.
+#[cfg(feature = "flac")]
+pub fn load_flac <Frame: dsp::Frame, P: AsRef<Path>> (path: P, start: NoteTime)->io::Result<PositionedSequence<Frame, Vec<Frame>>>
+    where Frame::Sample: FromSample<f64> {
+  let mut reader = claxon::FlacReader::open (path).map_err (| error | match error {
+    claxon::Error::IoError(error) => error,
+    error => invalid_data (error),
+  })?;
+  let info = reader.streaminfo();
+  let scale = integer_scale (info.bits_per_sample)?;
+  sequence_from_interleaved (reader.samples().map (| sample | sample.map (| sample | sample as f64*scale).map_err (invalid_data)), info.channels as usize, info.sample_rate as f64, start)
+}
+
+/// Reads an Ogg Vorbis file, to be played starting at `start`.
+#[cfg(feature = "ogg")]
+pub fn load_ogg <Frame: dsp::Frame, P: AsRef<Path>> (path: P, start: NoteTime)->io::Result<PositionedSequence<Frame, Vec<Frame>>>
+    where Frame::Sample: FromSample<f64> {
+  let mut reader = lewton::inside_ogg::OggStreamReader::new (File::open (path)?).map_err (invalid_data)?;
+  let channels = reader.ident_hdr.audio_channels as usize;
+  let sample_hz = reader.ident_hdr.audio_sample_rate as f64;
+  let mut samples = Vec::new();
+  while let Some(packet) = reader.read_dec_packet_itl().map_err (invalid_data)? {
+    samples.extend (packet.into_iter().map (| sample | Ok(sample as f64/32768.0)));
+  }
+  sequence_from_interleaved (samples.into_iter(), channels, sample_hz, start)
+}
+
+/// Reads an audio file of any format this build supports, going by its extension.
+pub fn load_audio <Frame: dsp::Frame, P: AsRef<Path>> (path: P, start: NoteTime)->io::Result<PositionedSequence<Frame, Vec<Frame>>>
+    where Frame::Sample: FromSample<f64> {
+  let extension = path.as_ref().extension().and_then (| extension | extension.to_str()).map (| extension | extension.to_lowercase());
+  match extension.as_ref().map (| extension | &extension [..]) {
+    Some("wav") | Some("wave") => load_wav (path, start),
+    #[cfg(feature = "flac")]
+    Some("flac") => load_flac (path, start),
+    #[cfg(feature = "ogg")]
+    Some("ogg") | Some("oga") => load_ogg (path, start),
+    _ => Err(io::Error::new (io::ErrorKind::InvalidInput, format!("codecophony can't load {:?}; FLAC and Ogg need the \"flac\" and \"ogg\" features", path.as_ref()))),
+  }
+}
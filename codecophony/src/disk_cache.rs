@@ -0,0 +1,200 @@
+use super::*;
+
+use std::io::{self, Read, Write};
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use filetime::{self, FileTime};
+use siphasher::sip::SipHasher;
+
+
+// change this whenever rendering changes in a way that would make old cache entries wrong
+const FORMAT_VERSION: &str = "codecophony rendered note v1";
+const EXTENSION: &str = "note";
+
+struct DiskCache {
+  directory: PathBuf,
+  max_bytes: u64,
+  // an estimate; other processes may be writing to the same directory
+  total_bytes: u64,
+}
+
+// makes temporary file names unique between threads of the same process
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new (0);
+
+lazy_static! {
+  static ref DISK_CACHE: Mutex<Option<DiskCache>> = Mutex::new(None);
+}
+
+fn cache_entries (directory: &Path)->io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+  let mut result = Vec::new();
+  for entry in fs::read_dir (directory)? {
+    let path = entry?.path();
+    if path.extension().map_or (true, | extension | extension != EXTENSION) { continue; }
+    // entries may get deleted by another process while we look at them
+    if let Ok(metadata) = fs::metadata (&path) {
+      result.push ((path, metadata.len(), metadata.modified()?));
+    }
+  }
+  Ok(result)
+}
+
+/// Makes with_rendered_midi_note() store rendered notes in `directory`, so that later runs don't have to render them again.
+///
+/// When the directory grows past `max_bytes`, the least recently used notes get deleted.
+pub fn enable_disk_cache (directory: &Path, max_bytes: u64)->io::Result<()> {
+  fs::create_dir_all (directory)?;
+  let total_bytes = cache_entries (directory)?.iter().map (| &(_, length, _) | length).sum();
+  *DISK_CACHE.lock().unwrap() = Some(DiskCache {
+    directory: directory.to_path_buf(),
+    max_bytes,
+    total_bytes,
+  });
+  evict_if_needed();
+  Ok(())
+}
+
+pub fn disable_disk_cache() {
+  *DISK_CACHE.lock().unwrap() = None;
+}
+
+fn evict_if_needed() {
+  let (directory, max_bytes) = {
+    let guard = DISK_CACHE.lock().unwrap();
+    match guard.as_ref() {
+      Some(cache) if cache.total_bytes > cache.max_bytes => (cache.directory.clone(), cache.max_bytes),
+      _ => return,
+    }
+  };
+  let mut entries = match cache_entries (&directory) {
+    Ok(a) => a,
+    Err(e) => {
+      printlnerr!("codecophony: error reading the rendered note cache: {:?}", e);
+      return;
+    }
+  };
+  entries.sort_by_key (| &(_, _, modified) | modified);
+  let mut total_bytes: u64 = entries.iter().map (| &(_, length, _) | length).sum();
+  for (path, length, _) in entries {
+    // leave some headroom, so we don't have to do this again after every new note
+    if total_bytes <= max_bytes - max_bytes/4 { break; }
+    if fs::remove_file (&path).is_ok() {
+      total_bytes -= length;
+    }
+  }
+  if let Some(cache) = DISK_CACHE.lock().unwrap().as_mut() {
+    cache.total_bytes = total_bytes;
+  }
+}
+
+// Everything that affects the rendered samples. The soundfont id is only meaningful within a single process,
+// so we use the file's identity instead.
+fn key (note: &FluidsynthDirectlyRenderableMIDINote, sample_hz: f64)->Option<String> {
+  let font = soundfont::soundfont_identity (note.instrument.font)?;
+  Some(format!("{}\n{}\nsample_hz {:016x}\nduration {:016x}\npitch {} bend {} velocity {}\nchannel {} bank {} preset {}\n",
+    FORMAT_VERSION,
+    font,
+    sample_hz.to_bits(),
+    note.duration.into_inner().to_bits(),
+    note.pitch, note.pitch_bend, note.velocity,
+    note.instrument.channel, note.instrument.bank, note.instrument.preset,
+  ))
+}
+
+fn entry_path (directory: &Path, key: &str)->PathBuf {
+  let mut hasher = SipHasher::new_with_keys (0, 0);
+  hasher.write (key.as_bytes());
+  directory.join (format!("{:016x}.{}", hasher.finish(), EXTENSION))
+}
+
+fn read_u64 (input: &[u8])->u64 {
+  input.iter().rev().fold (0, | result, &byte | (result << 8) | byte as u64)
+}
+fn write_u64 (output: &mut Vec<u8>, value: u64) {
+  output.extend ((0..8).map (| index | (value >> (index*8)) as u8));
+}
+fn read_f32 (input: &[u8])->f32 {
+  f32::from_bits (input.iter().rev().fold (0, | result, &byte | (result << 8) | byte as u32))
+}
+fn write_f32 (output: &mut Vec<u8>, value: f32) {
+  let bits = value.to_bits();
+  output.extend ((0..4).map (| index | (bits >> (index*8)) as u8));
+}
+
+fn read_entry (path: &Path, key: &str)->io::Result<Option<Arc<[[f32;2]]>>> {
+  let mut data = Vec::new();
+  File::open (path)?.read_to_end (&mut data)?;
+  let key_end = 8 + key.len();
+  // the file name is only a hash, so make sure it's actually the same note
+  if data.len() < key_end + 8 || read_u64 (&data [0..8]) != key.len() as u64 || &data [8..key_end] != key.as_bytes() {
+    return Ok(None);
+  }
+  let frames = read_u64 (&data [key_end..key_end + 8]) as usize;
+  let samples = &data [key_end + 8..];
+  if samples.len() != frames*8 {
+    return Ok(None);
+  }
+  let sample = | index: usize | read_f32 (&samples [index*4..index*4 + 4]);
+  Ok(Some((0..frames).map (| frame | [sample (frame*2), sample (frame*2 + 1)]).collect::<Vec<_>>().into_boxed_slice().into()))
+}
+
+pub(crate) fn load (note: &FluidsynthDirectlyRenderableMIDINote, sample_hz: f64)->Option<Arc<[[f32;2]]>> {
+  let directory = DISK_CACHE.lock().unwrap().as_ref()?.directory.clone();
+  let key = key (note, sample_hz)?;
+  let path = entry_path (&directory, &key);
+  match read_entry (&path, &key) {
+    Ok(Some(samples)) => {
+      // the modification time is how eviction decides what was used recently
+      let now = FileTime::from_system_time (SystemTime::now());
+      let _ = filetime::set_file_times (&path, now, now);
+      Some(samples)
+    },
+    Ok(None) => None,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+    Err(e) => {
+      printlnerr!("codecophony: error reading the rendered note cache: {:?}", e);
+      None
+    },
+  }
+}
+
+pub(crate) fn store (note: &FluidsynthDirectlyRenderableMIDINote, sample_hz: f64, samples: &[[f32;2]]) {
+  let directory = match DISK_CACHE.lock().unwrap().as_ref() {
+    Some(cache) => cache.directory.clone(),
+    None => return,
+  };
+  let key = match key (note, sample_hz) {
+    Some(a) => a,
+    None => return,
+  };
+  let mut data = Vec::with_capacity (16 + key.len() + samples.len()*8);
+  write_u64 (&mut data, key.len() as u64);
+  data.extend_from_slice (key.as_bytes());
+  write_u64 (&mut data, samples.len() as u64);
+  for frame in samples.iter() {
+    for &sample in frame.iter() {
+      write_f32 (&mut data, sample);
+    }
+  }
+
+  // write to a temporary file first, so other processes never see a partial entry
+  let path = entry_path (&directory, &key);
+  // if this replaces an existing entry, that entry's bytes are already counted
+  let replaced_bytes = fs::metadata (&path).map (| metadata | metadata.len()).unwrap_or (0);
+  let temporary_path = path.with_extension (format!("{}-{}.partial", ::std::process::id(), TEMPORARY_FILES.fetch_add (1, Ordering::Relaxed)));
+  let result = File::create (&temporary_path).and_then (| mut file | file.write_all (&data)).and_then (| _ | fs::rename (&temporary_path, &path));
+  if let Err(e) = result {
+    printlnerr!("codecophony: error writing the rendered note cache: {:?}", e);
+    let _ = fs::remove_file (&temporary_path);
+    return;
+  }
+
+  if let Some(cache) = DISK_CACHE.lock().unwrap().as_mut() {
+    cache.total_bytes = (cache.total_bytes + data.len() as u64).saturating_sub (replaced_bytes);
+  }
+  evict_if_needed();
+}
@@ -0,0 +1,593 @@
+use super::*;
+
+use std::f64::consts::PI;
+use std::sync::Mutex;
+use dsp::sample::ToSample;
+
+
+/// Audio processing that depends on what came before, like a filter or a delay line.
+///
+/// Processors work on one frame at a time, with one f64 sample per channel.
+pub trait Processor {
+  /// How long the output can keep going after the input becomes silent.
+  fn tail (&self)->NoteTime;
+  /// Prepares for a new pass over the audio, forgetting everything from any previous pass.
+  fn reset (&mut self, sample_hz: f64, channels: usize);
+  fn process (&mut self, frame: &mut [f64]);
+}
+
+/// Runs the processors one after another.
+impl Processor for Vec<Box<Processor + Send>> {
+  fn tail (&self)->NoteTime {
+    self.iter().map (| processor | processor.tail()).sum()
+  }
+  fn reset (&mut self, sample_hz: f64, channels: usize) {
+    for processor in self.iter_mut() { processor.reset (sample_hz, channels); }
+  }
+  fn process (&mut self, frame: &mut [f64]) {
+    for processor in self.iter_mut() { processor.process (frame); }
+  }
+}
+
+pub(crate) fn frame_to_samples <Frame: dsp::Frame> (frame: &Frame, samples: &mut [f64])
+    where Frame::Sample: ToSample<f64> {
+  for (channel, sample) in samples.iter_mut().enumerate() {
+    *sample = frame.channel (channel).unwrap().to_sample();
+  }
+}
+
+pub(crate) fn add_samples <Frame: dsp::Frame> (value_mut: &mut Frame, samples: &[f64])
+    where Frame::Sample: dsp::FromSample<f64> {
+  let frame = Frame::from_fn(| channel | Frame::Sample::from_sample(samples [channel]));
+  *value_mut = value_mut.add_amp(frame.to_signed_frame());
+}
+
+// for the effects that don't need to remember anything, so they can render any part of the timeline directly
+fn render_mapped <Frame: dsp::Frame, N: Renderable<Frame>, M: FnMut(&mut [f64])> (inner: &N, buffer: &mut [Frame], start: FrameTime, sample_hz: f64, mut map: M)
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  let mut rendered = vec![Frame::equilibrium(); buffer.len()];
+  inner.render (&mut rendered, start, sample_hz);
+  let mut samples = vec![0.0; Frame::n_channels()];
+  for (value_mut, frame) in buffer.iter_mut().zip (rendered.iter()) {
+    frame_to_samples (frame, &mut samples);
+    map (&mut samples);
+    add_samples (value_mut, &samples);
+  }
+}
+
+
+/// Multiplies the amplitude of `inner` by `gain`.
+#[derive (Clone, Debug)]
+pub struct Gain<N> {
+  pub inner: N,
+  pub gain: f64,
+}
+
+impl<N> Gain<N> {
+  pub fn new (inner: N, gain: f64)->Gain<N> {
+    Gain {inner, gain}
+  }
+  pub fn decibels (inner: N, decibels: f64)->Gain<N> {
+    Gain::new (inner, decibels_to_amplitude (decibels))
+  }
+}
+
+pub fn decibels_to_amplitude (decibels: f64)->f64 {
+  10f64.powf (decibels/20.0)
+}
+
+impl<N: Windowed> Windowed for Gain<N> {
+  fn start (&self)->NoteTime {self.inner.start()}
+  fn end (&self)->NoteTime {self.inner.end()}
+}
+impl<Frame: dsp::Frame, N: Renderable<Frame>> Renderable<Frame> for Gain<N>
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  fn render(&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    render_mapped (&self.inner, buffer, start, sample_hz, | samples | {
+      for sample in samples.iter_mut() { *sample *= self.gain; }
+    });
+  }
+}
+impl<N: Nudgable> Nudgable for Gain<N> {
+  fn nudge(&mut self, distance: NoteTime) {
+    self.inner.nudge (distance);
+  }
+}
+
+
+pub(crate) fn pan_gains (pan: f64)->(f64, f64) {
+  let pan = pan.max (-1.0).min (1.0);
+  ((1.0 - pan).min (1.0), (1.0 + pan).min (1.0))
+}
+
+/// Moves stereo audio to the left (negative `pan`) or right (positive `pan`), using the same law as MIDINote::with_pan().
+/// Audio with any other number of channels is left alone.
+#[derive (Clone, Debug)]
+pub struct Pan<N> {
+  pub inner: N,
+  pub pan: f64,
+}
+
+impl<N> Pan<N> {
+  pub fn new (inner: N, pan: f64)->Pan<N> {
+    Pan {inner, pan}
+  }
+}
+
+impl<N: Windowed> Windowed for Pan<N> {
+  fn start (&self)->NoteTime {self.inner.start()}
+  fn end (&self)->NoteTime {self.inner.end()}
+}
+impl<Frame: dsp::Frame, N: Renderable<Frame>> Renderable<Frame> for Pan<N>
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  fn render(&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    if Frame::n_channels() != 2 {
+      self.inner.render (buffer, start, sample_hz);
+      return;
+    }
+    let (left_gain, right_gain) = pan_gains (self.pan);
+    render_mapped (&self.inner, buffer, start, sample_hz, | samples | {
+      samples [0] *= left_gain;
+      samples [1] *= right_gain;
+    });
+  }
+}
+impl<N: Nudgable> Nudgable for Pan<N> {
+  fn nudge(&mut self, distance: NoteTime) {
+    self.inner.nudge (distance);
+  }
+}
+
+
+pub(crate) struct RenderedEffect {
+  pub(crate) sample_hz: f64,
+  pub(crate) channels: usize,
+  start: FrameTime,
+  samples: Vec<f32>,
+}
+
+impl RenderedEffect {
+  pub(crate) fn add_to <Frame: dsp::Frame> (&self, buffer: &mut [Frame], start: FrameTime)
+      where Frame::Sample: dsp::FromSample<f64> {
+    let channels = self.channels;
+    let length = (self.samples.len()/channels) as FrameTime;
+    let mut samples = vec![0.0; channels];
+    for (index, value_mut) in buffer.iter_mut().enumerate() {
+      let rendered_index = start + index as FrameTime - self.start;
+      if rendered_index < 0 || rendered_index >= length { continue; }
+      let first = rendered_index as usize*channels;
+      for (channel, sample) in samples.iter_mut().enumerate() {
+        *sample = self.samples [first + channel] as f64;
+      }
+      add_samples (value_mut, &samples);
+    }
+  }
+}
+
+struct EffectState<P> {
+  processor: P,
+  rendered: Option<RenderedEffect>,
+}
+
+/// Runs the output of `inner` through `processor`.
+///
+/// Processors need to see the audio in order, from the beginning, so the first time this is rendered at a sample rate,
+/// the whole thing gets processed and kept in memory. That way, later buffers (and other threads) can start anywhere.
+pub struct Effect<N, P> {
+  inner: N,
+  state: Mutex<EffectState<P>>,
+}
+
+impl<N, P> Effect<N, P> {
+  pub fn new (inner: N, processor: P)->Effect<N, P> {
+    Effect {inner, state: Mutex::new(EffectState {processor, rendered: None})}
+  }
+  pub fn inner (&self)->&N {&self.inner}
+  /// Changing the inner note discards the processed audio.
+  pub fn inner_mut (&mut self)->&mut N {
+    self.state.get_mut().unwrap().rendered = None;
+    &mut self.inner
+  }
+  /// Changing the processor discards the processed audio.
+  pub fn processor_mut (&mut self)->&mut P {
+    let state = self.state.get_mut().unwrap();
+    state.rendered = None;
+    &mut state.processor
+  }
+  pub fn into_inner (self)->(N, P) {
+    (self.inner, self.state.into_inner().unwrap().processor)
+  }
+}
+
+impl<N: Clone, P: Clone> Clone for Effect<N, P> {
+  fn clone (&self)->Self {
+    Effect::new (self.inner.clone(), self.state.lock().unwrap().processor.clone())
+  }
+}
+
+impl<N: Windowed, P: Processor> Windowed for Effect<N, P> {
+  fn start (&self)->NoteTime {self.inner.start()}
+  fn end (&self)->NoteTime {self.inner.end() + self.state.lock().unwrap().processor.tail()}
+}
+
+pub(crate) fn render_through <Frame: dsp::Frame, N: Renderable<Frame> + ?Sized, P: Processor + ?Sized> (inner: &N, processor: &mut P, sample_hz: f64)->RenderedEffect
+    where Frame::Sample: ToSample<f64> {
+  let channels = Frame::n_channels();
+  let earliest = (inner.start()*sample_hz).ceil() as FrameTime;
+  let inner_latest = (inner.end()*sample_hz).floor() as FrameTime;
+  let latest = ((inner.end() + processor.tail())*sample_hz).floor() as FrameTime;
+  let mut input = vec![Frame::equilibrium(); max(0, inner_latest+1-earliest) as usize];
+  inner.render (&mut input, earliest, sample_hz);
+
+  let length = max(0, latest+1-earliest) as usize;
+  let mut samples = Vec::with_capacity (length*channels);
+  let mut frame = vec![0.0; channels];
+  processor.reset (sample_hz, channels);
+  for index in 0..length {
+    match input.get (index) {
+      Some(input_frame) => frame_to_samples (input_frame, &mut frame),
+      None => for sample in frame.iter_mut() { *sample = 0.0; },
+    }
+    processor.process (&mut frame);
+    samples.extend (frame.iter().map (| &sample | sample as f32));
+  }
+  RenderedEffect {sample_hz, channels, start: earliest, samples}
+}
+
+impl<Frame: dsp::Frame, N: Renderable<Frame>, P: Processor> Renderable<Frame> for Effect<N, P>
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  fn render(&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    let channels = Frame::n_channels();
+    let mut guard = self.state.lock().unwrap();
+    let state = &mut *guard;
+    let up_to_date = match state.rendered {
+      Some(ref rendered) => rendered.sample_hz == sample_hz && rendered.channels == channels,
+      None => false,
+    };
+    if !up_to_date {
+      state.rendered = Some(render_through::<Frame, N, P> (&self.inner, &mut state.processor, sample_hz));
+    }
+    state.rendered.as_ref().unwrap().add_to (buffer, start);
+  }
+}
+
+impl<N: Nudgable, P> Nudgable for Effect<N, P> {
+  fn nudge(&mut self, distance: NoteTime) {
+    self.inner_mut().nudge (distance);
+  }
+}
+
+
+#[derive (Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum BiquadKind {
+  LowPass,
+  HighPass,
+  BandPass,
+  LowShelf,
+  HighShelf,
+  Peaking,
+}
+
+/// The standard second-order filters, from Robert Bristow-Johnson's Audio EQ Cookbook.
+///
+/// `gain_decibels` only matters for the shelving and peaking filters.
+#[derive (Clone, Debug)]
+pub struct Biquad {
+  pub kind: BiquadKind,
+  pub frequency: f64,
+  pub q: f64,
+  pub gain_decibels: f64,
+  coefficients: [f64; 5],
+  // the last two inputs and outputs of each channel
+  history: Vec<[f64; 4]>,
+}
+
+impl Biquad {
+  pub fn new (kind: BiquadKind, frequency: f64, q: f64, gain_decibels: f64)->Biquad {
+    Biquad {kind, frequency, q, gain_decibels, coefficients: [0.0; 5], history: Vec::new()}
+  }
+  pub fn low_pass (frequency: f64, q: f64)->Biquad {Biquad::new (BiquadKind::LowPass, frequency, q, 0.0)}
+  pub fn high_pass (frequency: f64, q: f64)->Biquad {Biquad::new (BiquadKind::HighPass, frequency, q, 0.0)}
+  pub fn band_pass (frequency: f64, q: f64)->Biquad {Biquad::new (BiquadKind::BandPass, frequency, q, 0.0)}
+  pub fn low_shelf (frequency: f64, gain_decibels: f64)->Biquad {Biquad::new (BiquadKind::LowShelf, frequency, ::std::f64::consts::FRAC_1_SQRT_2, gain_decibels)}
+  pub fn high_shelf (frequency: f64, gain_decibels: f64)->Biquad {Biquad::new (BiquadKind::HighShelf, frequency, ::std::f64::consts::FRAC_1_SQRT_2, gain_decibels)}
+  pub fn peaking (frequency: f64, q: f64, gain_decibels: f64)->Biquad {Biquad::new (BiquadKind::Peaking, frequency, q, gain_decibels)}
+}
+
+impl Processor for Biquad {
+  fn tail (&self)->NoteTime {
+    // roughly how long the resonance takes to die down by 60 dB
+    6.91*self.q.max (0.5)/(PI*self.frequency)
+  }
+  fn reset (&mut self, sample_hz: f64, channels: usize) {
+    let frequency = self.frequency.min (sample_hz*0.49);
+    let w0 = 2.0*PI*frequency/sample_hz;
+    let (sin, cos) = (w0.sin(), w0.cos());
+    let alpha = sin/(2.0*self.q);
+    let a = 10f64.powf (self.gain_decibels/40.0);
+    let shelf = 2.0*a.sqrt()*alpha;
+    let (b0, b1, b2, a0, a1, a2) = match self.kind {
+      BiquadKind::LowPass => ((1.0 - cos)/2.0, 1.0 - cos, (1.0 - cos)/2.0, 1.0 + alpha, -2.0*cos, 1.0 - alpha),
+      BiquadKind::HighPass => ((1.0 + cos)/2.0, -(1.0 + cos), (1.0 + cos)/2.0, 1.0 + alpha, -2.0*cos, 1.0 - alpha),
+      BiquadKind::BandPass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0*cos, 1.0 - alpha),
+      BiquadKind::Peaking => (1.0 + alpha*a, -2.0*cos, 1.0 - alpha*a, 1.0 + alpha/a, -2.0*cos, 1.0 - alpha/a),
+      BiquadKind::LowShelf => (
+        a*((a + 1.0) - (a - 1.0)*cos + shelf),
+        2.0*a*((a - 1.0) - (a + 1.0)*cos),
+        a*((a + 1.0) - (a - 1.0)*cos - shelf),
+        (a + 1.0) + (a - 1.0)*cos + shelf,
+        -2.0*((a - 1.0) + (a + 1.0)*cos),
+        (a + 1.0) + (a - 1.0)*cos - shelf,
+      ),
+      BiquadKind::HighShelf => (
+        a*((a + 1.0) + (a - 1.0)*cos + shelf),
+        -2.0*a*((a - 1.0) + (a + 1.0)*cos),
+        a*((a + 1.0) + (a - 1.0)*cos - shelf),
+        (a + 1.0) - (a - 1.0)*cos + shelf,
+        2.0*((a - 1.0) - (a + 1.0)*cos),
+        (a + 1.0) - (a - 1.0)*cos - shelf,
+      ),
+    };
+    self.coefficients = [b0/a0, b1/a0, b2/a0, a1/a0, a2/a0];
+    self.history = vec![[0.0; 4]; channels];
+  }
+  fn process (&mut self, frame: &mut [f64]) {
+    let [b0, b1, b2, a1, a2] = self.coefficients;
+    for (sample, history) in frame.iter_mut().zip (self.history.iter_mut()) {
+      let [x1, x2, y1, y2] = *history;
+      let x = *sample;
+      let y = b0*x + b1*x1 + b2*x2 - a1*y1 - a2*y2;
+      *history = [x, x1, y, y1];
+      *sample = y;
+    }
+  }
+}
+
+
+/// Echoes: each repeat comes `time` seconds after the last, `feedback` times as loud.
+/// `feedback` must be less than 1. `mix` is the fraction of the output that's delayed.
+#[derive (Clone, Debug)]
+pub struct Delay {
+  pub time: NoteTime,
+  pub feedback: f64,
+  pub mix: f64,
+  lines: Vec<Vec<f64>>,
+  position: usize,
+}
+
+impl Delay {
+  pub fn new (time: NoteTime, feedback: f64, mix: f64)->Delay {
+    Delay {time, feedback, mix, lines: Vec::new(), position: 0}
+  }
+}
+
+// how many times something has to be multiplied by `factor` to fade by 60 dB
+fn repeats_to_fade (factor: f64)->f64 {
+  if factor <= 0.0 { 0.0 } else { (0.001f64).ln()/factor.min (0.999).ln() }
+}
+
+impl Processor for Delay {
+  fn tail (&self)->NoteTime {
+    self.time*(1.0 + repeats_to_fade (self.feedback))
+  }
+  fn reset (&mut self, sample_hz: f64, channels: usize) {
+    let length = max(1, (self.time*sample_hz).round() as usize);
+    self.lines = vec![vec![0.0; length]; channels];
+    self.position = 0;
+  }
+  fn process (&mut self, frame: &mut [f64]) {
+    for (sample, line) in frame.iter_mut().zip (self.lines.iter_mut()) {
+      let delayed = line [self.position];
+      line [self.position] = *sample + delayed*self.feedback;
+      *sample = *sample*(1.0 - self.mix) + delayed*self.mix;
+    }
+    self.position = (self.position + 1) % self.lines.first().map_or (1, | line | line.len());
+  }
+}
+
+
+/// Mixes in copies delayed by `delay` seconds, plus or minus `depth` seconds, swaying at `rate` Hz.
+/// Each channel sways at a different phase, which widens stereo audio.
+#[derive (Clone, Debug)]
+pub struct Chorus {
+  pub delay: NoteTime,
+  pub depth: NoteTime,
+  pub rate: f64,
+  pub mix: f64,
+  sample_hz: f64,
+  lines: Vec<Vec<f64>>,
+  position: usize,
+  frames_processed: u64,
+}
+
+impl Chorus {
+  pub fn new (delay: NoteTime, depth: NoteTime, rate: f64, mix: f64)->Chorus {
+    Chorus {delay, depth, rate, mix, sample_hz: 0.0, lines: Vec::new(), position: 0, frames_processed: 0}
+  }
+}
+
+impl Default for Chorus {
+  fn default()->Chorus {
+    Chorus::new (0.02, 0.003, 0.8, 0.5)
+  }
+}
+
+impl Processor for Chorus {
+  fn tail (&self)->NoteTime {
+    self.delay + self.depth
+  }
+  fn reset (&mut self, sample_hz: f64, channels: usize) {
+    let length = ((self.delay + self.depth.abs())*sample_hz).ceil() as usize + 2;
+    self.sample_hz = sample_hz;
+    self.lines = vec![vec![0.0; length]; channels];
+    self.position = 0;
+    self.frames_processed = 0;
+  }
+  fn process (&mut self, frame: &mut [f64]) {
+    let time = self.frames_processed as f64/self.sample_hz;
+    let channels = frame.len() as f64;
+    for (channel, (sample, line)) in frame.iter_mut().zip (self.lines.iter_mut()).enumerate() {
+      let length = line.len();
+      line [self.position] = *sample;
+      let sway = (2.0*PI*(self.rate*time + channel as f64/channels)).sin();
+      let delay = ((self.delay + self.depth*sway)*self.sample_hz).max (0.0).min ((length - 2) as f64);
+      let whole = delay.floor() as usize;
+      let fraction = delay - whole as f64;
+      let newer = line [(self.position + length - whole) % length];
+      let older = line [(self.position + length - whole - 1) % length];
+      let delayed = newer + (older - newer)*fraction;
+      *sample = *sample*(1.0 - self.mix) + delayed*self.mix;
+    }
+    self.position = (self.position + 1) % self.lines.first().map_or (1, | line | line.len());
+    self.frames_processed += 1;
+  }
+}
+
+
+// Freeverb's tunings, in samples at 44100 Hz
+const REVERB_COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const REVERB_ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+const REVERB_STEREO_SPREAD: usize = 23;
+
+#[derive (Clone, Debug)]
+struct ReverbChannel {
+  combs: Vec<(Vec<f64>, f64)>,
+  allpasses: Vec<Vec<f64>>,
+}
+
+/// An algorithmic reverb, based on Jezar's Freeverb.
+///
+/// `room_size` and `damping` go from 0 to 1. `width` only matters for stereo audio, where 0 makes the reverb mono.
+#[derive (Clone, Debug)]
+pub struct Reverb {
+  pub room_size: f64,
+  pub damping: f64,
+  pub wet: f64,
+  pub dry: f64,
+  pub width: f64,
+  channels: Vec<ReverbChannel>,
+  position: usize,
+}
+
+impl Reverb {
+  pub fn new (room_size: f64, damping: f64, wet: f64, dry: f64)->Reverb {
+    Reverb {room_size, damping, wet, dry, width: 1.0, channels: Vec::new(), position: 0}
+  }
+  fn feedback (&self)->f64 {
+    self.room_size.max (0.0).min (1.0)*0.28 + 0.7
+  }
+}
+
+impl Default for Reverb {
+  fn default()->Reverb {
+    Reverb::new (0.5, 0.5, 0.3, 1.0)
+  }
+}
+
+impl Processor for Reverb {
+  fn tail (&self)->NoteTime {
+    let longest_comb = (REVERB_COMB_LENGTHS [7] + REVERB_STEREO_SPREAD) as f64/44100.0;
+    let allpasses = REVERB_ALLPASS_LENGTHS.iter().sum::<usize>() as f64/44100.0;
+    longest_comb*repeats_to_fade (self.feedback()) + allpasses
+  }
+  fn reset (&mut self, sample_hz: f64, channels: usize) {
+    let scale = | length: usize | max(1, (length as f64*sample_hz/44100.0).round() as usize);
+    self.channels = (0..channels).map (| channel | {
+      let spread = channel*REVERB_STEREO_SPREAD;
+      ReverbChannel {
+        combs: REVERB_COMB_LENGTHS.iter().map (| &length | (vec![0.0; scale (length + spread)], 0.0)).collect(),
+        allpasses: REVERB_ALLPASS_LENGTHS.iter().map (| &length | vec![0.0; scale (length + spread)]).collect(),
+      }
+    }).collect();
+    self.position = 0;
+  }
+  fn process (&mut self, frame: &mut [f64]) {
+    let feedback = self.feedback();
+    let damping = self.damping.max (0.0).min (1.0)*0.4;
+    let position = self.position;
+    // like Freeverb, feed every channel the same mono input, and let the different delay lengths decorrelate them
+    let input = frame.iter().sum::<f64>()*0.015;
+    let outputs: Vec<f64> = self.channels.iter_mut().map (| channel | {
+      let mut output = 0.0;
+      for &mut (ref mut line, ref mut filter_state) in channel.combs.iter_mut() {
+        let index = position % line.len();
+        let delayed = line [index];
+        *filter_state = delayed*(1.0 - damping) + *filter_state*damping;
+        line [index] = input + *filter_state*feedback;
+        output += delayed;
+      }
+      for line in channel.allpasses.iter_mut() {
+        let index = position % line.len();
+        let delayed = line [index];
+        line [index] = output + delayed*0.5;
+        output = delayed - output;
+      }
+      output
+    }).collect();
+
+    // Freeverb's wet scale
+    let wet = self.wet*3.0;
+    let stereo = frame.len() == 2;
+    let same_side = wet*(self.width/2.0 + 0.5);
+    let other_side = wet*(1.0 - self.width)/2.0;
+    for (channel, sample) in frame.iter_mut().enumerate() {
+      let reverberated = if stereo {
+        outputs [channel]*same_side + outputs [1 - channel]*other_side
+      }
+      else {
+        outputs [channel]*wet
+      };
+      *sample = *sample*self.dry + reverberated;
+    }
+    // the delay lines all have different lengths, so just count forever and wrap separately
+    self.position = self.position.wrapping_add (1);
+  }
+}
+
+
+/// Reduces the level of anything louder than `threshold_decibels`, so that every `ratio` decibels over the threshold
+/// becomes 1 decibel over. All channels are compressed together, so stereo images don't shift.
+#[derive (Clone, Debug)]
+pub struct Compressor {
+  pub threshold_decibels: f64,
+  pub ratio: f64,
+  pub attack: NoteTime,
+  pub release: NoteTime,
+  pub makeup_decibels: f64,
+  attack_factor: f64,
+  release_factor: f64,
+  reduction_decibels: f64,
+}
+
+impl Compressor {
+  pub fn new (threshold_decibels: f64, ratio: f64, attack: NoteTime, release: NoteTime)->Compressor {
+    Compressor {threshold_decibels, ratio, attack, release, makeup_decibels: 0.0, attack_factor: 0.0, release_factor: 0.0, reduction_decibels: 0.0}
+  }
+  pub fn with_makeup (mut self, makeup_decibels: f64)->Compressor {
+    self.makeup_decibels = makeup_decibels;
+    self
+  }
+}
+
+impl Processor for Compressor {
+  fn tail (&self)->NoteTime {
+    // it only ever turns things down, so silence stays silent
+    0.0
+  }
+  fn reset (&mut self, sample_hz: f64, _channels: usize) {
+    let smoothing = | time: NoteTime | if time <= 0.0 { 0.0 } else { (-1.0/(time*sample_hz)).exp() };
+    self.attack_factor = smoothing (self.attack);
+    self.release_factor = smoothing (self.release);
+    self.reduction_decibels = 0.0;
+  }
+  fn process (&mut self, frame: &mut [f64]) {
+    let peak = frame.iter().fold (0.0f64, | peak, sample | peak.max (sample.abs()));
+    let level = 20.0*peak.max (1e-10).log10();
+    let over = level - self.threshold_decibels;
+    let target = if over > 0.0 { over*(1.0 - 1.0/self.ratio.max (1.0)) } else { 0.0 };
+    let factor = if target > self.reduction_decibels { self.attack_factor } else { self.release_factor };
+    self.reduction_decibels = target + (self.reduction_decibels - target)*factor;
+    let gain = decibels_to_amplitude (self.makeup_decibels - self.reduction_decibels);
+    for sample in frame.iter_mut() { *sample *= gain; }
+  }
+}
@@ -0,0 +1,173 @@
+use super::*;
+
+
+/// The shape of the path from one envelope level to the next.
+#[derive (Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub enum Curve {
+  Linear,
+  /// Negative curvatures change quickly at first and then level off, like a plucked string dying away.
+  /// Positive curvatures start slowly and speed up, like a swell. Zero is linear.
+  Exponential {curvature: f64},
+}
+
+impl Curve {
+  // maps progress through a segment, from 0 to 1, to how far the level has moved, from 0 to 1
+  fn shape (&self, progress: f64)->f64 {
+    match *self {
+      Curve::Exponential {curvature} if curvature.abs() > 1e-6 =>
+        (1.0 - (curvature*progress).exp())/(1.0 - curvature.exp()),
+      _ => progress,
+    }
+  }
+}
+
+/// Moves from the level the previous segment ended at to `level`, over `duration` seconds.
+#[derive (Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub struct EnvelopeSegment {
+  pub duration: NoteTime,
+  pub level: f64,
+  pub curve: Curve,
+}
+
+impl EnvelopeSegment {
+  pub fn new (duration: NoteTime, level: f64, curve: Curve)->EnvelopeSegment {
+    EnvelopeSegment {duration, level, curve}
+  }
+  pub fn linear (duration: NoteTime, level: f64)->EnvelopeSegment {
+    EnvelopeSegment::new (duration, level, Curve::Linear)
+  }
+}
+
+/// A gain that changes over the course of a note.
+///
+/// If there's a sustain segment, the envelope holds the level that segment ends at for as long as the note is held,
+/// then plays the remaining segments as the release, starting from wherever it had gotten to.
+/// Without one, the envelope plays all of its segments regardless of how long the note is held.
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Envelope {
+  pub start_level: f64,
+  pub segments: Vec<EnvelopeSegment>,
+  pub sustain_segment: Option<usize>,
+  /// If set, the release ends when the note is let go, rather than after, so the note lasts exactly as long as it's held.
+  /// Notes held for less than this many seconds shrink every segment in proportion, so the envelope still fits.
+  #[serde (default)]
+  pub fit_within_held: Option<NoteTime>,
+}
+
+impl Default for Envelope {
+  /// A short linear fade at each end, just enough to avoid clicks, inside the time the note is held.
+  /// The fades are 50 ms long, or 5% of the note for notes shorter than a second.
+  fn default()->Envelope {
+    Envelope::adsr (0.05, 0.0, 1.0, 0.05).with_fit_within_held (1.0)
+  }
+}
+
+// `scale` multiplies the duration of every segment
+fn follow_segments (segments: &[EnvelopeSegment], start_level: f64, time: NoteTime, scale: f64)->f64 {
+  let mut level = start_level;
+  let mut remaining = time;
+  for segment in segments {
+    let duration = segment.duration*scale;
+    if remaining < duration {
+      return level + (segment.level - level)*segment.curve.shape (remaining/duration);
+    }
+    remaining -= duration;
+    level = segment.level;
+  }
+  level
+}
+
+impl Envelope {
+  /// Linear attack from silence to full level, linear decay to `sustain_level`, then a linear release after the note is let go.
+  pub fn adsr (attack: NoteTime, decay: NoteTime, sustain_level: f64, release: NoteTime)->Envelope {
+    Envelope {
+      start_level: 0.0,
+      segments: vec![
+        EnvelopeSegment::linear (attack, 1.0),
+        EnvelopeSegment::linear (decay, sustain_level),
+        EnvelopeSegment::linear (release, 0.0),
+      ],
+      sustain_segment: Some(1),
+      fit_within_held: None,
+    }
+  }
+
+  /// Straight lines between (time, level) points, starting at the note's start, ignoring how long the note is held.
+  pub fn breakpoints (points: &[(NoteTime, f64)])->Envelope {
+    let start_level = points.first().map_or (0.0, | &(_, level) | level);
+    Envelope {
+      start_level,
+      segments: points.windows (2).map (| pair | EnvelopeSegment::linear (pair [1].0 - pair [0].0, pair [1].1)).collect(),
+      sustain_segment: None,
+      fit_within_held: None,
+    }
+  }
+
+  /// A quick attack followed by an exponential decay to silence, ignoring how long the note is held.
+  pub fn pluck (attack: NoteTime, decay: NoteTime)->Envelope {
+    Envelope {
+      start_level: 0.0,
+      segments: vec![
+        EnvelopeSegment::linear (attack, 1.0),
+        EnvelopeSegment::new (decay, 0.0, Curve::Exponential {curvature: -6.0}),
+      ],
+      sustain_segment: None,
+      fit_within_held: None,
+    }
+  }
+
+  pub fn with_fit_within_held (mut self, reference_duration: NoteTime)->Envelope {
+    self.fit_within_held = Some(reference_duration);
+    self
+  }
+
+  fn split_at_release (&self)->(&[EnvelopeSegment], &[EnvelopeSegment]) {
+    match self.sustain_segment {
+      Some(index) => self.segments.split_at (min(index + 1, self.segments.len())),
+      None => (&self.segments [..], &[]),
+    }
+  }
+
+  // how much to shrink the segments, and when the release starts, for a note held for `held` seconds
+  fn timing (&self, held: NoteTime)->(f64, NoteTime) {
+    match self.fit_within_held {
+      Some(reference_duration) => {
+        let scale = if held < reference_duration {held/reference_duration} else {1.0};
+        let release_duration: NoteTime = self.split_at_release().1.iter().map (| segment | segment.duration*scale).sum();
+        (scale, held - release_duration)
+      },
+      None => (1.0, held),
+    }
+  }
+
+  /// The level at `time` seconds after the note starts, if the note is held for `held` seconds.
+  pub fn level (&self, time: NoteTime, held: NoteTime)->f64 {
+    let (scale, release_start) = self.timing (held);
+    if self.sustain_segment.is_none() {
+      return follow_segments (&self.segments, self.start_level, time, scale);
+    }
+    let (before_release, release) = self.split_at_release();
+    if time < release_start {
+      follow_segments (before_release, self.start_level, time, scale)
+    }
+    else {
+      follow_segments (release, follow_segments (before_release, self.start_level, release_start, scale), time - release_start, scale)
+    }
+  }
+
+  /// How long after its start the note becomes silent, if it's held for `held` seconds.
+  pub fn duration (&self, held: NoteTime)->NoteTime {
+    let (scale, release_start) = self.timing (held);
+    let (before_release, release) = self.split_at_release();
+    let release_duration: NoteTime = release.iter().map (| segment | segment.duration*scale).sum();
+    if self.fit_within_held.is_some() && self.sustain_segment.is_some() {
+      held
+    }
+    else if self.sustain_segment.is_some() {
+      release_start + release_duration
+    }
+    else {
+      before_release.iter().map (| segment | segment.duration*scale).sum()
+    }
+  }
+}
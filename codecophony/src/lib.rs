@@ -12,7 +12,17 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate xml;
 extern crate notify;
+extern crate siphasher;
+extern crate filetime;
+extern crate num_cpus;
+extern crate num_rational;
+extern crate num_traits;
+#[cfg(feature = "flac")]
+extern crate claxon;
+#[cfg(feature = "ogg")]
+extern crate lewton;
 
 macro_rules! printlnerr(
     ($($arg:tt)*) => { {use std::io::Write;
@@ -28,7 +38,10 @@ use std::cell::RefCell;
 use std::borrow::{Borrow, BorrowMut};
 use std::marker::PhantomData;
 use std::iter::{self, FromIterator};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::panic;
 
 use dsp::Sample;
 use ordered_float::{NotNaN, OrderedFloat};
@@ -36,6 +49,32 @@ use ordered_float::{NotNaN, OrderedFloat};
 
 pub mod project;
 pub mod phrase;
+pub mod midi_file;
+pub mod soundfont;
+pub mod disk_cache;
+pub mod memory_cache;
+pub mod resampling;
+pub mod envelope;
+pub mod oscillator;
+pub mod effects;
+pub mod mixer;
+pub mod mastering;
+pub mod audio_file;
+pub mod sfz;
+pub mod tempo;
+pub mod rational_time;
+pub mod theory;
+pub mod tuning;
+pub mod scrawl;
+pub mod musicxml;
+pub mod lilypond;
+
+use soundfont::SoundfontId;
+pub use resampling::Resampling;
+pub use envelope::Envelope;
+pub use oscillator::{Oscillator, Waveform};
+pub use tempo::{TempoMap, TimeSignature, Beats};
+pub use rational_time::{ExactBeats, Scored, ScoredPhrase};
 
 
 pub type FrameTime = i64;
@@ -128,6 +167,24 @@ pub trait Dilatable {
   fn dilate(&mut self, amount: f64, origin: f64);
 }
 
+/// Moves a note's start and end through a mapping from one timeline to another, like a TempoMap's mapping from beats to seconds.
+pub trait Retimable {
+  fn retime(&mut self, map: &Fn(NoteTime)->NoteTime);
+}
+
+impl<T: Retimable> Retimable for Vec<T> {
+  fn retime(&mut self, map: &Fn(NoteTime)->NoteTime) {
+    for note in self.iter_mut() {
+      note.retime(map);
+    }
+  }
+}
+
+/// Notes that can be moved to an exact start and duration, in seconds.
+pub trait Placeable {
+  fn place(&mut self, start: NoteTime, duration: NoteTime);
+}
+
 pub trait Pitched {
   fn frequency(&self)->f64;
 }
@@ -152,6 +209,7 @@ pub struct PositionedSequence<Frame: dsp::Frame, Frames: Borrow<[Frame]>> {
   pub start: FrameTime,
   pub sample_hz: f64,
   pub frames: Frames,
+  pub resampling: Resampling,
   _marker: PhantomData<Frame>,
 }
 impl<Frame: dsp::Frame, Frames: Borrow<[Frame]>> Windowed for PositionedSequence<Frame, Frames> {
@@ -169,10 +227,7 @@ impl<Frame: dsp::Frame, Frames: Borrow<[Frame]>> Renderable<Frame> for Positione
     }
     else {
       // if the sample rates are different, resample it
-      for (index, value_mut) in buffer.iter_mut().enumerate() {
-        let time = (start as f64 + index as f64) * sample_hz;
-        *value_mut = value_mut.add_amp(self.interpolate_sample (time).to_signed_frame());
-      }
+      self.render_resampled (buffer, start, sample_hz);
     }
   }
 }
@@ -212,43 +267,146 @@ impl<Frame: dsp::Frame, Frames: Borrow<[Frame]>> PositionedSequence<Frame, Frame
       start: earliest,
       sample_hz,
       frames,
+      resampling: Resampling::default(),
       _marker: PhantomData,
     }
   }
 }
 
+type RenderJob = Box<FnMut() + Send>;
+
+// worker threads that live as long as the process, so that the synthesizers and caches each one keeps stay warm between renders
+struct RenderPool {
+  jobs: Mutex<mpsc::Sender<RenderJob>>,
+}
+
+impl RenderPool {
+  fn new (threads: usize)->RenderPool {
+    let (sender, receiver) = mpsc::channel::<RenderJob>();
+    let receiver = Arc::new (Mutex::new (receiver));
+    for _ in 0..max (1, threads) {
+      let receiver = receiver.clone();
+      thread::spawn (move || loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+          Ok(mut job) => job(),
+          Err(_) => break,
+        }
+      });
+    }
+    RenderPool {jobs: Mutex::new (sender)}
+  }
+  fn run <F: FnOnce() + Send + 'static> (&self, job: F) {
+    let mut job = Some(job);
+    self.jobs.lock().unwrap().send (Box::new (move || if let Some(job) = job.take() {job()})).unwrap();
+  }
+}
+
+lazy_static! {
+  static ref RENDER_POOL: RenderPool = RenderPool::new (num_cpus::get());
+}
+
+impl<Frame: dsp::Frame + Send + 'static, Frames: Borrow<[Frame]>> PositionedSequence<Frame, Frames>
+  where Frames: FromIterator<Frame> + BorrowMut<[Frame]> {
+  /// Like rendered_from() on the Vec of notes, but renders the notes on a pool of worker threads, then adds them up in order.
+  ///
+  /// The worker threads are shared by every call, so the notes each one has rendered stay cached for later calls.
+  /// Their caches count towards the same memory_cache budget as every other thread's; see memory_cache::total_bytes().
+  /// The result is identical to rendered_from() as long as each note adds one value to each frame, like MIDI notes, sine waves and oscillators do.
+  /// A note that adds up several notes itself, like a nested Vec, may differ in rounding.
+  pub fn rendered_from_parallel <N: Renderable<Frame> + Send + 'static> (notes: Vec<N>, sample_hz: f64)->Self {
+    let earliest = (notes.start()*sample_hz).ceil() as FrameTime;
+    let latest = (notes.end()*sample_hz).floor() as FrameTime;
+    let length = max(0,latest+1-earliest) as usize;
+    let afterend = earliest + length as FrameTime;
+    let mut frames: Frames = iter::repeat(Frame::equilibrium()).take(length).collect();
+
+    // several notes per job, so that passing them around costs less than rendering them
+    let num_notes = notes.len();
+    let batch_size = max (1, num_notes/(num_cpus::get()*8));
+    let (sender, receiver) = mpsc::channel();
+    let mut notes = notes.into_iter().enumerate().peekable();
+    while notes.peek().is_some() {
+      let batch: Vec<(usize, N)> = notes.by_ref().take (batch_size).collect();
+      let sender = sender.clone();
+      RENDER_POOL.run (move || {
+        for (index, note) in batch {
+          let result = panic::catch_unwind (panic::AssertUnwindSafe (|| {
+            // the same part of the timeline that rendered_from() would give the note
+            let note_start = max(earliest, (note.start()*sample_hz).ceil() as FrameTime);
+            let note_afterend = min(afterend, (note.end()*sample_hz).floor() as FrameTime + 1);
+            let mut rendered = vec![Frame::equilibrium(); max(0, note_afterend - note_start) as usize];
+            if !rendered.is_empty() {
+              note.render(&mut rendered, note_start, sample_hz);
+            }
+            (note_start, rendered)
+          }));
+          if sender.send ((index, result)).is_err() { return; }
+        }
+      });
+    }
+    drop(sender);
+
+    let mut finished = HashMap::new();
+    let mut next = 0;
+    for (index, result) in receiver {
+      match result {
+        Ok(rendered) => {finished.insert (index, rendered);},
+        Err(payload) => panic::resume_unwind(payload),
+      }
+      while let Some((note_start, rendered)) = finished.remove (&next) {
+        if !rendered.is_empty() {
+          for (value_mut, value) in frames.borrow_mut()[(note_start-earliest) as usize..].iter_mut().zip (rendered) {
+            *value_mut = value_mut.add_amp(value.to_signed_frame());
+          }
+        }
+        next += 1;
+      }
+    }
+    assert_eq!(next, num_notes, "a render worker stopped without rendering all of its notes");
+
+    PositionedSequence {
+      start: earliest,
+      sample_hz,
+      frames,
+      resampling: Resampling::default(),
+      _marker: PhantomData,
+    }
+  }
+}
   
 
 #[derive (Clone, Debug)]
 pub struct SineWave {
   pub start: NoteTime,
+  // how long the note is held; the envelope may continue after that
   pub duration: NoteTime,
   pub frequency: f64,
   pub amplitude: f64,
+  pub envelope: Envelope,
 }
 
 impl SineWave {
+  pub fn new (start: NoteTime, duration: NoteTime, frequency: f64, amplitude: f64)->SineWave {
+    SineWave {start, duration, frequency, amplitude, envelope: Envelope::default()}
+  }
+  pub fn with_envelope (mut self, envelope: Envelope)->SineWave {
+    self.envelope = envelope;
+    self
+  }
+
   fn value(&self, time: NoteTime)->NoteTime {
     let start = self.start;
     let end = self.end();
     if time < start || time > end { return 0.0; }
-    let envelope_time = if self.duration<1.0 {self.duration*0.05} else {0.05};
-    let envelope =
-      if time < start + envelope_time {
-        (time-start) / envelope_time
-      }
-      else if time > end - envelope_time {
-        (end-time) / envelope_time
-      }
-      else {1.0};
-    //printlnerr!("{:?}", envelope);
+    let envelope = self.envelope.level (time - start, self.duration);
     self.amplitude * envelope * (self.frequency * time * (std::f64::consts::PI * 2.0)).sin()
   }
 }
 
 impl Windowed for SineWave {
   fn start (&self)->NoteTime {self.start}
-  fn end (&self)->NoteTime {self.start+self.duration}
+  fn end (&self)->NoteTime {self.start+self.envelope.duration (self.duration)}
 }
 impl<Frame: dsp::Frame> Renderable<Frame> for SineWave
     where Frame::Sample: dsp::FromSample<f64> {
@@ -274,6 +432,21 @@ impl Dilatable for SineWave {
   }
 }
 
+impl Retimable for SineWave {
+  fn retime(&mut self, map: &Fn(NoteTime)->NoteTime) {
+    let end = map(self.start + self.duration);
+    self.start = map(self.start);
+    self.duration = end - self.start;
+  }
+}
+
+impl Placeable for SineWave {
+  fn place(&mut self, start: NoteTime, duration: NoteTime) {
+    self.start = start;
+    self.duration = duration;
+  }
+}
+
 impl Pitched for SineWave {
   fn frequency(&self)->f64 {self.frequency}
 }
@@ -293,6 +466,7 @@ pub struct MIDIPercussion;
 #[derive (Clone, PartialEq, Eq, Hash, Debug)]
 pub struct FluidsynthDirectlyRenderableMIDIInstrument {
   channel: i32,
+  font: SoundfontId,
   bank: u32,
   preset: u32,
 }
@@ -300,29 +474,47 @@ const PERCUSSION_CHANNEL: i32 = 9;
 impl FluidsynthDirectlyRenderableMIDIInstrument {
   // offsets the program by one to use the same numbers as the General MIDI specification, which numbers the instruments from one rather than 0
   pub fn pitched(program: u32) -> Self {
+    Self::pitched_from_soundfont(soundfont::default_soundfont(), program)
+  }
+  pub fn percussion() -> Self {
+    Self::percussion_from_soundfont(soundfont::default_soundfont())
+  }
+  pub fn pitched_from_soundfont(font: SoundfontId, program: u32) -> Self {
     FluidsynthDirectlyRenderableMIDIInstrument {
+      font,
       bank: 0,
       preset: program - 1,
       channel: 0,
     }
   }
-  pub fn percussion() -> Self {
+  pub fn percussion_from_soundfont(font: SoundfontId) -> Self {
     FluidsynthDirectlyRenderableMIDIInstrument {
+      font,
       bank: 0,
       preset: 0,
       channel: PERCUSSION_CHANNEL,
     }
   }
+  // for soundfonts that have instruments outside the General MIDI bank
+  pub fn with_bank_and_preset(mut self, bank: u32, preset: u32) -> Self {
+    self.bank = bank;
+    self.preset = preset;
+    self
+  }
   pub fn is_percussion(&self) -> bool {
     self.channel == PERCUSSION_CHANNEL
   }
+  pub fn soundfont(&self) -> SoundfontId {
+    self.font
+  }
 }
 
 #[derive (Clone, PartialEq, Eq, Hash, Debug)]
 pub struct FluidsynthDirectlyRenderableMIDINote {
   pub duration: NotNaN<NoteTime>,
   pub pitch: i32,
-  //pub pitch_bend: i32,
+  // in units of 1/PITCH_BEND_UNITS_PER_SEMITONE semitones, relative to the pitch
+  pub pitch_bend: i32,
   pub velocity: i32,
   pub instrument: FluidsynthDirectlyRenderableMIDIInstrument,
 }
@@ -331,6 +523,9 @@ pub struct FluidsynthDirectlyRenderableMIDINote {
 pub struct MIDINote<PitchedOrPercussion> {
   start: NoteTime,
   raw: FluidsynthDirectlyRenderableMIDINote,
+  // these are applied after rendering, so notes with different panning can share the same rendered samples
+  pan: f64,
+  stereo_width: f64,
   _marker: PhantomData<PitchedOrPercussion>,
 }
 
@@ -339,29 +534,61 @@ pub type MIDIPercussionNote = MIDINote<MIDIPercussion>;
 
 impl MIDINote<MIDIPitched> {
   pub fn new(start: f64, duration: f64, pitch: i32, velocity: i32, instrument: u32)->Self {
-    MIDINote {
-      start,
-      raw: FluidsynthDirectlyRenderableMIDINote {
-        duration: NotNaN::new (duration).unwrap(),
-        pitch, velocity,
-        instrument: FluidsynthDirectlyRenderableMIDIInstrument::pitched(instrument),
-      },
-      _marker: PhantomData,
-    }
+    MIDINote::from_raw (start, FluidsynthDirectlyRenderableMIDINote {
+      duration: NotNaN::new (duration).unwrap(),
+      pitch, velocity,
+      pitch_bend: 0,
+      instrument: FluidsynthDirectlyRenderableMIDIInstrument::pitched(instrument),
+    })
+  }
+  /// Uses pitch bend to play the frequency exactly, rather than rounding to the nearest MIDI pitch.
+  pub fn from_frequency(start: f64, duration: f64, frequency: f64, velocity: i32, instrument: u32)->Self {
+    let (pitch, pitch_bend) = frequency_to_midi_pitch_and_bend(frequency);
+    MIDINote::from_raw (start, FluidsynthDirectlyRenderableMIDINote {
+      duration: NotNaN::new (duration).unwrap(),
+      pitch, pitch_bend, velocity,
+      instrument: FluidsynthDirectlyRenderableMIDIInstrument::pitched(instrument),
+    })
   }
 }
 impl MIDINote<MIDIPercussion> {
   pub fn new(start: f64, duration: f64, velocity: i32, instrument: i32)->Self {
+    MIDINote::from_raw (start, FluidsynthDirectlyRenderableMIDINote {
+      duration: NotNaN::new (duration).unwrap(),
+      pitch: instrument, velocity,
+      pitch_bend: 0,
+      instrument: FluidsynthDirectlyRenderableMIDIInstrument::percussion(),
+    })
+  }
+}
+
+impl<PitchedOrPercussion> MIDINote<PitchedOrPercussion> {
+  fn from_raw(start: NoteTime, raw: FluidsynthDirectlyRenderableMIDINote)->Self {
     MIDINote {
-      start,
-      raw: FluidsynthDirectlyRenderableMIDINote {
-        duration: NotNaN::new (duration).unwrap(),
-        pitch: instrument, velocity,
-        instrument: FluidsynthDirectlyRenderableMIDIInstrument::percussion(),
-      },
+      start, raw,
+      pan: 0.0,
+      stereo_width: 1.0,
       _marker: PhantomData,
     }
   }
+  pub fn with_soundfont(mut self, font: SoundfontId)->Self {
+    self.raw.instrument.font = font;
+    self
+  }
+  /// -1.0 is fully left, 1.0 is fully right. Only affects rendering to 2-channel frames.
+  ///
+  /// Panning works like a balance control: it attenuates the far side, so a centered note plays at full volume on both sides.
+  pub fn with_pan(mut self, pan: f64)->Self {
+    self.pan = pan;
+    self
+  }
+  /// 0.0 collapses the soundfont's stereo image to mono, 1.0 leaves it as rendered, and larger values exaggerate it.
+  pub fn with_stereo_width(mut self, stereo_width: f64)->Self {
+    self.stereo_width = stereo_width;
+    self
+  }
+  pub fn pan(&self)->f64 {self.pan}
+  pub fn stereo_width(&self)->f64 {self.stereo_width}
 }
 
 impl<PitchedOrPercussion> Nudgable for MIDINote<PitchedOrPercussion> {
@@ -377,6 +604,22 @@ impl<PitchedOrPercussion> Dilatable for MIDINote<PitchedOrPercussion> {
   }
 }
 
+impl<PitchedOrPercussion> Retimable for MIDINote<PitchedOrPercussion> {
+  fn retime(&mut self, map: &Fn(NoteTime)->NoteTime) {
+    let end = map(self.start + self.raw.duration.into_inner());
+    self.start = map(self.start);
+    self.raw.duration = NotNaN::new (end - self.start).unwrap();
+  }
+}
+
+impl<PitchedOrPercussion> Placeable for MIDINote<PitchedOrPercussion> {
+  fn place(&mut self, start: NoteTime, duration: NoteTime) {
+    self.start = start;
+    self.raw.duration = NotNaN::new (duration).unwrap();
+  }
+}
+
+/// Always 12-tone equal temperament, since that's what MIDI pitches mean; see tuning::Tuning for other tunings.
 pub fn midi_pitch_to_frequency(pitch: i32)->f64 {
   440.0*SEMITONE_RATIO.powi(pitch-69)
 }
@@ -384,12 +627,36 @@ pub fn frequency_to_nearest_midi_pitch(frequency: f64)->i32 {
   ((frequency/440.0).ln()/SEMITONE_RATIO.ln()).round() as i32 + 69
 }
 
+// the General MIDI default pitch bend range is 2 semitones either way,
+// so we use that for rendering too, to make exported MIDI files sound the same
+pub const PITCH_BEND_RANGE_SEMITONES: i32 = 2;
+pub const PITCH_BEND_UNITS_PER_SEMITONE: i32 = 8192/PITCH_BEND_RANGE_SEMITONES;
+
+pub fn midi_pitch_and_bend_to_frequency(pitch: i32, pitch_bend: i32)->f64 {
+  midi_pitch_to_frequency(pitch)*SEMITONE_RATIO.powf(pitch_bend as f64/PITCH_BEND_UNITS_PER_SEMITONE as f64)
+}
+/// Returns the nearest MIDI pitch, and the pitch bend needed to reach the exact frequency from there.
+pub fn frequency_to_midi_pitch_and_bend(frequency: f64)->(i32, i32) {
+  let exact = (frequency/440.0).ln()/SEMITONE_RATIO.ln() + 69.0;
+  let pitch = exact.round();
+  (pitch as i32, ((exact - pitch)*PITCH_BEND_UNITS_PER_SEMITONE as f64).round() as i32)
+}
+
 impl Pitched for MIDINote<MIDIPitched> {
   fn frequency(&self)->f64 {
-    midi_pitch_to_frequency(self.raw.pitch)
+    midi_pitch_and_bend_to_frequency(self.raw.pitch, self.raw.pitch_bend)
   }
 }
 
+impl PitchShiftable for MIDINote<MIDIPitched> {
+  fn pitch_shift(&mut self, frequency_ratio: f64) {
+    let (pitch, pitch_bend) = frequency_to_midi_pitch_and_bend(self.frequency()*frequency_ratio);
+    self.raw.pitch = pitch;
+    self.raw.pitch_bend = pitch_bend;
+  }
+}
+
+// specialized so that transposing doesn't accumulate floating-point error
 impl Transposable for MIDINote<MIDIPitched> {
   fn transpose(&mut self, amount: Semitones) {
     self.raw.pitch += amount as i32;
@@ -400,11 +667,14 @@ impl Transposable for MIDINote<MIDIPitched> {
 struct Fluid {
   settings: fluidsynth::settings::Settings,
   synth: fluidsynth::synth::Synth,
-  font_id: u32,
-  notes: HashMap<FluidsynthDirectlyRenderableMIDINote, Arc<[[f32;2]]>>,
+  // only fonts that loaded successfully, so that a missing font gets retried
+  font_ids: HashMap<SoundfontId, u32>,
+  notes: memory_cache::MemoryCache,
 }
 thread_local! {
   static SYNTHESIZERS: RefCell<HashMap<NotNaN<f64>, Fluid>> = RefCell::new (HashMap::new());
+  // fonts that Renderable has already warned about, so that every buffer doesn't warn again
+  static REPORTED_FONT_ERRORS: RefCell<::std::collections::HashSet<SoundfontId>> = RefCell::new (::std::collections::HashSet::new());
 }
 fn with_fluid <Return, F: FnOnce (&mut Fluid)->Return> (sample_hz: f64, callback: F)->Return {
   SYNTHESIZERS.with (move | synthesizers | {
@@ -413,55 +683,91 @@ fn with_fluid <Return, F: FnOnce (&mut Fluid)->Return> (sample_hz: f64, callback
       let mut settings = fluidsynth::settings::Settings::new();
       settings.setnum("synth.sample-rate", sample_hz);
       settings.setnum("synth.gain", 1.0);
-      let mut synthesizer = fluidsynth::synth::Synth::new(&mut settings);
-      let font_id = synthesizer.sfload("/usr/share/sounds/sf2/FluidR3_GM.sf2", 1).unwrap();
-      Fluid {settings: settings, synth: synthesizer, font_id: font_id, notes: HashMap::new()}
+      let synthesizer = fluidsynth::synth::Synth::new(&mut settings);
+      Fluid {settings: settings, synth: synthesizer, font_ids: HashMap::new(), notes: memory_cache::MemoryCache::default()}
     });
     
     callback (synthesizer)
   })
 }
 
-pub fn with_rendered_midi_note <Return, F: FnOnce (&Arc<[[f32;2]]>)->Return> (note: &FluidsynthDirectlyRenderableMIDINote, sample_hz: f64, callback: F)->Return {
+fn render_midi_note (synth: &mut fluidsynth::synth::Synth, font_ids: &mut HashMap<SoundfontId, u32>, note: &FluidsynthDirectlyRenderableMIDINote, sample_hz: f64)->std::io::Result<Arc<[[f32;2]]>> {
+  if let Some(samples) = disk_cache::load (note, sample_hz) {
+    return Ok(samples);
+  }
+  let font = note.instrument.font;
+  let font_id = match font_ids.get (&font).cloned() {
+    Some(font_id) => font_id,
+    None => {
+      soundfont::check_soundfont (font)?;
+      let path = soundfont::soundfont_path (font);
+      let font_id = path.to_str().and_then (| path | synth.sfload (path, 1)).ok_or_else (|| {
+        std::io::Error::new (std::io::ErrorKind::InvalidData, format!("fluidsynth couldn't load soundfont {:?}", path))
+      })?;
+      font_ids.insert (font, font_id);
+      font_id
+    },
+  };
+  // start from the same state every time, so a note sounds the same whatever this synth rendered before,
+  // which keeps parallel renders identical to serial ones
+  synth.system_reset();
+  if note.instrument.is_percussion() {
+    // fluidsynth keeps drum kits in bank 128
+    synth.program_select(note.instrument.channel, font_id,
+                                      128,
+                                      note.instrument.preset);
+  }
+  else {
+    synth.program_select(note.instrument.channel, font_id,
+                                      note.instrument.bank,
+                                      note.instrument.preset);
+  }
+  synth.pitch_wheel_sens(note.instrument.channel, PITCH_BEND_RANGE_SEMITONES);
+  synth.pitch_bend(note.instrument.channel, 8192 + max(-8192, min(8191, note.pitch_bend)));
+  synth.noteon(note.instrument.channel, note.pitch, note.velocity);
+  let mut left = Vec::new();
+  let mut right = Vec::new();
+  assert! (synth.write_f32 ((note.duration.into_inner()*sample_hz) as usize, &mut left, &mut right));
+  if !note.instrument.is_percussion() {
+    synth.noteoff(note.instrument.channel, note.pitch);
+  }
+  for index in 0..1000 {
+    let duration =(1.0+sample_hz/10.0) as usize;
+    assert! (synth.write_f32 (duration, &mut left, &mut right));
+    // continue rendering until we observe silence
+    if left.iter().rev().take (duration).chain (right.iter().rev().take (duration)).all(| sample | (sample.abs() < 0.000001)) {
+      break;
+    }
+    assert!(index <900);
+  }
+  while let (Some(left_sample), Some(right_sample)) = (left.pop(), right.pop()) {
+    if left_sample.abs() > 0.000001 || right_sample.abs() > 0.000001 {
+      left.push (left_sample) ;
+      right.push (right_sample) ;
+      break
+    }
+  }
+  
+  let samples: Arc<[[f32;2]]> = left.into_iter().zip (right.into_iter()).map (|(l,r)| [l,r]).collect::<Vec<_>>().into_boxed_slice().into();
+  disk_cache::store (note, sample_hz, &samples);
+  Ok(samples)
+}
+
+/// Passes the note's samples to `callback`, rendering them if they aren't cached.
+///
+/// Fails if the note's soundfont can't be loaded. Failures aren't cached, so fixing the file fixes later calls.
+pub fn with_rendered_midi_note <Return, F: FnOnce (&Arc<[[f32;2]]>)->Return> (note: &FluidsynthDirectlyRenderableMIDINote, sample_hz: f64, callback: F)->std::io::Result<Return> {
   with_fluid (sample_hz, | fluid | {
-    let samples = {
-      let synth = &mut fluid.synth;
-      let font_id = fluid.font_id;
-      fluid.notes.entry (note.clone()).or_insert_with(|| {
-        if !note.instrument.is_percussion() {
-          synth.program_select(note.instrument.channel, font_id,
-                                            note.instrument.bank,
-                                            note.instrument.preset);
-        }
-        synth.noteon(note.instrument.channel, note.pitch, note.velocity);
-        let mut left = Vec::new();
-        let mut right = Vec::new();
-        assert! (synth.write_f32 ((note.duration.into_inner()*sample_hz) as usize, &mut left, &mut right));
-        if !note.instrument.is_percussion() {
-          synth.noteoff(note.instrument.channel, note.pitch);
-        }
-        for index in 0..1000 {
-          let duration =(1.0+sample_hz/10.0) as usize;
-          assert! (synth.write_f32 (duration, &mut left, &mut right));
-          // continue rendering until we observe silence
-          if left.iter().rev().take (duration).chain (right.iter().rev().take (duration)).all(| sample | (sample.abs() < 0.000001)) {
-            break;
-          }
-          assert!(index <900);
-        }
-        while let (Some(left_sample), Some(right_sample)) = (left.pop(), right.pop()) {
-          if left_sample.abs() > 0.000001 || right_sample.abs() > 0.000001 {
-            left.push (left_sample) ;
-            right.push (right_sample) ;
-            break
-          }
-        }
-        
-        left.into_iter().zip (right.into_iter()).map (|(l,r)| [l,r]).collect::<Vec<_>>().into_boxed_slice().into()
-      })
+    let samples = match fluid.notes.get (note) {
+      Some(samples) => samples,
+      None => {
+        let samples = render_midi_note (&mut fluid.synth, &mut fluid.font_ids, note, sample_hz)?;
+        fluid.notes.insert (note.clone(), samples.clone());
+        samples
+      }
     };
     
-    callback(samples)
+    Ok(callback(&samples))
   })
 }
 
@@ -472,22 +778,41 @@ impl<PitchedOrPercussion> Windowed for MIDINote<PitchedOrPercussion> {
 impl<PitchedOrPercussion, Frame: dsp::Frame> Renderable<Frame> for MIDINote<PitchedOrPercussion> 
     where Frame::Sample: dsp::FromSample<f32> {
   fn render(&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
-    with_rendered_midi_note (&self.raw, sample_hz, | samples | {
+    let stereo = Frame::n_channels() == 2;
+    let pan = self.pan.max (-1.0).min (1.0) as f32;
+    let left_gain = (1.0 - pan).min (1.0);
+    let right_gain = (1.0 + pan).min (1.0);
+    let stereo_width = self.stereo_width as f32;
+    let result = with_rendered_midi_note (&self.raw, sample_hz, | samples | {
       let rounded_note_start = (self.start*sample_hz).round() as FrameTime;
       for (index, value_mut) in buffer.iter_mut().enumerate() {
         let rendered_index = ((index as FrameTime + start) - rounded_note_start) as usize;
-        let value = Frame::Sample::from_sample(if let Some([left, right]) = samples.get(rendered_index) {
+        let (left, right) = if let Some([left, right]) = samples.get(rendered_index) {
           assert!(left.is_finite());
           assert!(right.is_finite());
-          // hack: convert stereo to mono
-          (left + right)*0.5
+          let middle = (left + right)*0.5;
+          let side = (left - right)*0.5*stereo_width;
+          ((middle + side)*left_gain, (middle - side)*right_gain)
+        }
+        else {
+          (0.0, 0.0)
+        };
+        let frame = if stereo {
+          Frame::from_fn(|channel| Frame::Sample::from_sample(if channel == 0 {left} else {right}))
         }
         else {
-          0.0
-        });
-        *value_mut = value_mut.add_amp(Frame::from_fn(|_| value).to_signed_frame());
+          let value = Frame::Sample::from_sample((left + right)*0.5);
+          Frame::from_fn(|_| value)
+        };
+        *value_mut = value_mut.add_amp(frame.to_signed_frame());
       }
-    })
+    });
+    if let Err(error) = result {
+      let font = self.raw.instrument.font;
+      if REPORTED_FONT_ERRORS.with (| reported | reported.borrow_mut().insert (font)) {
+        printlnerr!("codecophony: {}; notes using that soundfont will be silent", error);
+      }
+    }
   }
 }
 
@@ -500,219 +825,8 @@ pub fn enforce_maximum<Frame: dsp::Frame<Sample = i32>>(sequence: &mut [Frame],
     return;
   }
   for frame in sequence.iter_mut() {
-    *frame = frame.map(|sample| (sample * forced_maximum * 2 + maximum) / maximum*2);
+    *frame = frame.map(|sample| ((sample as i64 * forced_maximum as i64 * 2 + maximum as i64) / (maximum as i64 * 2)) as i32);
   }
 }
 
 pub mod interval_optimizer; 
-
-
-// trait Interpreter
-
-/*
-struct BasicInterpreter<Render: Renderer> {
-  notes: Vec<Render>,
-  now: f64,
-  step_size: f64,
-  sustained_notes: HashMap<Semitones, Note<Render>>,
-  latest_notes: HashMap<Semitones, Note<Render>>,
-  command_in_progress: Option<String>,
-}
-
-
-
-trait InterpreterCaller <Render: Renderer> {
-  fn create(&mut self, semitones: Semitones) -> Render;
-}
-impl<Render: Renderer> Default for BasicInterpreter<Render> {
-  fn default() -> Self {
-    BasicInterpreter::<Render> {
-      now: 0.0,
-      step_size: 1.0,
-      notes: Default::default(),
-      sustained_notes: Default::default(),
-      latest_notes: Default::default(),
-      command_in_progress: None,
-    }
-  }
-}
-impl<Render: Renderer> BasicInterpreter<Render> {
-  fn finish_note(&mut self, note: Note<Render>) {
-    let mut note = note;
-    note.basics.duration = self.now - note.basics.start;//note.set_end (self.now);
-    self.notes.push(note);
-  }
-  fn finish_notes(&mut self) {
-    let last_begin = self.latest_notes.values().fold(-900000000.0f64, |max, note: &Note<Render>| {
-      if note.basics.start > max {
-        note.basics.start
-      } else {
-        max
-      }
-    });
-    let step_end = last_begin + self.step_size;
-    if step_end > self.now {
-      self.now = step_end
-    };
-
-    for (_, note) in self.latest_notes.clone().iter() {
-      self.finish_note(note.clone());
-    }
-    self.latest_notes.clear();
-
-  }
-
-  fn create_note<Caller: InterpreterCaller<Render>>(&mut self,
-                                                    caller: &mut Caller,
-                                                    semitones: Semitones)
-                                                    -> Note<Render> {
-    Note::<Render> {
-      basics: NoteBasics {
-        start: self.now,
-        duration: 0.0,
-      },
-      renderer: caller.create(semitones),
-    }
-  }
-
-  fn interpret<Caller: InterpreterCaller<Render>>(&mut self, caller: &mut Caller, command: &str) {
-    match self.command_in_progress.clone() {
-
-      None => {
-        match Semitones::from_str(command) {
-          Ok(semitones) => {
-            self.finish_notes();
-            let note = self.create_note(caller, semitones);
-            self.latest_notes.insert(semitones, note);
-          }
-          Err(_) => {
-            match command {
-              "finish" => self.finish_notes(),
-              _ => self.command_in_progress = Some(command.to_string()),
-            }
-          }
-        }
-      }
-      Some(last_command) => {
-        match &*last_command {
-          "and" => {
-            let semitones = Semitones::from_str(command).unwrap();
-            let note = self.create_note(caller, semitones);
-            self.latest_notes.insert(semitones, note);
-          }
-          "sustain" => {
-            let semitones = Semitones::from_str(command).unwrap();
-            let note = self.create_note(caller, semitones);
-            self.sustained_notes.insert(semitones, note);
-          }
-          "release" => {
-            match Semitones::from_str(command) {
-              Ok(semitones) => {
-                let note = self.sustained_notes.remove(&semitones).unwrap();
-                self.finish_note(note);
-              }
-              Err(_) => {
-                for (_, note) in self.sustained_notes.clone().iter() {
-                  self.finish_note(note.clone());
-                }
-                self.sustained_notes.clear();
-              }
-            }
-          }
-          "step" => {
-            self.step_size = f64::from_str(command).unwrap();
-          }
-          "advance" => {
-            let distance = f64::from_str(command).unwrap();
-            assert!(distance >= 0.0);
-            self.now += distance;
-          }
-          "at" => {
-            let time = f64::from_str(command).unwrap();
-            if time < self.now {
-              self.finish_notes();
-            }
-            self.now = time;
-          }
-          _ => panic!(),
-        };
-        self.command_in_progress = None;
-      }
-
-    }
-  }
-}
-
-struct MIDIInterpreter {
-  prototype: MIDINote,
-  velocity_adjustment: i16,
-  command_in_progress: Option<String>,
-}
-
-impl InterpreterCaller<MIDINote> for MIDIInterpreter {
-  fn create(&mut self, semitones: Semitones) -> MIDINote {
-    let mut velocity = self.prototype.velocity;
-    while self.velocity_adjustment > 0 {
-      self.velocity_adjustment -= 1;
-      velocity = (velocity * 2 + 128) / 3;
-    }
-    while self.velocity_adjustment < 0 {
-      self.velocity_adjustment += 1;
-      velocity = (velocity * 2) / 3;
-    }
-    MIDINote {
-      pitch: self.prototype.pitch + semitones as i32,
-      velocity: velocity,
-      ..self.prototype.clone()
-    }
-  }
-}
-
-impl MIDIInterpreter {
-  fn interpret(&mut self, basics: &mut BasicInterpreter<MIDINote>, command: &str) {
-    match self.command_in_progress.clone() {
-      None => {
-        match command {
-          "strong" => self.velocity_adjustment += 1,
-          "quiet" => self.velocity_adjustment -= 1,
-          "percussion" => self.prototype.instrument = MIDIInstrument::percussion(),
-          parametric@"instrument" | parametric@"velocity" | parametric@"transpose" => {
-            self.command_in_progress = Some(parametric.to_string())
-          }
-          other => basics.interpret(self, other),
-        }
-      }
-      Some(last_command) => {
-        match &*last_command {
-          "instrument" => {
-            self.prototype.instrument = MIDIInstrument::new(u32::from_str(command).unwrap())
-          }
-          "velocity" => self.prototype.velocity = i32::from_str(command).unwrap(),
-          "transpose" => self.prototype.pitch = i32::from_str(command).unwrap(),
-          _ => panic!(),
-        };
-        self.command_in_progress = None;
-      }
-    }
-  }
-}
-
-pub fn scrawl_MIDI_notes(scrawl: &str) -> Vec<MIDINote> {
-  let mut basics = BasicInterpreter::<MIDINote>::default();
-  let mut specifics = MIDIInterpreter {
-    velocity_adjustment: 0,
-    prototype: MIDINote {
-      pitch: 0,
-      velocity: 64,
-      instrument: MIDIInstrument::new(88),
-    },
-    command_in_progress: None,
-  };
-  for command in scrawl.split_whitespace() {
-    specifics.interpret(&mut basics, command);
-  }
-  basics.finish_notes();
-  basics.notes
-}
-
-*/
@@ -0,0 +1,277 @@
+use super::*;
+
+use std::io::{self, Write};
+use std::fs::File;
+use std::path::Path;
+
+use phrase::Phrase;
+use theory::{Key, Pitch};
+use musicxml::{ScorePart, Event, NoteShape, note_shape, note_pieces, bars_needed, mode_name, default_parts, assign_notes_to_parts};
+
+
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct LilyPondParameters {
+  pub title: String,
+  /// Converts the notes' times, in seconds, to beats, and gives the time signatures and tempo marks.
+  /// If the notes' times are already in beats, use `TempoMap::new (60.0)`.
+  pub tempo: TempoMap,
+  /// Gives the key signature and the spelling of each pitch.
+  pub key: Key,
+  /// The grid that notes are quantized to, in divisions of a quarter note. 4 quantizes to sixteenth notes, and 12 allows triplets too. It must be at least 1.
+  pub divisions: u32,
+  /// One staff for each part. Parts that get no notes are left out, and percussion parts get drum staves.
+  pub staves: Vec<ScorePart>,
+}
+
+impl Default for LilyPondParameters {
+  fn default()->Self {
+    LilyPondParameters {
+      title: String::new(),
+      tempo: TempoMap::default(),
+      key: Key::major (60),
+      divisions: 4,
+      staves: default_parts(),
+    }
+  }
+}
+
+/// The LilyPond drum name for a General MIDI percussion key, from 35 (acoustic bass drum) to 81 (open triangle).
+pub fn drum_name (key: i32)->Option<&'static str> {
+  const NAMES: [&str; 47] = [
+    "acousticbassdrum", "bassdrum", "sidestick", "acousticsnare", "handclap", "electricsnare", "lowfloortom", "closedhihat",
+    "highfloortom", "pedalhihat", "lowtom", "openhihat", "lowmidtom", "himidtom", "crashcymbala", "hightom",
+    "ridecymbala", "chinesecymbal", "ridebell", "tambourine", "splashcymbal", "cowbell", "crashcymbalb", "vibraslap",
+    "ridecymbalb", "hibongo", "lobongo", "mutehiconga", "openhiconga", "loconga", "hitimbale", "lotimbale",
+    "hiagogo", "loagogo", "cabasa", "maracas", "shortwhistle", "longwhistle", "shortguiro", "longguiro",
+    "claves", "hiwoodblock", "lowoodblock", "mutecuica", "opencuica", "mutetriangle", "opentriangle",
+  ];
+  if key < 35 { return None; }
+  NAMES.get ((key - 35) as usize).cloned()
+}
+
+fn escape (text: &str)->String {
+  text.replace ('\\', "\\\\").replace ('"', "\\\"")
+}
+
+// a duration as a fraction of a whole note, for lengths that aren't any single note
+fn scaled_duration (length: i64, divisions: i64)->String {
+  format!("1*{}/{}", length, divisions*4)
+}
+
+fn duration (length: i64, shape: Option<NoteShape>, divisions: i64)->String {
+  match shape {
+    Some(shape) => format!("{}{}", 1 << shape.type_index, ".".repeat (shape.dots as usize)),
+    None => scaled_duration (length, divisions),
+  }
+}
+
+// the Dutch note name, which LilyPond reads by default, without the octave
+fn step_name (pitch: Pitch, key: &Key)->String {
+  let spelled = key.spell (pitch);
+  let mut result = spelled.letter.to_lowercase().to_string();
+  for _ in 0..spelled.alter { result.push_str ("is"); }
+  for _ in spelled.alter..0 { result.push_str ("es"); }
+  result
+}
+
+// a duration outside any tuplet, like a whole bar or a spacer
+fn plain_duration (length: i64, divisions: i64)->String {
+  match note_shape (length, divisions) {
+    Some(shape) if !shape.triplet => duration (length, Some(shape), divisions),
+    _ => scaled_duration (length, divisions),
+  }
+}
+
+fn pitch_name (pitch: Pitch, key: &Key)->String {
+  let spelled = key.spell (pitch);
+  let mut result = step_name (pitch, key);
+  // LilyPond's unmarked octave is the one below middle C
+  for _ in 3..spelled.octave { result.push ('\''); }
+  for _ in spelled.octave..3 { result.push (','); }
+  result
+}
+
+// picks the clef that needs the fewest ledger lines, preferring the plain clefs
+fn clef (voices: &[Vec<Event>])->&'static str {
+  let pitches: Vec<Pitch> = voices.iter().flat_map (| voice | voice.iter()).flat_map (| event | event.pitches.iter().cloned()).collect();
+  // the clefs with the lowest and highest pitch of their staves, and a penalty for the octave clefs
+  let clefs = [("treble", 64, 77, 0), ("bass", 43, 57, 0), ("\"treble^8\"", 76, 89, 3), ("\"bass_8\"", 31, 45, 3)];
+  clefs.iter().min_by_key (| &&(_, low, high, penalty) | {
+    penalty*pitches.len() as i32 + pitches.iter().map (| &pitch | max (low - pitch, 0) + max (pitch - high, 0)).sum::<i32>()
+  }).unwrap().0
+}
+
+// one note, chord, rest or skip written in a bar
+struct Item {
+  text: String,
+  length: i64,
+  triplet: bool,
+}
+
+fn rest_items (output: &mut Vec<Item>, length: i64, divisions: i64, rest: &str) {
+  for (piece, shape) in note_pieces (length, divisions) {
+    output.push (Item {text: format!("{}{}", rest, duration (piece, shape, divisions)), length: piece, triplet: shape.map_or (false, | shape | shape.triplet)});
+  }
+}
+
+// the part of `event` from `start` to `end`, tied to the rest of it
+fn event_items (output: &mut Vec<Item>, event: &Event, start: i64, end: i64, divisions: i64, key: &Key, percussion: bool) {
+  let names: Vec<String> = event.pitches.iter().map (| &pitch | {
+    if percussion {
+      drum_name (pitch).unwrap_or ("snare").to_string()
+    }
+    else {
+      pitch_name (pitch, key)
+    }
+  }).collect();
+  let mut position = start;
+  for (piece, shape) in note_pieces (end - start, divisions) {
+    let mut text = if names.len() == 1 {names [0].clone()} else {format!("<{}>", names.join (" "))};
+    text.push_str (&duration (piece, shape, divisions));
+    position += piece;
+    if position < event.end { text.push_str (" ~"); }
+    output.push (Item {text, length: piece, triplet: shape.map_or (false, | shape | shape.triplet)});
+  }
+}
+
+// writes the items, with runs of triplets grouped into tuplets that each fill whole beats
+fn write_items (output: &mut String, items: &[Item], divisions: i64) {
+  let mut tuplet_length = None;
+  for item in items {
+    if !item.triplet && tuplet_length.is_some() {
+      output.push_str ("} ");
+      tuplet_length = None;
+    }
+    if item.triplet && tuplet_length.is_none() {
+      output.push_str ("\\tuplet 3/2 { ");
+      tuplet_length = Some(0);
+    }
+    output.push_str (&item.text);
+    output.push (' ');
+    if let Some(length) = tuplet_length {
+      let length = length + item.length;
+      tuplet_length = if length % divisions == 0 {
+        output.push_str ("} ");
+        None
+      } else {Some(length)};
+    }
+  }
+  if tuplet_length.is_some() { output.push_str ("} "); }
+}
+
+fn write_voice (output: &mut String, voice: &[Event], voice_index: usize, bars: i64, percussion: bool, parameters: &LilyPondParameters) {
+  let divisions = parameters.divisions as i64;
+  let tempo = &parameters.tempo;
+  let bar_start = | bar: i64 | (tempo.bar_start (bar)*divisions as f64).round() as i64;
+  for bar in 0..bars {
+    let (start, end) = (bar_start (bar), bar_start (bar + 1));
+    let events: Vec<&Event> = voice.iter().filter (| event | event.start < end && event.end > start).collect();
+    output.push_str ("      ");
+    if events.is_empty() {
+      // lower voices skip the bars they have nothing in, so only one whole-bar rest shows
+      output.push_str (&format!("{}{} ", if voice_index == 0 {"R"} else {"s"}, plain_duration (end - start, divisions)));
+    }
+    else {
+      let mut items = Vec::new();
+      let mut position = start;
+      for event in events {
+        let event_start = max (event.start, start);
+        if event_start > position { rest_items (&mut items, event_start - position, divisions, "r"); }
+        position = min (event.end, end);
+        event_items (&mut items, event, event_start, position, divisions, &parameters.key, percussion);
+      }
+      if position < end { rest_items (&mut items, end - position, divisions, "r"); }
+      write_items (output, &items, divisions);
+    }
+    output.push_str (&format!("| % {}\n", bar + 1));
+  }
+}
+
+// the key, time signatures and tempo marks, as spacer rests that every staff shares
+fn write_global (output: &mut String, bars: i64, parameters: &LilyPondParameters) {
+  let divisions = parameters.divisions as i64;
+  let tempo = &parameters.tempo;
+  let bar_start = | bar: i64 | (tempo.bar_start (bar)*divisions as f64).round() as i64;
+  let key = &parameters.key;
+  output.push_str (&format!("global = {{\n  \\key {} \\{}\n", step_name (key.tonic, key), mode_name (key)));
+  for bar in 0..bars {
+    let (start, end) = (bar_start (bar), bar_start (bar + 1));
+    output.push_str ("  ");
+    if bar == 0 || tempo.meter_changes().iter().any (| change | change.bar == bar) {
+      let time_signature = tempo.time_signature_at_bar (bar);
+      output.push_str (&format!("\\time {}/{} ", time_signature.numerator, time_signature.denominator));
+    }
+    let mut position = start;
+    for change in tempo.tempo_changes() {
+      let change_position = (change.beat*divisions as f64).round() as i64;
+      if change_position >= start && change_position < end {
+        if change_position > position { output.push_str (&format!("s{} ", plain_duration (change_position - position, divisions))); }
+        position = change_position;
+        output.push_str (&format!("\\tempo 4 = {} ", change.beats_per_minute.round()));
+      }
+    }
+    output.push_str (&format!("s{} |\n", plain_duration (end - position, divisions)));
+  }
+  output.push_str ("}\n\n");
+}
+
+fn write_staff (output: &mut String, voices: &[Vec<Event>], bars: i64, staff: &ScorePart, parameters: &LilyPondParameters) {
+  let (staff_context, voice_context) = if staff.percussion {("DrumStaff", "DrumVoice")} else {("Staff", "Voice")};
+  output.push_str (&format!("    \\new {} \\with {{ instrumentName = \"{}\" }} <<\n      \\global\n", staff_context, escape (&staff.name)));
+  const VOICE_NAMES: [&str; 4] = ["One", "Two", "Three", "Four"];
+  for (voice_index, voice) in voices.iter().enumerate() {
+    output.push_str (&format!("      \\new {} {{", voice_context));
+    if voices.len() > 1 {
+      match VOICE_NAMES.get (voice_index) {
+        Some(name) => output.push_str (&format!(" \\voice{}", name)),
+        None => output.push_str (" \\voiceFour"),
+      }
+    }
+    if staff.percussion {
+      output.push_str (" \\drummode {\n");
+    }
+    else {
+      output.push_str (&format!(" \\clef {}\n", clef (voices)));
+    }
+    write_voice (output, voice, voice_index, bars, staff.percussion, parameters);
+    if staff.percussion { output.push_str ("      }\n"); }
+    output.push_str ("      }\n");
+  }
+  output.push_str ("    >>\n");
+}
+
+/// Writes the phrase as LilyPond source, with notes quantized to the grid and split at barlines with ties.
+/// Overlapping notes go in separate voices, and notes that only fit the grid as triplets are grouped into tuplets.
+pub fn to_lilypond (phrase: &Phrase, parameters: &LilyPondParameters)->String {
+  let staves = assign_notes_to_parts (phrase, &parameters.staves, &parameters.tempo, parameters.divisions);
+
+  let unknown_drums = staves.iter().filter (| &&(staff, _) | staff.percussion).flat_map (| &(_, ref voices) | voices.iter()).flat_map (| voice | voice.iter())
+    .flat_map (| event | event.pitches.iter()).filter (| &&pitch | drum_name (pitch).is_none()).count();
+  if unknown_drums > 0 {
+    printlnerr!("Warning: {} percussion notes weren't General MIDI drums, and were written as snare", unknown_drums);
+  }
+
+  let bars = bars_needed (staves.iter().flat_map (| &(_, ref voices) | voices.iter()), &parameters.tempo, parameters.divisions);
+
+  let mut output = String::new();
+  output.push_str ("\\version \"2.18.2\"\n\n");
+  if !parameters.title.is_empty() {
+    output.push_str (&format!("\\header {{\n  title = \"{}\"\n}}\n\n", escape (&parameters.title)));
+  }
+  write_global (&mut output, bars, parameters);
+  output.push_str ("\\score {\n  <<\n");
+  for &(staff, ref voices) in staves.iter() {
+    write_staff (&mut output, voices, bars, staff, parameters);
+  }
+  output.push_str ("  >>\n  \\layout { }\n}\n");
+  output
+}
+
+/// Writes MIDI notes as LilyPond source, with the percussion on a drum staff.
+pub fn midi_to_lilypond (pitched: &[MIDIPitchedNote], percussion: &[MIDIPercussionNote], parameters: &LilyPondParameters)->String {
+  to_lilypond (&Phrase::from_midi (pitched, percussion), parameters)
+}
+
+pub fn write_lilypond <P: AsRef<Path>> (phrase: &Phrase, path: P, parameters: &LilyPondParameters)->io::Result<()> {
+  File::create (path)?.write_all (to_lilypond (phrase, parameters).as_bytes())
+}
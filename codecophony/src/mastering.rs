@@ -0,0 +1,296 @@
+use super::*;
+
+use std::f64::consts::PI;
+use dsp::sample::ToSample;
+
+use effects::decibels_to_amplitude;
+
+
+fn amplitude_to_decibels (amplitude: f64)->f64 {
+  20.0*amplitude.log10()
+}
+
+fn channels_of <Frame: dsp::Frame> (frames: &[Frame])->Vec<Vec<f64>>
+    where Frame::Sample: ToSample<f64> {
+  (0..Frame::n_channels()).map (| channel | frames.iter().map (| frame | frame.channel (channel).unwrap().to_sample()).collect()).collect()
+}
+
+fn apply_gains <Frame: dsp::Frame, G: Fn(usize)->f64> (frames: &mut [Frame], gain: G)
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  for (index, frame) in frames.iter_mut().enumerate() {
+    let gain = gain (index);
+    *frame = frame.map (| sample: Frame::Sample | Frame::Sample::from_sample (sample.to_sample::<f64>()*gain));
+  }
+}
+
+/// The largest absolute sample value.
+pub fn sample_peak <Frame: dsp::Frame> (frames: &[Frame])->f64
+    where Frame::Sample: ToSample<f64> {
+  frames.iter().flat_map (| frame | frame.channels()).fold (0.0f64, | peak, sample | peak.max (sample.to_sample::<f64>().abs()))
+}
+
+/// Scales everything so that the largest sample is at `target_decibels` relative to full scale.
+/// Silence is left alone.
+pub fn normalize_peak <Frame: dsp::Frame> (frames: &mut [Frame], target_decibels: f64)
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  let peak = sample_peak (frames);
+  if peak == 0.0 { return; }
+  let gain = decibels_to_amplitude (target_decibels)/peak;
+  apply_gains (frames, | _ | gain);
+}
+
+
+// BS.1770 measures true peaks by oversampling 4 times
+const OVERSAMPLING: usize = 4;
+const OVERSAMPLING_ZERO_CROSSINGS: usize = 8;
+
+// the interpolation filter for each point between two samples, starting at the sample `first_tap` samples away
+struct Oversampler {
+  first_tap: isize,
+  phases: Vec<Vec<f64>>,
+}
+
+impl Oversampler {
+  fn new()->Oversampler {
+    let reach = OVERSAMPLING_ZERO_CROSSINGS as isize;
+    let first_tap = 1 - reach;
+    let phases = (1..OVERSAMPLING).map (| phase | {
+      let fraction = phase as f64/OVERSAMPLING as f64;
+      let weights: Vec<f64> = (first_tap..reach + 1).map (| tap | resampling::kernel (fraction - tap as f64, 1.0, OVERSAMPLING_ZERO_CROSSINGS)).collect();
+      let total: f64 = weights.iter().sum();
+      weights.into_iter().map (| weight | weight/total).collect()
+    }).collect();
+    Oversampler {first_tap, phases}
+  }
+
+  // the largest absolute value at or after the sample at `index`, up to the next sample
+  fn peak_after (&self, samples: &[f64], index: usize)->f64 {
+    let mut peak = samples [index].abs();
+    for weights in self.phases.iter() {
+      let mut value = 0.0;
+      for (tap, weight) in weights.iter().enumerate() {
+        let source = index as isize + self.first_tap + tap as isize;
+        if source >= 0 && (source as usize) < samples.len() {
+          value += samples [source as usize]*weight;
+        }
+      }
+      peak = peak.max (value.abs());
+    }
+    peak
+  }
+}
+
+// the true peak between each frame and the next, across all channels
+fn true_peaks (channels: &[Vec<f64>])->Vec<f64> {
+  let oversampler = Oversampler::new();
+  let length = channels.first().map_or (0, | channel | channel.len());
+  (0..length).map (| index | channels.iter().fold (0.0f64, | peak, channel | peak.max (oversampler.peak_after (channel, index)))).collect()
+}
+
+/// The largest absolute value of the signal, including peaks between samples, estimated by oversampling like ITU-R BS.1770.
+pub fn true_peak <Frame: dsp::Frame> (frames: &[Frame])->f64
+    where Frame::Sample: ToSample<f64> {
+  true_peaks (&channels_of (frames)).into_iter().fold (0.0f64, f64::max)
+}
+
+
+/// A limiter that sees peaks coming, so it can turn things down smoothly before they arrive instead of clipping them.
+#[derive (Clone, Debug)]
+pub struct TruePeakLimiter {
+  /// The highest true peak allowed, relative to full scale.
+  pub ceiling_decibels: f64,
+  /// How long before a peak the limiter starts turning things down.
+  pub lookahead: NoteTime,
+  /// How long the limiter takes to recover afterwards.
+  pub release: NoteTime,
+}
+
+impl Default for TruePeakLimiter {
+  fn default()->Self {
+    TruePeakLimiter {
+      ceiling_decibels: -1.0,
+      lookahead: 0.005,
+      release: 0.1,
+    }
+  }
+}
+
+impl TruePeakLimiter {
+  pub fn apply <Frame: dsp::Frame> (&self, frames: &mut [Frame], sample_hz: f64)
+      where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+    let ceiling = decibels_to_amplitude (self.ceiling_decibels);
+    let lookahead = max(1, (self.lookahead*sample_hz).round() as usize);
+    let release_factor = if self.release <= 0.0 { 0.0 } else { (-1.0/(self.release*sample_hz)).exp() };
+
+    // Smoothing the gain lets the peaks between samples move a little, so check again afterwards.
+    for _ in 0..4 {
+      let peaks = true_peaks (&channels_of (frames));
+      if peaks.iter().all (| &peak | peak <= ceiling*1.0001) { return; }
+      let needed: Vec<f64> = peaks.iter().map (| &peak | if peak > ceiling { ceiling/peak } else { 1.0 }).collect();
+
+      // the smallest gain needed anywhere in the next `lookahead` frames
+      let mut lowest_ahead = vec![1.0; needed.len()];
+      let mut window: ::std::collections::VecDeque<usize> = ::std::collections::VecDeque::new();
+      for index in (0..needed.len()).rev() {
+        while window.back().map_or (false, | &other | needed [other] >= needed [index]) { window.pop_back(); }
+        window.push_back (index);
+        while *window.front().unwrap() >= index + lookahead { window.pop_front(); }
+        lowest_ahead [index] = needed [*window.front().unwrap()];
+      }
+
+      // Averaging over the lookahead ramps the gain down smoothly, and because every value averaged
+      // before a peak is at most the gain that peak needs, the average is too.
+      // There's nothing before the start to ramp down over, so start out already turned down.
+      let initial = lowest_ahead.first().cloned().unwrap_or (1.0);
+      let mut gains = Vec::with_capacity (needed.len());
+      let mut sum = initial*lookahead as f64;
+      let mut gain: f64 = 1.0;
+      for index in 0..needed.len() {
+        sum += lowest_ahead [index];
+        sum -= if index >= lookahead { lowest_ahead [index - lookahead] } else { initial };
+        let smoothed = sum/lookahead as f64;
+        gain = if smoothed < gain { smoothed } else { smoothed + (gain - smoothed)*release_factor };
+        gains.push (gain);
+      }
+      apply_gains (frames, | index | gains [index]);
+    }
+
+    // if the last pass still left a peak over the ceiling, turn everything down by the rest
+    let peak = true_peak (frames);
+    if peak > ceiling {
+      apply_gains (frames, | _ | ceiling/peak);
+    }
+  }
+}
+
+
+// K-weighting, from BS.1770, with the coefficients worked out for any sample rate the same way libebur128 does
+struct KWeighting {
+  stages: [([f64; 3], [f64; 2]); 2],
+}
+
+impl KWeighting {
+  fn new (sample_hz: f64)->KWeighting {
+    // the high shelf, modelling the head
+    let k = (PI*1681.974450955533/sample_hz).tan();
+    let q = 0.7071752369554196;
+    let shelf_gain = decibels_to_amplitude (3.999843853973347);
+    let band_gain = shelf_gain.powf (0.4996667741545416);
+    let a0 = 1.0 + k/q + k*k;
+    let shelf = (
+      [(shelf_gain + band_gain*k/q + k*k)/a0, 2.0*(k*k - shelf_gain)/a0, (shelf_gain - band_gain*k/q + k*k)/a0],
+      [2.0*(k*k - 1.0)/a0, (1.0 - k/q + k*k)/a0],
+    );
+    // the high pass
+    let k = (PI*38.13547087602444/sample_hz).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k/q + k*k;
+    let high_pass = ([1.0, -2.0, 1.0], [2.0*(k*k - 1.0)/a0, (1.0 - k/q + k*k)/a0]);
+    KWeighting {stages: [shelf, high_pass]}
+  }
+
+  fn filter (&self, samples: &[f64])->Vec<f64> {
+    let mut result = samples.to_vec();
+    for &(b, a) in self.stages.iter() {
+      let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+      for sample in result.iter_mut() {
+        let x = *sample;
+        let y = b[0]*x + b[1]*x1 + b[2]*x2 - a[0]*y1 - a[1]*y2;
+        x2 = x1; x1 = x; y2 = y1; y1 = y;
+        *sample = y;
+      }
+    }
+    result
+  }
+}
+
+fn block_loudness (mean_square: f64)->f64 {
+  -0.691 + 10.0*mean_square.log10()
+}
+
+/// The integrated loudness in LUFS, following ITU-R BS.1770-4, with every channel weighted equally
+/// (which is what the standard does for mono and stereo).
+///
+/// Returns None if there isn't at least one 400 ms block louder than -70 LUFS.
+pub fn integrated_loudness <Frame: dsp::Frame> (frames: &[Frame], sample_hz: f64)->Option<f64>
+    where Frame::Sample: ToSample<f64> {
+  let weighting = KWeighting::new (sample_hz);
+  let weighted: Vec<Vec<f64>> = channels_of (frames).iter().map (| channel | weighting.filter (channel)).collect();
+
+  // 400 ms blocks, overlapping by 75%
+  let block_length = (0.4*sample_hz).round() as usize;
+  let step = max(1, block_length/4);
+  if block_length == 0 || frames.len() < block_length { return None; }
+  let mut blocks = Vec::new();
+  let mut block_start = 0;
+  while block_start + block_length <= frames.len() {
+    let mean_square: f64 = weighted.iter().map (| channel | {
+      channel [block_start..block_start + block_length].iter().map (| sample | sample*sample).sum::<f64>()/block_length as f64
+    }).sum();
+    blocks.push (mean_square);
+    block_start += step;
+  }
+
+  let gated_mean = | threshold: f64 | {
+    let loud: Vec<f64> = blocks.iter().cloned().filter (| &mean_square | mean_square > 0.0 && block_loudness (mean_square) > threshold).collect();
+    if loud.is_empty() { None } else { Some(loud.iter().sum::<f64>()/loud.len() as f64) }
+  };
+  let absolutely_gated = gated_mean (-70.0)?;
+  let relative_threshold = block_loudness (absolutely_gated) - 10.0;
+  gated_mean (relative_threshold).map (block_loudness)
+}
+
+/// Scales everything so that its integrated loudness is `target_lufs`. Returns the gain applied, in decibels,
+/// or None (changing nothing) if it's too quiet or short to measure.
+///
+/// This doesn't prevent clipping; follow it with a TruePeakLimiter if the gain might be positive.
+pub fn normalize_loudness <Frame: dsp::Frame> (frames: &mut [Frame], sample_hz: f64, target_lufs: f64)->Option<f64>
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  let gain_decibels = target_lufs - integrated_loudness (frames, sample_hz)?;
+  let gain = decibels_to_amplitude (gain_decibels);
+  apply_gains (frames, | _ | gain);
+  Some(gain_decibels)
+}
+
+
+#[derive (Clone, Debug)]
+pub struct MasteringParameters {
+  /// If set, the loudness is normalized to this many LUFS first.
+  pub target_loudness: Option<f64>,
+  pub limiter: TruePeakLimiter,
+}
+
+impl Default for MasteringParameters {
+  fn default()->Self {
+    MasteringParameters {
+      target_loudness: Some(-14.0),
+      limiter: TruePeakLimiter::default(),
+    }
+  }
+}
+
+/// Loudness normalization followed by true-peak limiting, to get consistent levels without clipping.
+pub fn master <Frame: dsp::Frame> (frames: &mut [Frame], sample_hz: f64, parameters: &MasteringParameters)
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  if let Some(target) = parameters.target_loudness {
+    normalize_loudness (frames, sample_hz, target);
+  }
+  parameters.limiter.apply (frames, sample_hz);
+}
+
+/// A summary of the levels, for checking a master.
+#[derive (Clone, Debug)]
+pub struct LevelReport {
+  pub sample_peak_decibels: f64,
+  pub true_peak_decibels: f64,
+  pub integrated_loudness: Option<f64>,
+}
+
+pub fn level_report <Frame: dsp::Frame> (frames: &[Frame], sample_hz: f64)->LevelReport
+    where Frame::Sample: ToSample<f64> {
+  LevelReport {
+    sample_peak_decibels: amplitude_to_decibels (sample_peak (frames)),
+    true_peak_decibels: amplitude_to_decibels (true_peak (frames)),
+    integrated_loudness: integrated_loudness (frames, sample_hz),
+  }
+}
@@ -0,0 +1,114 @@
+use super::*;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+// about 50 minutes of stereo audio at 44100 Hz
+const DEFAULT_BUDGET_BYTES: usize = 1 << 30;
+static BUDGET_BYTES: AtomicUsize = AtomicUsize::new (DEFAULT_BUDGET_BYTES);
+// in every thread's caches together, including the worker threads of PositionedSequence::rendered_from_parallel()
+static BYTES_IN_USE: AtomicUsize = AtomicUsize::new (0);
+
+/// Limits how much memory rendered fluidsynth notes can use.
+///
+/// Each sample rate in each thread has its own synthesizer and cache, but the limit applies to all of them together.
+/// When the limit is exceeded, the cache that's adding a note forgets its least recently used notes,
+/// which will be rendered again if they are needed.
+pub fn set_budget (bytes: usize) {
+  BUDGET_BYTES.store (bytes, Ordering::Relaxed);
+}
+
+pub fn budget()->usize {
+  BUDGET_BYTES.load (Ordering::Relaxed)
+}
+
+#[derive (Clone, Debug, Default)]
+pub struct CacheStatistics {
+  pub hits: u64,
+  pub misses: u64,
+  pub evictions: u64,
+  pub notes: usize,
+  pub bytes: usize,
+}
+
+/// Statistics for the rendered notes at this sample rate, in the current thread.
+pub fn statistics (sample_hz: f64)->CacheStatistics {
+  with_fluid (sample_hz, | fluid | fluid.notes.statistics.clone())
+}
+
+/// The memory used by rendered notes in every thread and at every sample rate, which is what the budget limits.
+pub fn total_bytes()->usize {
+  BYTES_IN_USE.load (Ordering::Relaxed)
+}
+
+fn size_of_samples (samples: &[[f32;2]])->usize {
+  samples.len()*::std::mem::size_of::<[f32;2]>()
+}
+
+#[derive (Default)]
+pub(crate) struct MemoryCache {
+  notes: HashMap<FluidsynthDirectlyRenderableMIDINote, (Arc<[[f32;2]]>, u64)>,
+  // maps the time each note was last used to the note
+  recency: BTreeMap<u64, FluidsynthDirectlyRenderableMIDINote>,
+  now: u64,
+  statistics: CacheStatistics,
+}
+
+impl MemoryCache {
+  pub(crate) fn get (&mut self, note: &FluidsynthDirectlyRenderableMIDINote)->Option<Arc<[[f32;2]]>> {
+    self.now += 1;
+    let now = self.now;
+    match self.notes.get_mut (note) {
+      Some(&mut (ref samples, ref mut last_used)) => {
+        let note = self.recency.remove (last_used).unwrap();
+        self.recency.insert (now, note);
+        *last_used = now;
+        self.statistics.hits += 1;
+        Some(samples.clone())
+      },
+      None => {
+        self.statistics.misses += 1;
+        None
+      },
+    }
+  }
+
+  pub(crate) fn insert (&mut self, note: FluidsynthDirectlyRenderableMIDINote, samples: Arc<[[f32;2]]>) {
+    self.now += 1;
+    self.add_bytes (size_of_samples (&samples));
+    self.recency.insert (self.now, note.clone());
+    if let Some((old_samples, old_last_used)) = self.notes.insert (note, (samples, self.now)) {
+      self.recency.remove (&old_last_used);
+      self.remove_bytes (size_of_samples (&old_samples));
+    }
+
+    // Never evict the note that was just inserted, even if it's bigger than the whole budget.
+    // Other threads' caches get back under the budget when they next insert a note.
+    let budget = budget();
+    while total_bytes() > budget && self.notes.len() > 1 {
+      let oldest = *self.recency.keys().next().unwrap();
+      let note = self.recency.remove (&oldest).unwrap();
+      let (samples, _) = self.notes.remove (&note).unwrap();
+      self.remove_bytes (size_of_samples (&samples));
+      self.statistics.evictions += 1;
+    }
+    self.statistics.notes = self.notes.len();
+  }
+
+  fn add_bytes (&mut self, bytes: usize) {
+    self.statistics.bytes += bytes;
+    BYTES_IN_USE.fetch_add (bytes, Ordering::Relaxed);
+  }
+  fn remove_bytes (&mut self, bytes: usize) {
+    self.statistics.bytes -= bytes;
+    BYTES_IN_USE.fetch_sub (bytes, Ordering::Relaxed);
+  }
+}
+
+// a thread's caches go away with it
+impl Drop for MemoryCache {
+  fn drop (&mut self) {
+    BYTES_IN_USE.fetch_sub (self.statistics.bytes, Ordering::Relaxed);
+  }
+}
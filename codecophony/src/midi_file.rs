@@ -0,0 +1,667 @@
+use super::*;
+
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::path::Path;
+use std::collections::HashSet;
+
+use phrase::{Phrase, PhraseNote, ToPhraseNote};
+
+
+#[derive (Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MIDIFileFormat {
+  // type 0: everything in one track
+  SingleTrack,
+  // type 1: a tempo track, followed by one track per instrument
+  MultipleTracks,
+}
+
+#[derive (Clone, Debug)]
+pub struct MIDIFileParameters {
+  pub format: MIDIFileFormat,
+  pub ticks_per_quarter_note: u16,
+  // NoteTime is in seconds, so this only decides how the file gets displayed in other software;
+  // it doesn't change how the file sounds
+  pub beats_per_minute: f64,
+}
+
+impl Default for MIDIFileParameters {
+  fn default()->Self {
+    MIDIFileParameters {
+      format: MIDIFileFormat::MultipleTracks,
+      ticks_per_quarter_note: 480,
+      beats_per_minute: 120.0,
+    }
+  }
+}
+
+
+/// Anything that can be written to a Standard MIDI File.
+pub trait ToMIDIFileNotes {
+  fn collect_midi_file_notes<'a> (&'a self, notes: &mut Vec<(NoteTime, &'a FluidsynthDirectlyRenderableMIDINote)>);
+}
+
+impl<PitchedOrPercussion> ToMIDIFileNotes for MIDINote<PitchedOrPercussion> {
+  fn collect_midi_file_notes<'a> (&'a self, notes: &mut Vec<(NoteTime, &'a FluidsynthDirectlyRenderableMIDINote)>) {
+    notes.push ((self.start, &self.raw));
+  }
+}
+
+impl<T: ToMIDIFileNotes> ToMIDIFileNotes for Vec<T> {
+  fn collect_midi_file_notes<'a> (&'a self, notes: &mut Vec<(NoteTime, &'a FluidsynthDirectlyRenderableMIDINote)>) {
+    for note in self.iter() {
+      note.collect_midi_file_notes (notes);
+    }
+  }
+}
+
+impl<T: ToMIDIFileNotes + ?Sized> ToMIDIFileNotes for Box<T> {
+  fn collect_midi_file_notes<'a> (&'a self, notes: &mut Vec<(NoteTime, &'a FluidsynthDirectlyRenderableMIDINote)>) {
+    (**self).collect_midi_file_notes (notes);
+  }
+}
+
+
+fn big_endian (value: u32, bytes: usize)->Vec<u8> {
+  (0..bytes).rev().map (| index | (value >> (index*8)) as u8).collect()
+}
+
+pub(crate) fn write_variable_length_quantity (output: &mut Vec<u8>, value: u32) {
+  let mut groups = vec![(value & 0x7f) as u8];
+  let mut remaining = value >> 7;
+  while remaining > 0 {
+    groups.push (((remaining & 0x7f) as u8) | 0x80);
+    remaining >>= 7;
+  }
+  output.extend (groups.into_iter().rev());
+}
+
+// the numbers are the order in which simultaneous events get written:
+// note-offs first, so that a program change doesn't affect notes that are ending,
+// then setup events, then note-ons.
+#[derive (Clone, Debug)]
+enum TrackEventData {
+  NoteOff {channel: u8, pitch: u8},
+  Setup (Vec<u8>),
+  NoteOn {channel: u8, pitch: u8, velocity: u8},
+}
+
+impl TrackEventData {
+  fn order (&self)->u8 {
+    match *self {
+      TrackEventData::NoteOff {..} => 0,
+      TrackEventData::Setup (_) => 1,
+      TrackEventData::NoteOn {..} => 2,
+    }
+  }
+  fn write (&self, output: &mut Vec<u8>) {
+    match *self {
+      TrackEventData::NoteOff {channel, pitch} => output.extend_from_slice (&[0x80 | channel, pitch, 64]),
+      TrackEventData::Setup (ref bytes) => output.extend_from_slice (bytes),
+      TrackEventData::NoteOn {channel, pitch, velocity} => output.extend_from_slice (&[0x90 | channel, pitch, velocity]),
+    }
+  }
+}
+
+#[derive (Clone, Debug)]
+struct TrackEvent {
+  tick: u32,
+  data: TrackEventData,
+}
+
+#[derive (Clone, Debug, Default)]
+struct Track {
+  name: Option<String>,
+  events: Vec<TrackEvent>,
+}
+
+fn meta_event (kind: u8, data: &[u8])->Vec<u8> {
+  let mut result = vec![0xff, kind];
+  write_variable_length_quantity (&mut result, data.len() as u32);
+  result.extend_from_slice (data);
+  result
+}
+
+impl Track {
+  fn push (&mut self, tick: u32, data: TrackEventData) {
+    self.events.push (TrackEvent {tick, data});
+  }
+  fn chunk (&self)->Vec<u8> {
+    let mut events = self.events.clone();
+    // stable sort, so bank selects stay in front of their program changes
+    events.sort_by_key (| event | (event.tick, event.data.order()));
+
+    let mut data = Vec::new();
+    if let Some(ref name) = self.name {
+      write_variable_length_quantity (&mut data, 0);
+      data.extend (meta_event (0x03, name.as_bytes()));
+    }
+    let mut previous_tick = 0;
+    for event in events.iter() {
+      write_variable_length_quantity (&mut data, event.tick - previous_tick);
+      event.data.write (&mut data);
+      previous_tick = event.tick;
+    }
+    write_variable_length_quantity (&mut data, 0);
+    data.extend (meta_event (0x2f, &[]));
+
+    let mut result = b"MTrk".to_vec();
+    result.extend (big_endian (data.len() as u32, 4));
+    result.extend (data);
+    result
+  }
+}
+
+const NUM_CHANNELS: u8 = 16;
+
+fn instrument_name (instrument: &FluidsynthDirectlyRenderableMIDIInstrument)->String {
+  if instrument.is_percussion() {
+    String::from_str ("Percussion").unwrap()
+  }
+  else if instrument.bank == 0 {
+    format!("Program {}", instrument.preset + 1)
+  }
+  else {
+    format!("Bank {} Program {}", instrument.bank, instrument.preset + 1)
+  }
+}
+
+fn invalid_input (message: String)->io::Error {
+  io::Error::new (io::ErrorKind::InvalidInput, message)
+}
+
+/// Writes the notes as a Standard MIDI File.
+///
+/// Each pitched instrument gets its own channel, and percussion always uses channel 10.
+/// Notes with different pitch bends also get different channels, so microtonal chords stay in tune.
+/// If that needs more than 15 channels, a channel whose notes have all ended gets reused, with a new program change.
+/// If every channel still has notes sounding, it's an error, since changing the channel would change those notes too.
+pub fn write_midi_file <N: ToMIDIFileNotes + ?Sized, W: Write> (notes: &N, parameters: &MIDIFileParameters, mut output: W)->io::Result<()> {
+  let mut collected = Vec::new();
+  notes.collect_midi_file_notes (&mut collected);
+  collected.sort_by_key (| &(start, _) | OrderedFloat (start));
+
+  let ticks_per_second = parameters.ticks_per_quarter_note as f64 * parameters.beats_per_minute / 60.0;
+  let to_ticks = | time: NoteTime | -> io::Result<u32> {
+    let ticks = (time*ticks_per_second).round();
+    if ticks < 0.0 || ticks > u32::max_value() as f64 || !ticks.is_finite() {
+      return Err(invalid_input (format!("Time {} can't be represented in a MIDI file", time)));
+    }
+    Ok(ticks as u32)
+  };
+
+  let mut tracks: Vec<Track> = Vec::new();
+  let mut instrument_tracks: HashMap<FluidsynthDirectlyRenderableMIDIInstrument, usize> = HashMap::new();
+  // for each channel: which instrument and pitch bend are selected, and when its last note ends
+  let mut channels: Vec<Option<(FluidsynthDirectlyRenderableMIDIInstrument, i32, u32)>> = vec![None; NUM_CHANNELS as usize];
+
+  for (start, note) in collected {
+    let pitch = note.pitch;
+    if pitch < 0 || pitch > 127 {
+      return Err(invalid_input (format!("MIDI pitch {} is out of range", pitch)));
+    }
+    let pitch = pitch as u8;
+    let velocity = max (1, min (127, note.velocity)) as u8;
+    let pitch_bend = max (-8192, min (8191, note.pitch_bend));
+    let start_tick = to_ticks (start)?;
+    let end_tick = max (start_tick, to_ticks (start + note.duration.into_inner())?);
+
+    let track_index = *instrument_tracks.entry (note.instrument.clone()).or_insert_with (|| {
+      tracks.push (Track {name: Some(instrument_name (&note.instrument)), events: Vec::new()});
+      tracks.len() - 1
+    });
+    let track = &mut tracks [track_index];
+
+    let channel = if note.instrument.is_percussion() {
+      PERCUSSION_CHANNEL as u8
+    }
+    else {
+      // pitch bend affects the whole channel, so notes with different pitch bends need different channels
+      let existing = (0..NUM_CHANNELS).find (| &channel | channels [channel as usize].as_ref().map_or (false, | &(ref instrument, bend, _) | instrument == &note.instrument && bend == pitch_bend));
+      match existing {
+        Some(channel) => channel,
+        None => {
+          let candidates = (0..NUM_CHANNELS).filter (| &channel | channel != PERCUSSION_CHANNEL as u8);
+          let unused = candidates.clone().find (| &channel | channels [channel as usize].is_none());
+          let same_instrument = candidates.clone().find (| &channel | channels [channel as usize].as_ref().map_or (false, | &(ref instrument, _, last_end) | instrument == &note.instrument && last_end <= start_tick));
+          let silent = candidates.clone().filter (| &channel | channels [channel as usize].as_ref().map_or (false, | &(_, _, last_end) | last_end <= start_tick))
+            .min_by_key (| &channel | channels [channel as usize].as_ref().unwrap().2);
+          let channel = match unused.or (same_instrument).or (silent) {
+            Some(channel) => channel,
+            None => return Err(invalid_input (format!("At time {}, notes on every MIDI channel are still sounding, so there's no channel for another instrument or pitch bend", start))),
+          };
+          let index = channel as usize;
+          let (previous_instrument, previous_bend) = match channels [index] {
+            Some((ref instrument, bend, _)) => (Some(instrument.clone()), bend),
+            None => (None, 0),
+          };
+          if channels [index].is_none() {
+            // set the pitch bend range with RPN 0, then deselect the RPN, so that other software doesn't have to assume the default
+            for &(controller, value) in [(101, 0), (100, 0), (6, PITCH_BEND_RANGE_SEMITONES as u8), (38, 0), (101, 127), (100, 127)].iter() {
+              track.push (start_tick, TrackEventData::Setup (vec![0xb0 | channel, controller, value]));
+            }
+          }
+          if previous_instrument.as_ref() != Some(&note.instrument) {
+            let bank = note.instrument.bank;
+            let previous_bank = previous_instrument.as_ref().map_or (0, | instrument | instrument.bank);
+            if bank != previous_bank {
+              track.push (start_tick, TrackEventData::Setup (vec![0xb0 | channel, 0, ((bank >> 7) & 0x7f) as u8]));
+              track.push (start_tick, TrackEventData::Setup (vec![0xb0 | channel, 32, (bank & 0x7f) as u8]));
+            }
+            track.push (start_tick, TrackEventData::Setup (vec![0xc0 | channel, (note.instrument.preset & 0x7f) as u8]));
+          }
+          if pitch_bend != previous_bend {
+            let value = (pitch_bend + 8192) as u32;
+            track.push (start_tick, TrackEventData::Setup (vec![0xe0 | channel, (value & 0x7f) as u8, ((value >> 7) & 0x7f) as u8]));
+          }
+          channels [index] = Some((note.instrument.clone(), pitch_bend, end_tick));
+          channel
+        }
+      }
+    };
+
+    if let Some((_, _, ref mut last_end)) = channels [channel as usize] {
+      *last_end = max (*last_end, end_tick);
+    }
+    track.push (start_tick, TrackEventData::NoteOn {channel, pitch, velocity});
+    track.push (end_tick, TrackEventData::NoteOff {channel, pitch});
+  }
+
+  let microseconds_per_quarter_note = (60_000_000.0 / parameters.beats_per_minute).round() as u32;
+  let tempo = meta_event (0x51, &big_endian (microseconds_per_quarter_note, 3));
+
+  let tracks = match parameters.format {
+    MIDIFileFormat::SingleTrack => {
+      let mut combined = Track::default();
+      combined.push (0, TrackEventData::Setup (tempo));
+      for track in tracks {
+        combined.events.extend (track.events);
+      }
+      vec![combined]
+    },
+    MIDIFileFormat::MultipleTracks => {
+      let mut tempo_track = Track::default();
+      tempo_track.push (0, TrackEventData::Setup (tempo));
+      iter::once (tempo_track).chain (tracks).collect()
+    },
+  };
+
+  let format = match parameters.format {
+    MIDIFileFormat::SingleTrack => 0,
+    MIDIFileFormat::MultipleTracks => 1,
+  };
+  let mut header = b"MThd".to_vec();
+  header.extend (big_endian (6, 4));
+  header.extend (big_endian (format, 2));
+  header.extend (big_endian (tracks.len() as u32, 2));
+  header.extend (big_endian ((parameters.ticks_per_quarter_note & 0x7fff) as u32, 2));
+
+  output.write_all (&header)?;
+  for track in tracks.iter() {
+    output.write_all (&track.chunk())?;
+  }
+  Ok(())
+}
+
+pub fn save_midi_file <N: ToMIDIFileNotes + ?Sized> (path: &Path, notes: &N, parameters: &MIDIFileParameters)->io::Result<()> {
+  let file = io::BufWriter::new (File::create (path)?);
+  write_midi_file (notes, parameters, file)
+}
+
+
+/// A note read from a Standard MIDI File, with the context it was found in.
+#[derive (Clone, Debug)]
+pub struct MIDIFileNote {
+  pub start: NoteTime,
+  pub duration: NoteTime,
+  pub pitch: i32,
+  // in the same units as FluidsynthDirectlyRenderableMIDINote::pitch_bend, converted from the range that the file sets with RPN 0;
+  // a bend beyond that range moves the pitch instead
+  pub pitch_bend: i32,
+  pub velocity: i32,
+  // all of these use the numbers from the file, starting at 0
+  pub channel: u8,
+  pub bank: u32,
+  pub program: u32,
+  pub track: usize,
+  pub track_name: Option<String>,
+}
+
+impl MIDIFileNote {
+  pub fn is_percussion (&self)->bool {
+    self.channel as i32 == PERCUSSION_CHANNEL
+  }
+  pub fn instrument (&self)->FluidsynthDirectlyRenderableMIDIInstrument {
+    if self.is_percussion() {
+      FluidsynthDirectlyRenderableMIDIInstrument::percussion()
+    }
+    else {
+      FluidsynthDirectlyRenderableMIDIInstrument {
+        channel: 0,
+        font: soundfont::default_soundfont(),
+        bank: self.bank,
+        preset: self.program,
+      }
+    }
+  }
+  fn raw (&self)->FluidsynthDirectlyRenderableMIDINote {
+    FluidsynthDirectlyRenderableMIDINote {
+      duration: NotNaN::new (self.duration).unwrap(),
+      pitch: self.pitch,
+      pitch_bend: if self.is_percussion() {0} else {self.pitch_bend},
+      velocity: self.velocity,
+      instrument: self.instrument(),
+    }
+  }
+}
+
+impl ToPhraseNote for MIDIFileNote {
+  fn to_phrase_note (&self)->PhraseNote {
+    let mut tags = HashSet::new();
+    if self.is_percussion() {
+      tags.insert (String::from_str ("percussion").unwrap());
+    }
+    else {
+      tags.insert (String::from_str ("pitched").unwrap());
+      // use the General MIDI numbering, like FluidsynthDirectlyRenderableMIDIInstrument::pitched()
+      tags.insert (format!("program:{}", self.program + 1));
+      if self.bank != 0 {
+        tags.insert (format!("bank:{}", self.bank));
+      }
+    }
+    tags.insert (format!("channel:{}", self.channel as u32 + 1));
+    tags.insert (format!("track:{}", self.track));
+    if let Some(ref name) = self.track_name {
+      tags.insert (format!("track_name:{}", name));
+    }
+    PhraseNote {
+      start: self.start,
+      end: self.start + self.duration,
+      // for percussion, this is the same hack as ToPhraseNote for MIDIPercussionNote
+      frequency: if self.is_percussion() {midi_pitch_to_frequency (self.pitch)} else {midi_pitch_and_bend_to_frequency (self.pitch, self.pitch_bend)},
+      tags,
+    }
+  }
+}
+
+#[derive (Clone, Debug, Default)]
+pub struct MIDIFileContents {
+  pub notes: Vec<MIDIFileNote>,
+  pub track_names: Vec<Option<String>>,
+}
+
+impl MIDIFileContents {
+  pub fn pitched_notes (&self)->Vec<MIDIPitchedNote> {
+    self.notes.iter().filter (| note | !note.is_percussion()).map (| note | MIDINote::from_raw (note.start, note.raw())).collect()
+  }
+  pub fn percussion_notes (&self)->Vec<MIDIPercussionNote> {
+    self.notes.iter().filter (| note | note.is_percussion()).map (| note | MIDINote::from_raw (note.start, note.raw())).collect()
+  }
+  pub fn to_phrase (&self)->Phrase {
+    self.notes.iter().collect()
+  }
+}
+
+
+fn invalid_data (message: String)->io::Error {
+  io::Error::new (io::ErrorKind::InvalidData, message)
+}
+
+struct Reader<'a> {
+  data: &'a [u8],
+  position: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn finished (&self)->bool {
+    self.position >= self.data.len()
+  }
+  fn bytes (&mut self, count: usize)->io::Result<&'a [u8]> {
+    if self.data.len() - self.position < count {
+      return Err(invalid_data (format!("MIDI file ended unexpectedly at byte {}", self.position)));
+    }
+    let result = &self.data [self.position..self.position + count];
+    self.position += count;
+    Ok(result)
+  }
+  fn byte (&mut self)->io::Result<u8> {
+    Ok(self.bytes (1)? [0])
+  }
+  fn peek (&self)->io::Result<u8> {
+    self.data.get (self.position).cloned().ok_or_else (|| invalid_data (format!("MIDI file ended unexpectedly at byte {}", self.position)))
+  }
+  fn big_endian (&mut self, count: usize)->io::Result<u32> {
+    Ok(self.bytes (count)?.iter().fold (0, | result, &byte | (result << 8) | byte as u32))
+  }
+  fn variable_length_quantity (&mut self)->io::Result<u32> {
+    let mut result = 0u32;
+    for _ in 0..4 {
+      let byte = self.byte()?;
+      result = (result << 7) | (byte & 0x7f) as u32;
+      if byte & 0x80 == 0 {
+        return Ok(result);
+      }
+    }
+    Err(invalid_data (format!("Variable-length quantity too long at byte {}", self.position)))
+  }
+  fn chunk (&mut self)->io::Result<(&'a [u8], Reader<'a>)> {
+    let kind = self.bytes (4)?;
+    let length = self.big_endian (4)? as usize;
+    Ok((kind, Reader {data: self.bytes (length)?, position: 0}))
+  }
+}
+
+#[derive (Clone, Debug)]
+enum FileEvent {
+  NoteOff {channel: u8, pitch: u8},
+  NoteOn {channel: u8, pitch: u8, velocity: u8},
+  Controller {channel: u8, controller: u8, value: u8},
+  ProgramChange {channel: u8, program: u8},
+  // relative to the center, like FluidsynthDirectlyRenderableMIDINote::pitch_bend
+  PitchBend {channel: u8, value: i32},
+  Tempo (u32),
+  TrackName (String),
+}
+
+fn read_track (mut reader: Reader, track: usize, events: &mut Vec<(u32, usize, FileEvent)>)->io::Result<()> {
+  let mut tick = 0u32;
+  let mut running_status = None;
+  while !reader.finished() {
+    tick = tick.checked_add (reader.variable_length_quantity()?).ok_or_else (|| invalid_data (format!("Track {} is too long", track)))?;
+    let status = if reader.peek()? & 0x80 != 0 {
+      reader.byte()?
+    }
+    else {
+      running_status.ok_or_else (|| invalid_data (format!("Track {} uses running status without a previous status byte", track)))?
+    };
+    match status {
+      0xff => {
+        running_status = None;
+        let kind = reader.byte()?;
+        let length = reader.variable_length_quantity()? as usize;
+        let data = reader.bytes (length)?;
+        match kind {
+          0x03 => events.push ((tick, track, FileEvent::TrackName (String::from_utf8_lossy (data).into_owned()))),
+          0x51 if length == 3 => events.push ((tick, track, FileEvent::Tempo ((data [0] as u32) << 16 | (data [1] as u32) << 8 | data [2] as u32))),
+          0x2f => break,
+          _ => (),
+        }
+      },
+      0xf0 | 0xf7 => {
+        running_status = None;
+        let length = reader.variable_length_quantity()? as usize;
+        reader.bytes (length)?;
+      },
+      0xf1 ..= 0xfe => {
+        return Err(invalid_data (format!("Unexpected system message {:#x} in track {}", status, track)));
+      },
+      _ => {
+        running_status = Some(status);
+        let channel = status & 0x0f;
+        let first = reader.byte()? & 0x7f;
+        let event = match status & 0xf0 {
+          0x80 => {reader.byte()?; Some(FileEvent::NoteOff {channel, pitch: first})},
+          0x90 => {
+            let velocity = reader.byte()? & 0x7f;
+            Some(if velocity == 0 {FileEvent::NoteOff {channel, pitch: first}} else {FileEvent::NoteOn {channel, pitch: first, velocity}})
+          },
+          0xb0 => Some(FileEvent::Controller {channel, controller: first, value: reader.byte()? & 0x7f}),
+          0xc0 => Some(FileEvent::ProgramChange {channel, program: first}),
+          0xd0 => None,
+          0xe0 => {
+            let value = (reader.byte()? as i32 & 0x7f) << 7 | first as i32;
+            Some(FileEvent::PitchBend {channel, value: value - 8192})
+          },
+          _ => {reader.byte()?; None},
+        };
+        if let Some(event) = event {
+          events.push ((tick, track, event));
+        }
+      },
+    }
+  }
+  Ok(())
+}
+
+/// Reads a type 0 or type 1 Standard MIDI File.
+pub fn read_midi_file <R: Read> (mut input: R)->io::Result<MIDIFileContents> {
+  let mut data = Vec::new();
+  input.read_to_end (&mut data)?;
+  let mut reader = Reader {data: &data, position: 0};
+
+  let (kind, mut header) = reader.chunk()?;
+  if kind != b"MThd" {
+    return Err(invalid_data (String::from_str ("Not a Standard MIDI File").unwrap()));
+  }
+  let format = header.big_endian (2)?;
+  let num_tracks = header.big_endian (2)? as usize;
+  let division = header.big_endian (2)?;
+  if format > 1 {
+    return Err(invalid_data (format!("MIDI file type {} is not supported", format)));
+  }
+
+  let mut events = Vec::new();
+  let mut track = 0;
+  while track < num_tracks && !reader.finished() {
+    let (kind, track_reader) = reader.chunk()?;
+    // the spec says to ignore unknown chunk types
+    if kind == b"MTrk" {
+      read_track (track_reader, track, &mut events)?;
+      track += 1;
+    }
+  }
+  // in a type 1 file, all tracks share the channels, so interleave them.
+  // the sort is stable, so events at the same tick stay in file order.
+  events.sort_by_key (| &(tick, _, _) | tick);
+
+  let tick_to_time: Box<Fn(u32)->NoteTime> = if division & 0x8000 != 0 {
+    let frames_per_second = match (division >> 8) as u8 as i8 {
+      -29 => 29.97,
+      other => -(other as f64),
+    };
+    let ticks_per_frame = (division & 0xff) as f64;
+    Box::new (move | tick | tick as f64 / (frames_per_second*ticks_per_frame))
+  }
+  else {
+    if division == 0 {
+      return Err(invalid_data (String::from_str ("MIDI file has a division of 0 ticks").unwrap()));
+    }
+    let ticks_per_quarter_note = division as f64;
+    // the default tempo is 500,000 microseconds per quarter note
+    let mut map = TempoMap::new (120.0);
+    for &(tick, _, ref event) in events.iter() {
+      if let FileEvent::Tempo (tempo) = *event {
+        map.add_tempo_change (tempo::TempoChange {beat: tick as f64/ticks_per_quarter_note, beats_per_minute: 60_000_000.0/tempo as f64, curve: tempo::TempoCurve::Step});
+      }
+    }
+    Box::new (move | tick | map.seconds (tick as f64/ticks_per_quarter_note))
+  };
+
+  let mut result = MIDIFileContents {notes: Vec::new(), track_names: vec![None; track]};
+  let mut programs = [0u8; 16];
+  let mut banks = [(0u8, 0u8); 16];
+  let mut pitch_bends = [0i32; 16];
+  // the selected RPN, and the pitch bend range in cents that RPN 0 sets
+  let mut rpns = [(127u8, 127u8); 16];
+  let mut pitch_bend_ranges = [PITCH_BEND_RANGE_SEMITONES*100; 16];
+  // notes that have started but not ended yet, in the order they started
+  let mut sounding: HashMap<(u8, u8), Vec<MIDIFileNote>> = HashMap::new();
+  let mut last_tick = 0;
+  for (tick, track, event) in events {
+    last_tick = tick;
+    let time = tick_to_time (tick);
+    match event {
+      FileEvent::TrackName (name) => {
+        if result.track_names [track].is_none() {
+          result.track_names [track] = Some(name);
+        }
+      },
+      FileEvent::Tempo (_) => (),
+      FileEvent::ProgramChange {channel, program} => programs [channel as usize] = program,
+      FileEvent::PitchBend {channel, value} => pitch_bends [channel as usize] = value,
+      FileEvent::Controller {channel, controller: 0, value} => banks [channel as usize].0 = value,
+      FileEvent::Controller {channel, controller: 32, value} => banks [channel as usize].1 = value,
+      FileEvent::Controller {channel, controller: 101, value} => rpns [channel as usize].0 = value,
+      FileEvent::Controller {channel, controller: 100, value} => rpns [channel as usize].1 = value,
+      FileEvent::Controller {channel, controller: 6, value} => if rpns [channel as usize] == (0, 0) {
+        let range = &mut pitch_bend_ranges [channel as usize];
+        *range = value as i32*100 + *range % 100;
+      },
+      FileEvent::Controller {channel, controller: 38, value} => if rpns [channel as usize] == (0, 0) {
+        let range = &mut pitch_bend_ranges [channel as usize];
+        *range = *range/100*100 + value as i32;
+      },
+      FileEvent::Controller {..} => (),
+      FileEvent::NoteOn {channel, pitch, velocity} => {
+        let (msb, lsb) = banks [channel as usize];
+        // convert the bend to the range we render with, moving to the nearest pitch if it's outside that range
+        let mut note_pitch = pitch as i32;
+        let mut pitch_bend = (pitch_bends [channel as usize] as i64*pitch_bend_ranges [channel as usize] as i64/(PITCH_BEND_RANGE_SEMITONES as i64*100)) as i32;
+        if pitch_bend < -8192 || pitch_bend > 8191 {
+          let semitones = (pitch_bend as f64/PITCH_BEND_UNITS_PER_SEMITONE as f64).round() as i32;
+          note_pitch += semitones;
+          pitch_bend -= semitones*PITCH_BEND_UNITS_PER_SEMITONE;
+        }
+        sounding.entry ((channel, pitch)).or_insert_with (Vec::new).push (MIDIFileNote {
+          start: time,
+          duration: 0.0,
+          pitch: note_pitch,
+          pitch_bend,
+          velocity: velocity as i32,
+          channel,
+          bank: (msb as u32) << 7 | lsb as u32,
+          program: programs [channel as usize] as u32,
+          track,
+          track_name: None,
+        });
+      },
+      FileEvent::NoteOff {channel, pitch} => {
+        if let Some(notes) = sounding.get_mut (&(channel, pitch)) {
+          if !notes.is_empty() {
+            let mut note = notes.remove (0);
+            note.duration = time - note.start;
+            result.notes.push (note);
+          }
+        }
+      },
+    }
+  }
+  // notes that never got a note-off last until the end
+  let end_time = tick_to_time (last_tick);
+  for (_, notes) in sounding {
+    for mut note in notes {
+      note.duration = end_time - note.start;
+      result.notes.push (note);
+    }
+  }
+
+  for note in result.notes.iter_mut() {
+    note.track_name = result.track_names [note.track].clone();
+  }
+  result.notes.sort_by_key (| note | (OrderedFloat (note.start), note.channel, note.pitch));
+  Ok(result)
+}
+
+pub fn load_midi_file (path: &Path)->io::Result<MIDIFileContents> {
+  read_midi_file (io::BufReader::new (File::open (path)?))
+}
@@ -0,0 +1,447 @@
+use super::*;
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use dsp::sample::ToSample;
+
+use effects::{Processor, RenderedEffect};
+
+
+/// A named part of a song, like "drums" or "melody", with its own level and placement.
+pub struct Track<Frame: dsp::Frame> {
+  name: String,
+  pub notes: Box<Renderable<Frame> + Send>,
+  pub gain: f64,
+  pub pan: f64,
+  pub mute: bool,
+  pub solo: bool,
+  output: Option<String>,
+  sends: Vec<(String, f64)>,
+}
+
+impl<Frame: dsp::Frame> Track<Frame> {
+  pub fn name (&self)->&str {&self.name}
+  /// The bus this track plays through. None means the master bus.
+  pub fn output (&self)->Option<&str> {self.output.as_ref().map (| name | &name [..])}
+  /// Extra copies of this track, sent to other buses (usually ones with effects, like a shared reverb).
+  /// Each level is relative to the track's gain and pan.
+  pub fn sends (&self)->&[(String, f64)] {&self.sends}
+}
+
+struct BusState {
+  processor: Option<Box<Processor + Send>>,
+  rendered: Option<RenderedEffect>,
+}
+
+/// Mixes together everything routed to it, runs it through its processor, if it has one,
+/// and passes it on to its output.
+pub struct Bus {
+  name: String,
+  pub gain: f64,
+  pub pan: f64,
+  pub mute: bool,
+  output: Option<String>,
+  state: Mutex<BusState>,
+}
+
+impl Bus {
+  fn new (name: &str)->Bus {
+    Bus {
+      name: name.to_string(),
+      gain: 1.0,
+      pan: 0.0,
+      mute: false,
+      output: None,
+      state: Mutex::new(BusState {processor: None, rendered: None}),
+    }
+  }
+  pub fn name (&self)->&str {&self.name}
+  /// The bus this one plays through. None means the master bus, and always for the master bus itself.
+  pub fn output (&self)->Option<&str> {self.output.as_ref().map (| name | &name [..])}
+  pub fn set_processor (&mut self, processor: Option<Box<Processor + Send>>) {
+    let state = self.state.get_mut().unwrap();
+    state.processor = processor;
+    state.rendered = None;
+  }
+}
+
+// None means the master bus
+type BusIndex = Option<usize>;
+
+struct Routing {
+  track_outputs: Vec<BusIndex>,
+  track_sends: Vec<Vec<(BusIndex, f64)>>,
+  bus_outputs: Vec<BusIndex>,
+  soloing: bool,
+}
+
+/// Tracks playing through a graph of buses, ending at the master bus.
+///
+/// Buses with processors get rendered all at once, like effects::Effect, the first time the mixer is rendered at a sample rate.
+/// Changing anything through the mixer's methods discards that, so it will be rendered again.
+/// Only reading through track_mut() and bus_mut() doesn't.
+pub struct Mixer<Frame: dsp::Frame> {
+  tracks: Vec<Track<Frame>>,
+  buses: Vec<Bus>,
+  master: Bus,
+  routing: Mutex<Option<Arc<Routing>>>,
+}
+
+impl<Frame: dsp::Frame> Default for Mixer<Frame> {
+  fn default()->Self {
+    Mixer {
+      tracks: Vec::new(),
+      buses: Vec::new(),
+      master: Bus::new ("master"),
+      routing: Mutex::new(None),
+    }
+  }
+}
+
+impl<Frame: dsp::Frame> Mixer<Frame> {
+  pub fn new()->Self {
+    Mixer::default()
+  }
+
+  fn changed (&mut self) {
+    *self.routing.get_mut().unwrap() = None;
+    for bus in self.buses.iter_mut().chain (iter::once (&mut self.master)) {
+      bus.state.get_mut().unwrap().rendered = None;
+    }
+  }
+
+  /// Adds a track playing through the master bus at full volume, or replaces the notes of the track with that name.
+  pub fn add_track <N: Renderable<Frame> + Send + 'static> (&mut self, name: &str, notes: N)->&mut Track<Frame> {
+    self.changed();
+    match self.tracks.iter().position (| track | track.name == name) {
+      Some(index) => {
+        self.tracks [index].notes = Box::new(notes);
+        &mut self.tracks [index]
+      },
+      None => {
+        self.tracks.push (Track {
+          name: name.to_string(),
+          notes: Box::new(notes),
+          gain: 1.0,
+          pan: 0.0,
+          mute: false,
+          solo: false,
+          output: None,
+          sends: Vec::new(),
+        });
+        self.tracks.last_mut().unwrap()
+      },
+    }
+  }
+
+  /// Adds a bus playing through the master bus, or returns the existing bus with that name.
+  pub fn add_bus (&mut self, name: &str)->&mut Bus {
+    self.changed();
+    match self.buses.iter().position (| bus | bus.name == name) {
+      Some(index) => &mut self.buses [index],
+      None => {
+        self.buses.push (Bus::new (name));
+        self.buses.last_mut().unwrap()
+      },
+    }
+  }
+
+  pub fn track (&self, name: &str)->Option<&Track<Frame>> {
+    self.tracks.iter().find (| track | track.name == name)
+  }
+  pub fn track_mut <'a> (&'a mut self, name: &str)->Option<TrackMut<'a, Frame>> {
+    let index = self.tracks.iter().position (| track | track.name == name)?;
+    Some(TrackMut {mixer: self, index})
+  }
+  pub fn tracks (&self)->&[Track<Frame>] {&self.tracks}
+
+  pub fn bus (&self, name: &str)->Option<&Bus> {
+    self.buses.iter().find (| bus | bus.name == name)
+  }
+  pub fn bus_mut <'a> (&'a mut self, name: &str)->Option<BusMut<'a, Frame>> {
+    let index = self.buses.iter().position (| bus | bus.name == name)?;
+    Some(BusMut {mixer: self, index: Some(index)})
+  }
+  pub fn buses (&self)->&[Bus] {&self.buses}
+
+  pub fn master (&self)->&Bus {&self.master}
+  pub fn master_mut <'a> (&'a mut self)->BusMut<'a, Frame> {
+    BusMut {mixer: self, index: None}
+  }
+
+  fn bus_index (&self, name: &str)->Option<BusIndex> {
+    if name == self.master.name { return Some(None); }
+    self.buses.iter().position (| bus | bus.name == name).map (Some)
+  }
+
+  fn existing_bus (&self, name: &str)->io::Result<BusIndex> {
+    self.bus_index (name).ok_or_else (|| invalid_input (format!("There is no bus called {:?}", name)))
+  }
+
+  fn existing_track (&self, name: &str)->io::Result<usize> {
+    self.tracks.iter().position (| track | track.name == name).ok_or_else (|| invalid_input (format!("There is no track called {:?}", name)))
+  }
+
+  /// Makes the track play through the bus called `output`, or the master bus if it's None.
+  pub fn set_track_output (&mut self, track: &str, output: Option<&str>)->io::Result<()> {
+    let index = self.existing_track (track)?;
+    if let Some(output) = output { self.existing_bus (output)?; }
+    self.changed();
+    self.tracks [index].output = output.map (| output | output.to_string());
+    Ok(())
+  }
+
+  /// Sends a copy of the track to the bus called `bus`, at `level`, or changes the level of an existing send there.
+  pub fn set_send (&mut self, track: &str, bus: &str, level: f64)->io::Result<()> {
+    let index = self.existing_track (track)?;
+    self.existing_bus (bus)?;
+    self.changed();
+    let sends = &mut self.tracks [index].sends;
+    match sends.iter().position (| &(ref name, _) | name == bus) {
+      Some(existing) => sends [existing].1 = level,
+      None => sends.push ((bus.to_string(), level)),
+    }
+    Ok(())
+  }
+
+  pub fn remove_send (&mut self, track: &str, bus: &str)->io::Result<()> {
+    let index = self.existing_track (track)?;
+    self.changed();
+    self.tracks [index].sends.retain (| &(ref name, _) | name != bus);
+    Ok(())
+  }
+
+  /// Makes the bus play through the bus called `output`, or the master bus if it's None.
+  /// Fails if that would make the bus feed back into itself.
+  pub fn set_bus_output (&mut self, bus: &str, output: Option<&str>)->io::Result<()> {
+    let index = match self.existing_bus (bus)? {
+      Some(index) => index,
+      None => return Err(invalid_input ("The master bus can't play through another bus".to_string())),
+    };
+    let mut current = match output {
+      Some(output) => self.existing_bus (output)?,
+      None => None,
+    };
+    while let Some(next) = current {
+      if next == index {
+        return Err(invalid_input (format!("Routing bus {:?} through {:?} would make it feed back into itself", bus, output.unwrap())));
+      }
+      current = self.buses [next].output.as_ref().and_then (| name | self.bus_index (name).unwrap());
+    }
+    self.changed();
+    self.buses [index].output = output.map (| output | output.to_string());
+    Ok(())
+  }
+
+  // Buses can't be removed, and the routes were checked when they were set, so every route here is valid.
+  fn routing (&self)->Arc<Routing> {
+    let mut guard = self.routing.lock().unwrap();
+    if let Some(ref routing) = *guard {
+      return routing.clone();
+    }
+    let output_index = | output: &Option<String> | output.as_ref().and_then (| name | self.bus_index (name).unwrap());
+
+    let routing = Arc::new(Routing {
+      track_outputs: self.tracks.iter().map (| track | output_index (&track.output)).collect(),
+      track_sends: self.tracks.iter().map (| track | track.sends.iter().map (| &(ref name, level) | (self.bus_index (name).unwrap(), level)).collect()).collect(),
+      bus_outputs: self.buses.iter().map (| bus | output_index (&bus.output)).collect(),
+      soloing: self.tracks.iter().any (| track | track.solo),
+    });
+    *guard = Some(routing.clone());
+    routing
+  }
+
+  fn bus_at (&self, index: BusIndex)->&Bus {
+    index.map_or (&self.master, | index | &self.buses [index])
+  }
+
+  fn audible (&self, routing: &Routing, track_index: usize)->bool {
+    let track = &self.tracks [track_index];
+    !track.mute && (track.solo || !routing.soloing)
+  }
+
+  // how much of this track goes straight into this bus
+  fn track_level_into (&self, routing: &Routing, track_index: usize, bus: BusIndex)->f64 {
+    if !self.audible (routing, track_index) { return 0.0; }
+    let direct = if routing.track_outputs [track_index] == bus {1.0} else {0.0};
+    let sent: f64 = routing.track_sends [track_index].iter().filter (| &&(index, _) | index == bus).map (| &(_, level) | level).sum();
+    direct + sent
+  }
+}
+
+fn invalid_input (message: String)->io::Error {
+  io::Error::new (io::ErrorKind::InvalidInput, message)
+}
+
+/// Mutable access to one of a mixer's tracks. Changing the track through this discards what the mixer has rendered;
+/// only reading it doesn't.
+pub struct TrackMut<'a, Frame: dsp::Frame + 'a> {
+  mixer: &'a mut Mixer<Frame>,
+  index: usize,
+}
+
+impl<'a, Frame: dsp::Frame> Deref for TrackMut<'a, Frame> {
+  type Target = Track<Frame>;
+  fn deref (&self)->&Track<Frame> {&self.mixer.tracks [self.index]}
+}
+
+impl<'a, Frame: dsp::Frame> DerefMut for TrackMut<'a, Frame> {
+  fn deref_mut (&mut self)->&mut Track<Frame> {
+    self.mixer.changed();
+    &mut self.mixer.tracks [self.index]
+  }
+}
+
+/// Mutable access to one of a mixer's buses, like TrackMut.
+pub struct BusMut<'a, Frame: dsp::Frame + 'a> {
+  mixer: &'a mut Mixer<Frame>,
+  index: BusIndex,
+}
+
+impl<'a, Frame: dsp::Frame> Deref for BusMut<'a, Frame> {
+  type Target = Bus;
+  fn deref (&self)->&Bus {self.mixer.bus_at (self.index)}
+}
+
+impl<'a, Frame: dsp::Frame> DerefMut for BusMut<'a, Frame> {
+  fn deref_mut (&mut self)->&mut Bus {
+    self.mixer.changed();
+    match self.index {
+      Some(index) => &mut self.mixer.buses [index],
+      None => &mut self.mixer.master,
+    }
+  }
+}
+
+fn union (first: Option<(NoteTime, NoteTime)>, second: Option<(NoteTime, NoteTime)>)->Option<(NoteTime, NoteTime)> {
+  match (first, second) {
+    (Some((start, end)), Some((other_start, other_end))) => Some((start.min (other_start), end.max (other_end))),
+    (a, None) => a,
+    (None, b) => b,
+  }
+}
+
+impl<Frame: dsp::Frame> Mixer<Frame> {
+  // the window of everything flowing into the bus, before its processor
+  fn input_window (&self, routing: &Routing, bus: BusIndex)->Option<(NoteTime, NoteTime)> {
+    let mut result = None;
+    for (index, track) in self.tracks.iter().enumerate() {
+      if self.track_level_into (routing, index, bus) != 0.0 {
+        result = union (result, Some((track.notes.start(), track.notes.end())));
+      }
+    }
+    for (index, &output) in routing.bus_outputs.iter().enumerate() {
+      if output == bus && Some(index) != bus {
+        result = union (result, self.output_window (routing, Some(index)));
+      }
+    }
+    result
+  }
+
+  fn output_window (&self, routing: &Routing, bus: BusIndex)->Option<(NoteTime, NoteTime)> {
+    let tail = self.bus_at (bus).state.lock().unwrap().processor.as_ref().map_or (0.0, | processor | processor.tail());
+    self.input_window (routing, bus).map (| (start, end) | (start, end + tail))
+  }
+}
+
+impl<Frame: dsp::Frame> Windowed for Mixer<Frame> {
+  fn start (&self)->NoteTime {
+    self.output_window (&self.routing(), None).map_or (1.0, | (start, _) | start)
+  }
+  fn end (&self)->NoteTime {
+    self.output_window (&self.routing(), None).map_or (0.0, | (_, end) | end)
+  }
+}
+
+// everything flowing into one bus, for rendering through its processor
+struct BusInput<'a, Frame: dsp::Frame + 'a> {
+  mixer: &'a Mixer<Frame>,
+  routing: &'a Routing,
+  bus: BusIndex,
+}
+
+impl<'a, Frame: dsp::Frame> Windowed for BusInput<'a, Frame> {
+  fn start (&self)->NoteTime {
+    self.mixer.input_window (self.routing, self.bus).map_or (1.0, | (start, _) | start)
+  }
+  fn end (&self)->NoteTime {
+    self.mixer.input_window (self.routing, self.bus).map_or (0.0, | (_, end) | end)
+  }
+}
+
+impl<'a, Frame: dsp::Frame> Renderable<Frame> for BusInput<'a, Frame>
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  fn render(&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    let afterend = start + buffer.len() as FrameTime;
+    let mut rendered = Vec::new();
+    for (index, track) in self.mixer.tracks.iter().enumerate() {
+      let level = self.mixer.track_level_into (self.routing, index, self.bus);
+      if level == 0.0 { continue; }
+      let note_start = max(start, (track.notes.start()*sample_hz).ceil() as FrameTime);
+      let note_afterend = min(afterend, (track.notes.end()*sample_hz).floor() as FrameTime + 1);
+      if note_afterend <= note_start { continue; }
+      rendered.clear();
+      rendered.resize ((note_afterend - note_start) as usize, Frame::equilibrium());
+      track.notes.render (&mut rendered, note_start, sample_hz);
+      mix_into (&rendered, &mut buffer [(note_start - start) as usize..(note_afterend - start) as usize], track.gain*level, track.pan);
+    }
+    for (index, &output) in self.routing.bus_outputs.iter().enumerate() {
+      if output == self.bus && Some(index) != self.bus {
+        self.mixer.render_bus (self.routing, Some(index), buffer, start, sample_hz);
+      }
+    }
+  }
+}
+
+fn mix_into <Frame: dsp::Frame> (source: &[Frame], destination: &mut [Frame], gain: f64, pan: f64)
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  let stereo = Frame::n_channels() == 2;
+  let (left_gain, right_gain) = effects::pan_gains (pan);
+  let mut samples = vec![0.0; Frame::n_channels()];
+  for (value_mut, frame) in destination.iter_mut().zip (source.iter()) {
+    effects::frame_to_samples (frame, &mut samples);
+    for sample in samples.iter_mut() { *sample *= gain; }
+    if stereo {
+      samples [0] *= left_gain;
+      samples [1] *= right_gain;
+    }
+    effects::add_samples (value_mut, &samples);
+  }
+}
+
+impl<Frame: dsp::Frame> Mixer<Frame>
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  // adds the output of the bus, after its processor, gain and pan, to the buffer
+  fn render_bus (&self, routing: &Routing, bus_index: BusIndex, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    let bus = self.bus_at (bus_index);
+    if bus.mute { return; }
+    let input = BusInput {mixer: self, routing, bus: bus_index};
+    let mut processed = vec![Frame::equilibrium(); buffer.len()];
+    {
+      let mut guard = bus.state.lock().unwrap();
+      let state = &mut *guard;
+      match state.processor {
+        Some(ref mut processor) => {
+          let up_to_date = match state.rendered {
+            Some(ref rendered) => rendered.sample_hz == sample_hz && rendered.channels == Frame::n_channels(),
+            None => false,
+          };
+          if !up_to_date {
+            state.rendered = Some(effects::render_through::<Frame, _, _> (&input, &mut **processor, sample_hz));
+          }
+          state.rendered.as_ref().unwrap().add_to (&mut processed, start);
+        },
+        None => input.render (&mut processed, start, sample_hz),
+      }
+    }
+    mix_into (&processed, buffer, bus.gain, bus.pan);
+  }
+}
+
+impl<Frame: dsp::Frame> Renderable<Frame> for Mixer<Frame>
+    where Frame::Sample: dsp::FromSample<f64> + ToSample<f64> {
+  fn render(&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    self.render_bus (&self.routing(), None, buffer, start, sample_hz);
+  }
+}
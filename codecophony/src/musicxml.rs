@@ -0,0 +1,699 @@
+use super::*;
+
+use std::io::{self, BufRead, Write};
+use std::fs::File;
+use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use xml::reader::{EventReader, XmlEvent};
+
+use phrase::{Phrase, PhraseNote};
+use theory::{Key, Pitch};
+use tempo::{TempoChange, TempoCurve, MeterChange};
+
+
+/// One part of a score, which gets the notes with any of its tags. MusicXML and LilyPond export both use these.
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct ScorePart {
+  pub name: String,
+  /// A note goes in the first part that has one of its tags. A part with no tags takes every note that earlier parts didn't.
+  pub tags: Vec<String>,
+  /// Writes the notes as unpitched percussion, reading each pitch as a General MIDI percussion key.
+  pub percussion: bool,
+}
+
+impl ScorePart {
+  pub fn new (name: &str, tags: &[&str])->ScorePart {
+    ScorePart {
+      name: name.to_string(),
+      tags: tags.iter().map (| tag | tag.to_string()).collect(),
+      percussion: false,
+    }
+  }
+  pub fn with_percussion (mut self, percussion: bool)->ScorePart {
+    self.percussion = percussion;
+    self
+  }
+}
+
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct MusicXmlParameters {
+  pub title: String,
+  /// Converts the notes' times, in seconds, to beats, and gives the time signatures.
+  /// If the notes' times are already in beats, use `TempoMap::new (60.0)`.
+  pub tempo: TempoMap,
+  /// Gives the key signature and the spelling of each pitch.
+  pub key: Key,
+  /// The grid that notes are quantized to, in divisions of a quarter note. 4 quantizes to sixteenth notes, and 12 allows triplets too. It must be at least 1.
+  pub divisions: u32,
+  /// Parts that get no notes are left out.
+  pub parts: Vec<ScorePart>,
+}
+
+// a percussion part, and one for everything else
+pub(crate) fn default_parts()->Vec<ScorePart> {
+  vec![
+    ScorePart::new ("Percussion", &["percussion"]).with_percussion (true),
+    ScorePart::new ("Music", &[]),
+  ]
+}
+
+impl Default for MusicXmlParameters {
+  fn default()->Self {
+    MusicXmlParameters {
+      title: String::new(),
+      tempo: TempoMap::default(),
+      key: Key::major (60),
+      divisions: 4,
+      parts: default_parts(),
+    }
+  }
+}
+
+fn escape (text: &str)->String {
+  text.replace ('&', "&amp;").replace ('<', "&lt;").replace ('>', "&gt;").replace ('"', "&quot;")
+}
+
+// a note or chord, in grid units
+#[derive (Clone, Debug)]
+pub(crate) struct Event {
+  pub(crate) start: i64,
+  pub(crate) end: i64,
+  pub(crate) pitches: Vec<Pitch>,
+}
+
+const NOTE_TYPES: [&str; 8] = ["whole", "half", "quarter", "eighth", "16th", "32nd", "64th", "128th"];
+
+// how a duration can be written: its type, how many dots, and whether it's a triplet
+#[derive (Clone, Copy, Debug)]
+pub(crate) struct NoteShape {
+  pub(crate) type_index: usize,
+  pub(crate) dots: u32,
+  pub(crate) triplet: bool,
+}
+
+pub(crate) fn note_shape (length: i64, divisions: i64)->Option<NoteShape> {
+  for type_index in 0..NOTE_TYPES.len() {
+    for dots in 0..3 {
+      // a whole note with `dots` dots is 4*(2 - 1/2^dots) quarters
+      if length*(1 << (type_index as u32 + dots)) == divisions*4*((1 << (dots + 1)) - 1) {
+        return Some(NoteShape {type_index, dots, triplet: false});
+      }
+    }
+  }
+  for type_index in 0..NOTE_TYPES.len() {
+    if 3*length*(1 << type_index) == 2*divisions*4 {
+      return Some(NoteShape {type_index, dots: 0, triplet: true});
+    }
+  }
+  None
+}
+
+/// Splits a length into pieces that can each be written as one note, longest first, using triplets only where needed.
+/// A piece that can't be written as any single note has no shape.
+pub(crate) fn note_pieces (length: i64, divisions: i64)->Vec<(i64, Option<NoteShape>)> {
+  // the shortest length that plain notes can add up to
+  let unit = (1..divisions*4 + 1).find (| &piece | note_shape (piece, divisions).map_or (false, | shape | !shape.triplet)).unwrap_or (1);
+  let mut result = Vec::new();
+  let mut remaining = length;
+  while remaining > 0 {
+    let candidates = || (1..remaining + 1).rev().filter_map (| piece | note_shape (piece, divisions).map (| shape | (piece, shape)));
+    let choice = if remaining % unit == 0 {
+      candidates().find (| &(_, shape) | !shape.triplet)
+    }
+    else {
+      candidates().find (| &(piece, _) | (remaining - piece) % unit == 0).or_else (|| candidates().next())
+    };
+    match choice {
+      Some((piece, shape)) => {
+        result.push ((piece, Some(shape)));
+        remaining -= piece;
+      },
+      None => {
+        result.push ((remaining, None));
+        remaining = 0;
+      },
+    }
+  }
+  result
+}
+
+/// Converts the notes' times to quantized grid positions, where each grid unit is 1/`divisions` of a quarter note.
+/// Notes are at least one grid unit long, and notes before time 0 start at 0.
+pub(crate) fn quantize (note: &PhraseNote, tempo: &TempoMap, divisions: u32)->(i64, i64) {
+  let grid = | seconds: NoteTime | (tempo.beat (seconds)*divisions as f64).round() as i64;
+  let start = max (grid (note.start), 0);
+  let end = max (grid (note.end), start + 1);
+  (start, end)
+}
+
+// groups notes that start and end together into chords, and stacks overlapping chords into separate voices
+pub(crate) fn voices (notes: &[&PhraseNote], tempo: &TempoMap, divisions: u32)->Vec<Vec<Event>> {
+  let mut chords: BTreeMap<(i64, i64), Vec<Pitch>> = BTreeMap::new();
+  for note in notes {
+    let pitches = chords.entry (quantize (note, tempo, divisions)).or_insert_with (Vec::new);
+    let pitch = frequency_to_nearest_midi_pitch (note.frequency);
+    if !pitches.contains (&pitch) { pitches.push (pitch); }
+  }
+  let mut voices: Vec<Vec<Event>> = Vec::new();
+  for ((start, end), mut pitches) in chords {
+    pitches.sort();
+    let event = Event {start, end, pitches};
+    match voices.iter_mut().find (| voice | voice.last().unwrap().end <= start) {
+      Some(voice) => voice.push (event),
+      None => voices.push (vec![event]),
+    }
+  }
+  voices
+}
+
+/// How many bars it takes to hold every event, and at least one.
+pub(crate) fn bars_needed <'a, I: Iterator<Item=&'a Vec<Event>>> (voices: I, tempo: &TempoMap, divisions: u32)->i64 {
+  let last_end = voices.flat_map (| voice | voice.iter()).map (| event | event.end).max().unwrap_or (0);
+  let mut bars = 1;
+  while ((tempo.bar_start (bars)*divisions as f64).round() as i64) < last_end { bars += 1; }
+  bars
+}
+
+fn write_duration_and_shape (output: &mut String, length: i64, shape: Option<NoteShape>, voice: usize, ties: (bool, bool), instrument: Option<&str>) {
+  output.push_str (&format!("        <duration>{}</duration>\n", length));
+  if ties.0 { output.push_str ("        <tie type=\"stop\"/>\n"); }
+  if ties.1 { output.push_str ("        <tie type=\"start\"/>\n"); }
+  if let Some(instrument) = instrument { output.push_str (&format!("        <instrument id=\"{}\"/>\n", instrument)); }
+  output.push_str (&format!("        <voice>{}</voice>\n", voice + 1));
+  if let Some(shape) = shape {
+    output.push_str (&format!("        <type>{}</type>\n", NOTE_TYPES [shape.type_index]));
+    for _ in 0..shape.dots { output.push_str ("        <dot/>\n"); }
+    if shape.triplet {
+      output.push_str ("        <time-modification>\n          <actual-notes>3</actual-notes>\n          <normal-notes>2</normal-notes>\n        </time-modification>\n");
+    }
+  }
+  if ties.0 || ties.1 {
+    output.push_str ("        <notations>\n");
+    if ties.0 { output.push_str ("          <tied type=\"stop\"/>\n"); }
+    if ties.1 { output.push_str ("          <tied type=\"start\"/>\n"); }
+    output.push_str ("        </notations>\n");
+  }
+}
+
+fn write_rest (output: &mut String, length: i64, divisions: i64, voice: usize) {
+  for (piece, shape) in note_pieces (length, divisions) {
+    output.push_str ("      <note>\n        <rest/>\n");
+    write_duration_and_shape (output, piece, shape, voice, (false, false), None);
+    output.push_str ("      </note>\n");
+  }
+}
+
+// General MIDI percussion keys 35 to 81: each drum's name, and where it goes on a drum staff, as a display step and octave
+const DRUMS: [(&str, char, i32); 47] = [
+  ("Acoustic Bass Drum", 'E', 4), ("Bass Drum 1", 'F', 4), ("Side Stick", 'C', 5), ("Acoustic Snare", 'C', 5),
+  ("Hand Clap", 'D', 5), ("Electric Snare", 'C', 5), ("Low Floor Tom", 'G', 4), ("Closed Hi-Hat", 'G', 5),
+  ("High Floor Tom", 'A', 4), ("Pedal Hi-Hat", 'D', 4), ("Low Tom", 'B', 4), ("Open Hi-Hat", 'G', 5),
+  ("Low-Mid Tom", 'D', 5), ("Hi-Mid Tom", 'E', 5), ("Crash Cymbal 1", 'A', 5), ("High Tom", 'F', 5),
+  ("Ride Cymbal 1", 'F', 5), ("Chinese Cymbal", 'B', 5), ("Ride Bell", 'F', 5), ("Tambourine", 'E', 5),
+  ("Splash Cymbal", 'B', 5), ("Cowbell", 'E', 5), ("Crash Cymbal 2", 'B', 5), ("Vibraslap", 'C', 5),
+  ("Ride Cymbal 2", 'D', 5), ("Hi Bongo", 'E', 5), ("Low Bongo", 'D', 5), ("Mute Hi Conga", 'C', 5),
+  ("Open Hi Conga", 'C', 5), ("Low Conga", 'A', 4), ("High Timbale", 'E', 5), ("Low Timbale", 'D', 5),
+  ("High Agogo", 'E', 5), ("Low Agogo", 'D', 5), ("Cabasa", 'C', 5), ("Maracas", 'C', 5),
+  ("Short Whistle", 'G', 5), ("Long Whistle", 'G', 5), ("Short Guiro", 'C', 5), ("Long Guiro", 'C', 5),
+  ("Claves", 'E', 5), ("Hi Wood Block", 'E', 5), ("Low Wood Block", 'D', 5), ("Mute Cuica", 'C', 5),
+  ("Open Cuica", 'C', 5), ("Mute Triangle", 'A', 5), ("Open Triangle", 'A', 5),
+];
+
+// other keys go on the snare line
+fn drum (key: Pitch)->(String, char, i32) {
+  if key >= 35 && key <= 81 {
+    let (name, step, octave) = DRUMS [(key - 35) as usize];
+    (name.to_string(), step, octave)
+  }
+  else {(format!("Percussion {}", key + 1), 'C', 5)}
+}
+
+// MusicXML numbers the keys from 1, so the ids do too
+fn instrument_id (part_index: usize, key: Pitch)->String {
+  format!("P{}-I{}", part_index + 1, key + 1)
+}
+
+// writes the part of `event` from `start` to `end`, tied to the rest of it
+fn write_event (output: &mut String, event: &Event, start: i64, end: i64, divisions: i64, voice: usize, key: &Key, part: &ScorePart, part_index: usize) {
+  let mut position = start;
+  for (piece, shape) in note_pieces (end - start, divisions) {
+    let ties = (position > event.start, position + piece < event.end);
+    for (index, &pitch) in event.pitches.iter().enumerate() {
+      output.push_str ("      <note>\n");
+      if index > 0 { output.push_str ("        <chord/>\n"); }
+      let mut instrument = None;
+      if part.percussion {
+        let (_, step, octave) = drum (pitch);
+        output.push_str (&format!("        <unpitched>\n          <display-step>{}</display-step>\n          <display-octave>{}</display-octave>\n        </unpitched>\n", step, octave));
+        instrument = Some(instrument_id (part_index, pitch));
+      }
+      else {
+        let spelled = key.spell (pitch);
+        output.push_str (&format!("        <pitch>\n          <step>{}</step>\n", spelled.letter));
+        if spelled.alter != 0 { output.push_str (&format!("          <alter>{}</alter>\n", spelled.alter)); }
+        output.push_str (&format!("          <octave>{}</octave>\n        </pitch>\n", spelled.octave));
+      }
+      write_duration_and_shape (output, piece, shape, voice, ties, instrument.as_ref().map (| id | &id [..]));
+      output.push_str ("      </note>\n");
+    }
+    position += piece;
+  }
+}
+
+pub(crate) fn mode_name (key: &Key)->&'static str {
+  use theory::Mode::*;
+  match key.mode {
+    Ionian | MajorPentatonic | Chromatic => "major",
+    Aeolian | HarmonicMinor | MelodicMinor | MinorPentatonic | Blues => "minor",
+    Dorian => "dorian",
+    Phrygian => "phrygian",
+    Lydian => "lydian",
+    Mixolydian => "mixolydian",
+    Locrian => "locrian",
+  }
+}
+
+fn write_part (output: &mut String, voices: &[Vec<Event>], bars: i64, part: &ScorePart, part_index: usize, parameters: &MusicXmlParameters) {
+  let divisions = parameters.divisions as i64;
+  let tempo = &parameters.tempo;
+  let bar_start = | bar: i64 | (tempo.bar_start (bar)*divisions as f64).round() as i64;
+  let clef = if part.percussion {("percussion", None)} else {
+    let pitches: Vec<Pitch> = voices.iter().flat_map (| voice | voice.iter()).flat_map (| event | event.pitches.iter().cloned()).collect();
+    let average = pitches.iter().sum::<Pitch>() as f64/max (pitches.len(), 1) as f64;
+    if average < 60.0 {("F", Some(4))} else {("G", Some(2))}
+  };
+
+  for bar in 0..bars {
+    let (start, end) = (bar_start (bar), bar_start (bar + 1));
+    output.push_str (&format!("    <measure number=\"{}\">\n", bar + 1));
+
+    let meter_change = tempo.meter_changes().iter().find (| change | change.bar == bar);
+    if bar == 0 || meter_change.is_some() {
+      output.push_str ("      <attributes>\n");
+      if bar == 0 {
+        output.push_str (&format!("        <divisions>{}</divisions>\n", divisions));
+        output.push_str (&format!("        <key>\n          <fifths>{}</fifths>\n          <mode>{}</mode>\n        </key>\n", parameters.key.fifths(), mode_name (&parameters.key)));
+      }
+      let time_signature = tempo.time_signature_at_bar (bar);
+      output.push_str (&format!("        <time>\n          <beats>{}</beats>\n          <beat-type>{}</beat-type>\n        </time>\n", time_signature.numerator, time_signature.denominator));
+      if bar == 0 {
+        output.push_str (&format!("        <clef>\n          <sign>{}</sign>\n", clef.0));
+        if let Some(line) = clef.1 { output.push_str (&format!("          <line>{}</line>\n", line)); }
+        output.push_str ("        </clef>\n");
+      }
+      output.push_str ("      </attributes>\n");
+    }
+
+    // tempo marks go in the top part only
+    if part_index == 0 {
+      for change in tempo.tempo_changes() {
+        let position = (change.beat*divisions as f64).round() as i64;
+        if position >= start && position < end {
+          output.push_str ("      <direction placement=\"above\">\n        <direction-type>\n          <metronome>\n            <beat-unit>quarter</beat-unit>\n");
+          output.push_str (&format!("            <per-minute>{}</per-minute>\n          </metronome>\n        </direction-type>\n", change.beats_per_minute.round()));
+          if position > start { output.push_str (&format!("        <offset>{}</offset>\n", position - start)); }
+          output.push_str (&format!("        <sound tempo=\"{}\"/>\n      </direction>\n", change.beats_per_minute));
+        }
+      }
+    }
+
+    let mut wrote_voice = false;
+    for (voice_index, voice) in voices.iter().enumerate() {
+      let events: Vec<&Event> = voice.iter().filter (| event | event.start < end && event.end > start).collect();
+      if events.is_empty() && voice_index > 0 { continue; }
+      if wrote_voice {
+        output.push_str (&format!("      <backup>\n        <duration>{}</duration>\n      </backup>\n", end - start));
+      }
+      wrote_voice = true;
+      if events.is_empty() {
+        output.push_str (&format!("      <note>\n        <rest measure=\"yes\"/>\n        <duration>{}</duration>\n        <voice>1</voice>\n      </note>\n", end - start));
+        continue;
+      }
+      let mut position = start;
+      for event in events {
+        let event_start = max (event.start, start);
+        if event_start > position { write_rest (output, event_start - position, divisions, voice_index); }
+        position = min (event.end, end);
+        write_event (output, event, event_start, position, divisions, voice_index, &parameters.key, part, part_index);
+      }
+      if position < end { write_rest (output, end - position, divisions, voice_index); }
+    }
+    output.push_str ("    </measure>\n");
+  }
+}
+
+// Puts each note in the first part that takes it, and quantizes each part into voices. Parts that get no notes are left out.
+pub(crate) fn assign_notes_to_parts <'a> (phrase: &Phrase, parts: &'a [ScorePart], tempo: &TempoMap, divisions: u32)->Vec<(&'a ScorePart, Vec<Vec<Event>>)> {
+  assert!(divisions >= 1, "divisions must be at least 1, not {}", divisions);
+  let mut assigned: Vec<(&ScorePart, Vec<&PhraseNote>)> = parts.iter().map (| part | (part, Vec::new())).collect();
+  let mut unplaced = 0;
+  for note in phrase.notes.iter() {
+    match assigned.iter_mut().find (| &&mut (part, _) | part.tags.is_empty() || part.tags.iter().any (| tag | note.tags.contains (tag))) {
+      Some(&mut (_, ref mut notes)) => notes.push (note),
+      None => unplaced += 1,
+    }
+  }
+  if unplaced > 0 {
+    printlnerr!("Warning: {} notes didn't match any part, and were left out of the score", unplaced);
+  }
+  assigned.into_iter().filter (| &(_, ref notes) | !notes.is_empty()).map (| (part, notes) | (part, voices (&notes, tempo, divisions))).collect()
+}
+
+/// Writes the phrase as a MusicXML score, with notes quantized to the grid and split at barlines with ties.
+pub fn to_musicxml (phrase: &Phrase, parameters: &MusicXmlParameters)->String {
+  let parts = assign_notes_to_parts (phrase, &parameters.parts, &parameters.tempo, parameters.divisions);
+
+  // every part gets the same bars, enough for the last note of any part
+  let bars = bars_needed (parts.iter().flat_map (| &(_, ref voices) | voices.iter()), &parameters.tempo, parameters.divisions);
+
+  let mut output = String::new();
+  output.push_str ("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+  output.push_str ("<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 3.1 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">\n");
+  output.push_str ("<score-partwise version=\"3.1\">\n");
+  if !parameters.title.is_empty() {
+    output.push_str (&format!("  <work>\n    <work-title>{}</work-title>\n  </work>\n", escape (&parameters.title)));
+  }
+  output.push_str ("  <part-list>\n");
+  for (index, &(part, ref voices)) in parts.iter().enumerate() {
+    output.push_str (&format!("    <score-part id=\"P{}\">\n      <part-name>{}</part-name>\n", index + 1, escape (&part.name)));
+    if part.percussion {
+      let keys: BTreeSet<Pitch> = voices.iter().flat_map (| voice | voice.iter()).flat_map (| event | event.pitches.iter().cloned()).collect();
+      for &key in keys.iter() {
+        output.push_str (&format!("      <score-instrument id=\"{}\">\n        <instrument-name>{}</instrument-name>\n      </score-instrument>\n", instrument_id (index, key), escape (&drum (key).0)));
+      }
+      for &key in keys.iter().filter (| &&key | key >= 0 && key <= 127) {
+        output.push_str (&format!("      <midi-instrument id=\"{}\">\n        <midi-channel>10</midi-channel>\n        <midi-unpitched>{}</midi-unpitched>\n      </midi-instrument>\n", instrument_id (index, key), key + 1));
+      }
+    }
+    output.push_str ("    </score-part>\n");
+  }
+  output.push_str ("  </part-list>\n");
+  for (index, &(part, ref voices)) in parts.iter().enumerate() {
+    output.push_str (&format!("  <part id=\"P{}\">\n", index + 1));
+    write_part (&mut output, voices, bars, part, index, parameters);
+    output.push_str ("  </part>\n");
+  }
+  output.push_str ("</score-partwise>\n");
+  output
+}
+
+pub fn write_musicxml <P: AsRef<Path>> (phrase: &Phrase, path: P, parameters: &MusicXmlParameters)->io::Result<()> {
+  File::create (path)?.write_all (to_musicxml (phrase, parameters).as_bytes())
+}
+
+
+/// A score read from MusicXML.
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct MusicXmlImport {
+  pub title: String,
+  /// The score's tempo marks and time signatures, which were used to convert its beats to seconds.
+  pub tempo: TempoMap,
+  /// Each note is tagged with "part:" and its part name and "voice:" and its voice number, and "percussion" if it's unpitched.
+  /// Notes also get "dynamic:" tags for the latest dynamic in their part, like "dynamic:mf",
+  /// and "articulation:" tags for their articulations, like "articulation:staccato".
+  pub phrase: Phrase,
+}
+
+// just enough of an XML element to read MusicXML
+#[derive (Clone, Default, Debug)]
+struct Element {
+  name: String,
+  attributes: Vec<(String, String)>,
+  children: Vec<Element>,
+  text: String,
+}
+
+impl Element {
+  fn attribute (&self, name: &str)->Option<&str> {
+    self.attributes.iter().find (| &&(ref key, _) | key == name).map (| &(_, ref value) | &value [..])
+  }
+  fn child (&self, name: &str)->Option<&Element> {
+    self.children.iter().find (| child | child.name == name)
+  }
+  fn children_named (&self, name: &str)->Vec<&Element> {
+    self.children.iter().filter (| child | child.name == name).collect()
+  }
+  fn child_text (&self, name: &str)->Option<&str> {
+    self.child (name).map (| child | child.text.trim())
+  }
+  // None if the child is missing or isn't a number; NaN and infinity are errors
+  fn child_number (&self, name: &str)->io::Result<Option<f64>> {
+    self.child_text (name).map_or (Ok(None), | text | finite_number (text, name))
+  }
+}
+
+fn invalid_data (message: String)->io::Error {
+  io::Error::new (io::ErrorKind::InvalidData, message)
+}
+
+fn finite_number (text: &str, name: &str)->io::Result<Option<f64>> {
+  match text.trim().parse::<f64>() {
+    Ok(value) if !value.is_finite() => Err(invalid_data (format!("{} should be a finite number, not {:?}", name, text))),
+    Ok(value) => Ok(Some(value)),
+    Err(_) => Ok(None),
+  }
+}
+
+fn parse_xml <R: io::Read> (source: R)->io::Result<Element> {
+  let mut stack = vec![Element::default()];
+  for event in EventReader::new (source) {
+    match event.map_err (| error | invalid_data (format!("couldn't parse MusicXML: {}", error)))? {
+      XmlEvent::StartElement {name, attributes, ..} => stack.push (Element {
+        name: name.local_name,
+        attributes: attributes.into_iter().map (| attribute | (attribute.name.local_name, attribute.value)).collect(),
+        .. Default::default()
+      }),
+      XmlEvent::EndElement {..} => {
+        let element = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push (element);
+      },
+      XmlEvent::Characters (text) | XmlEvent::CData (text) => stack.last_mut().unwrap().text.push_str (&text),
+      _ => (),
+    }
+  }
+  stack.pop().unwrap().children.into_iter().next().ok_or_else (|| invalid_data ("the MusicXML document is empty".to_string()))
+}
+
+fn step_pitch (step: &str, octave: f64)->Option<Pitch> {
+  let natural = match step {
+    "C" => 0, "D" => 2, "E" => 4, "F" => 5, "G" => 7, "A" => 9, "B" => 11,
+    _ => return None,
+  };
+  Some((octave as Pitch + 1)*12 + natural)
+}
+
+// a note that's still being read, which a later tied note may extend
+struct ImportedNote {
+  note: PhraseNote,
+  tied_onwards: bool,
+}
+
+// The MIDI key of each of a part's instruments that has one, by instrument id, from <midi-unpitched>.
+// Notes that don't say which instrument they are use the first one.
+struct PartInstruments {
+  keys: HashMap<String, i32>,
+  first: Option<String>,
+}
+
+impl PartInstruments {
+  fn new (score_part: &Element)->io::Result<PartInstruments> {
+    let mut keys = HashMap::new();
+    for midi_instrument in score_part.children_named ("midi-instrument") {
+      if let (Some(id), Some(unpitched)) = (midi_instrument.attribute ("id"), midi_instrument.child_number ("midi-unpitched")?) {
+        // MusicXML numbers the keys from 1
+        keys.insert (id.to_string(), unpitched.round() as i32 - 1);
+      }
+    }
+    let first = score_part.child ("score-instrument").and_then (| instrument | instrument.attribute ("id")).map (| id | id.to_string());
+    Ok(PartInstruments {keys, first})
+  }
+
+  fn key (&self, note: &Element)->Option<i32> {
+    let id = note.child ("instrument").and_then (| instrument | instrument.attribute ("id")).or_else (|| self.first.as_ref().map (| id | &id [..]))?;
+    self.keys.get (id).cloned()
+  }
+}
+
+// reads the notes of one part, in beats, and adds its tempo marks and (for the first part) time signatures to the tempo map
+fn read_part (measures: &[&Element], part_name: &str, instruments: &PartInstruments, first_part: bool, tempo_marks: &mut Vec<(Beats, f64)>, tempo: &mut TempoMap)->io::Result<Vec<PhraseNote>> {
+  let mut notes: Vec<ImportedNote> = Vec::new();
+  let mut dynamics: Vec<(Beats, String)> = Vec::new();
+  let mut divisions = 1.0;
+  let mut measure_start = 0.0;
+  for (bar, measure) in measures.iter().enumerate() {
+    let mut position = 0.0;
+    let mut furthest = 0.0f64;
+    let mut chord_start = 0.0;
+    for element in measure.children.iter() {
+      let duration = element.child_number ("duration")?.unwrap_or (0.0)/divisions;
+      match &element.name [..] {
+        "attributes" => {
+          if let Some(new_divisions) = element.child_number ("divisions")? {
+            if new_divisions <= 0.0 {
+              return Err(invalid_data (format!("in part {}, bar {}, divisions should be positive, not {}", part_name, bar + 1, new_divisions)));
+            }
+            divisions = new_divisions;
+          }
+          if let Some(time) = element.child ("time") {
+            // "3+2" means 5
+            let numerator = time.child_text ("beats").and_then (| beats | beats.split ('+').map (| part | part.trim().parse::<u32>().ok()).sum::<Option<u32>>());
+            let denominator = time.child_text ("beat-type").and_then (| beat_type | beat_type.parse().ok());
+            if let (true, Some(numerator), Some(denominator)) = (first_part, numerator, denominator) {
+              tempo.add_meter_change (MeterChange {bar: bar as i64, time_signature: TimeSignature::new (numerator, denominator)});
+            }
+          }
+        },
+        "backup" => position -= duration,
+        "forward" => position += duration,
+        "direction" | "sound" => {
+          let time = measure_start + position + element.child_number ("offset")?.unwrap_or (0.0)/divisions;
+          for direction_type in element.children_named ("direction-type") {
+            for marking in direction_type.children_named ("dynamics") {
+              for dynamic in marking.children.iter() {
+                let name = if dynamic.name == "other-dynamics" {dynamic.text.trim().to_string()} else {dynamic.name.clone()};
+                dynamics.push ((time, name));
+              }
+            }
+          }
+          let sound = if element.name == "sound" {Some(element)} else {element.child ("sound")};
+          if let Some(value) = sound.and_then (| sound | sound.attribute ("tempo")) {
+            if let Some(beats_per_minute) = finite_number (value, "tempo")? {
+              if beats_per_minute <= 0.0 {
+                return Err(invalid_data (format!("in part {}, bar {}, the tempo should be positive, not {}", part_name, bar + 1, beats_per_minute)));
+              }
+              tempo_marks.push ((time, beats_per_minute));
+            }
+          }
+        },
+        "note" => {
+          if element.child ("grace").is_some() { continue; }
+          let start = if element.child ("chord").is_some() {chord_start} else {
+            chord_start = position;
+            position += duration;
+            chord_start
+          };
+          furthest = furthest.max (position);
+          if element.child ("cue").is_some() || element.child ("rest").is_some() { continue; }
+
+          // MusicXML's octaves are 0 to 9, and anything much further out would overflow the pitch
+          let octave = | parent: &Element, name: &str | -> io::Result<f64> {
+            let octave = parent.child_number (name)?.unwrap_or (4.0);
+            if octave.fract() != 0.0 || octave < 0.0 || octave > 9.0 {
+              return Err(invalid_data (format!("in part {}, bar {}, the {} should be a whole number from 0 to 9, not {}", part_name, bar + 1, name, octave)));
+            }
+            Ok(octave)
+          };
+          let mut tags = HashSet::new();
+          let pitch = if let Some(pitch) = element.child ("pitch") {
+            let alter = pitch.child_number ("alter")?.unwrap_or (0.0);
+            if alter.abs() > 12.0 {
+              return Err(invalid_data (format!("in part {}, bar {}, the alter should be at most 12 semitones either way, not {}", part_name, bar + 1, alter)));
+            }
+            step_pitch (pitch.child_text ("step").unwrap_or (""), octave (pitch, "octave")?).map (| natural | natural as f64 + alter)
+          }
+          else if let Some(unpitched) = element.child ("unpitched") {
+            tags.insert ("percussion".to_string());
+            // the staff position only says where the note is drawn, so it's just a fallback for parts that don't say which drum it is
+            match instruments.key (element) {
+              Some(key) => Some(key as f64),
+              None => step_pitch (unpitched.child_text ("display-step").unwrap_or ("E"), octave (unpitched, "display-octave")?).map (| natural | natural as f64),
+            }
+          }
+          else { None };
+          let pitch = match pitch {Some(pitch) => pitch, None => continue};
+          // microtonal alterations are kept as a fraction of a semitone
+          let whole = pitch.round();
+          let frequency = midi_pitch_to_frequency (whole as Pitch)*2f64.powf ((pitch - whole)/12.0);
+
+          tags.insert (format!("part:{}", part_name));
+          tags.insert (format!("voice:{}", element.child_text ("voice").unwrap_or ("1")));
+          let mut tie_types: Vec<&str> = element.children_named ("tie").into_iter().filter_map (| tie | tie.attribute ("type")).collect();
+          for notations in element.children_named ("notations") {
+            tie_types.extend (notations.children_named ("tied").into_iter().filter_map (| tied | tied.attribute ("type")));
+            for articulations in notations.children_named ("articulations") {
+              for articulation in articulations.children.iter() {
+                tags.insert (format!("articulation:{}", articulation.name));
+              }
+            }
+            if notations.child ("fermata").is_some() { tags.insert ("articulation:fermata".to_string()); }
+          }
+          let tied_from_earlier = tie_types.contains (&"stop");
+          let tied_onwards = tie_types.contains (&"start");
+
+          let start = measure_start + start;
+          let end = start + duration;
+          if tied_from_earlier {
+            if let Some(earlier) = notes.iter_mut().rev().find (| earlier | earlier.tied_onwards && (earlier.note.end - start).abs() < 1e-6 && (earlier.note.frequency/frequency - 1.0).abs() < 1e-9) {
+              earlier.note.end = end;
+              earlier.tied_onwards = tied_onwards;
+              continue;
+            }
+          }
+          let mut note = PhraseNote::new (start, end, frequency);
+          note.tags = tags;
+          notes.push (ImportedNote {note, tied_onwards});
+        },
+        _ => (),
+      }
+    }
+    measure_start += furthest.max (position);
+  }
+
+  dynamics.sort_by (| a, b | a.0.partial_cmp (&b.0).unwrap());
+  Ok(notes.into_iter().map (| imported | {
+    let mut note = imported.note;
+    if let Some(&(_, ref dynamic)) = dynamics.iter().rev().find (| &&(time, _) | time <= note.start + 1e-6) {
+      note.tags.insert (format!("dynamic:{}", dynamic));
+    }
+    note
+  }).collect())
+}
+
+/// Reads an uncompressed MusicXML score, either partwise or timewise.
+/// Compressed .mxl files aren't supported; they give an error, and the score inside them has to be extracted first.
+///
+/// Tied notes become single notes, and grace notes, cue notes and repeats are ignored.
+/// Unpitched notes get the MIDI key of their instrument's <midi-unpitched>, or their staff position if they don't have one.
+pub fn from_musicxml <R: io::Read> (source: R)->io::Result<MusicXmlImport> {
+  let mut source = io::BufReader::new (source);
+  // .mxl files are zip archives
+  if source.fill_buf()?.starts_with (b"PK\x03\x04") {
+    return Err(invalid_data ("this is a compressed MusicXML (.mxl) file, which isn't supported; extract the .musicxml file inside it and read that instead".to_string()));
+  }
+  let root = parse_xml (source)?;
+  let timewise = match &root.name [..] {
+    "score-partwise" => false,
+    "score-timewise" => true,
+    other => return Err(invalid_data (format!("expected a MusicXML score, found <{}>", other))),
+  };
+  let title = root.child ("work").and_then (| work | work.child_text ("work-title"))
+    .or_else (|| root.child_text ("movement-title")).unwrap_or ("").to_string();
+
+  let score_parts = root.child ("part-list").map_or (Vec::new(), | list | list.children_named ("score-part"));
+  let mut tempo = TempoMap::default();
+  let mut tempo_marks = Vec::new();
+  let mut notes = Vec::new();
+  for (index, score_part) in score_parts.iter().enumerate() {
+    let id = score_part.attribute ("id").unwrap_or ("");
+    let name = score_part.child_text ("part-name").filter (| name | !name.is_empty()).unwrap_or (id);
+    let measures: Vec<&Element> = if timewise {
+      root.children_named ("measure").into_iter().filter_map (| measure | measure.children_named ("part").into_iter().find (| part | part.attribute ("id") == Some(id))).collect()
+    }
+    else {
+      root.children_named ("part").into_iter().find (| part | part.attribute ("id") == Some(id)).map_or (Vec::new(), | part | part.children_named ("measure"))
+    };
+    notes.extend (read_part (&measures, name, &PartInstruments::new (score_part)?, index == 0, &mut tempo_marks, &mut tempo)?);
+  }
+
+  for (beat, beats_per_minute) in tempo_marks {
+    tempo.add_tempo_change (TempoChange {beat, beats_per_minute, curve: TempoCurve::Step});
+  }
+  let mut phrase = Phrase {notes};
+  tempo.beats_to_seconds (&mut phrase);
+  Ok(MusicXmlImport {title, tempo, phrase})
+}
+
+pub fn read_musicxml <P: AsRef<Path>> (path: P)->io::Result<MusicXmlImport> {
+  from_musicxml (File::open (path)?)
+}
@@ -0,0 +1,184 @@
+use super::*;
+
+
+#[derive (Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub enum Waveform {
+  Saw,
+  Square,
+  /// `width` is the fraction of each cycle spent high, between 0 and 1. A width of 0.5 is a square wave.
+  Pulse {width: f64},
+  Triangle,
+  /// White noise, which doesn't depend on the frequency. Notes with the same seed sound the same.
+  Noise {seed: u32},
+}
+
+/// A note played by a basic synthesizer oscillator.
+///
+/// The waveforms with corners are band-limited with PolyBLEP/PolyBLAMP corrections,
+/// so high notes don't alias nearly as badly as a naive waveform would.
+#[derive (Clone, Debug)]
+pub struct Oscillator {
+  pub start: NoteTime,
+  // how long the note is held; the envelope may continue after that
+  pub duration: NoteTime,
+  pub frequency: f64,
+  pub amplitude: f64,
+  pub waveform: Waveform,
+  pub envelope: Envelope,
+}
+
+impl Oscillator {
+  pub fn new (waveform: Waveform, start: NoteTime, duration: NoteTime, frequency: f64, amplitude: f64)->Oscillator {
+    Oscillator {start, duration, frequency, amplitude, waveform, envelope: Envelope::default()}
+  }
+  pub fn saw (start: NoteTime, duration: NoteTime, frequency: f64, amplitude: f64)->Oscillator {
+    Oscillator::new (Waveform::Saw, start, duration, frequency, amplitude)
+  }
+  pub fn square (start: NoteTime, duration: NoteTime, frequency: f64, amplitude: f64)->Oscillator {
+    Oscillator::new (Waveform::Square, start, duration, frequency, amplitude)
+  }
+  pub fn pulse (start: NoteTime, duration: NoteTime, frequency: f64, amplitude: f64, width: f64)->Oscillator {
+    Oscillator::new (Waveform::Pulse {width}, start, duration, frequency, amplitude)
+  }
+  pub fn triangle (start: NoteTime, duration: NoteTime, frequency: f64, amplitude: f64)->Oscillator {
+    Oscillator::new (Waveform::Triangle, start, duration, frequency, amplitude)
+  }
+  pub fn noise (start: NoteTime, duration: NoteTime, amplitude: f64, seed: u32)->Oscillator {
+    Oscillator::new (Waveform::Noise {seed}, start, duration, 0.0, amplitude)
+  }
+  pub fn with_envelope (mut self, envelope: Envelope)->Oscillator {
+    self.envelope = envelope;
+    self
+  }
+
+  // `frame` is only used by the noise, which has to come out the same no matter how the timeline is split into buffers
+  fn value (&self, frame: FrameTime, sample_hz: f64)->f64 {
+    let time = frame as f64/sample_hz;
+    if time < self.start || time > self.end() { return 0.0; }
+    let envelope = self.envelope.level (time - self.start, self.duration);
+    self.amplitude*envelope*self.waveform.value (self.frequency*time, self.frequency/sample_hz, frame)
+  }
+}
+
+// Corrections for a jump in the waveform at phase 0, spread over the samples on either side.
+// `phase` and `increment` are in cycles.
+fn polyblep (phase: f64, increment: f64)->f64 {
+  if phase < increment {
+    let t = phase/increment;
+    2.0*t - t*t - 1.0
+  }
+  else if phase > 1.0 - increment {
+    let t = (phase - 1.0)/increment;
+    t*t + 2.0*t + 1.0
+  }
+  else {0.0}
+}
+
+// the same, for a sudden change in slope
+fn polyblamp (phase: f64, increment: f64)->f64 {
+  if phase < increment {
+    let t = phase/increment - 1.0;
+    -t*t*t/3.0
+  }
+  else if phase > 1.0 - increment {
+    let t = (phase - 1.0)/increment + 1.0;
+    t*t*t/3.0
+  }
+  else {0.0}
+}
+
+fn wrap (phase: f64)->f64 {
+  phase - phase.floor()
+}
+
+// a well-mixed hash of the seed and frame, as a number from -1 to 1
+fn noise_value (seed: u32, frame: FrameTime)->f64 {
+  // splitmix64
+  let mut z = ((seed as u64) << 32 ^ frame as u64).wrapping_add (0x9E3779B97F4A7C15);
+  z = (z ^ (z >> 30)).wrapping_mul (0xBF58476D1CE4E5B9);
+  z = (z ^ (z >> 27)).wrapping_mul (0x94D049BB133111EB);
+  z ^= z >> 31;
+  (z >> 11) as f64/(1u64 << 52) as f64 - 1.0
+}
+
+impl Waveform {
+  /// The value at `cycles` cycles into the waveform, which advances by `increment` cycles per sample.
+  pub fn value (&self, cycles: f64, increment: f64, frame: FrameTime)->f64 {
+    let phase = wrap (cycles);
+    let pulse = | width: f64 | {
+      let naive = if phase < width {1.0} else {-1.0};
+      // remove the DC offset, so narrow pulses don't push everything off center
+      naive + polyblep (phase, increment) - polyblep (wrap (phase - width), increment) - (2.0*width - 1.0)
+    };
+    match *self {
+      Waveform::Noise {seed} => return noise_value (seed, frame),
+      // every harmonic would be above the Nyquist frequency
+      _ if increment >= 0.5 => return 0.0,
+      _ => (),
+    }
+    match *self {
+      Waveform::Saw => 2.0*phase - 1.0 - polyblep (phase, increment),
+      Waveform::Square => pulse (0.5),
+      Waveform::Pulse {width} => pulse (width.max(0.0).min(1.0)),
+      Waveform::Triangle => {
+        let naive = if phase < 0.5 {4.0*phase - 1.0} else {3.0 - 4.0*phase};
+        // the slope changes by 8 per cycle at each corner, upward at phase 0 and downward at phase 0.5;
+        // polyblamp() is scaled for a change of 2, like polyblep() is for a jump of 2
+        naive + 4.0*increment*(polyblamp (phase, increment) - polyblamp (wrap (phase - 0.5), increment))
+      },
+      Waveform::Noise {..} => unreachable!(),
+    }
+  }
+}
+
+impl Windowed for Oscillator {
+  fn start (&self)->NoteTime {self.start}
+  fn end (&self)->NoteTime {self.start+self.envelope.duration (self.duration)}
+}
+impl<Frame: dsp::Frame> Renderable<Frame> for Oscillator
+    where Frame::Sample: dsp::FromSample<f64> {
+  fn render(&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    for (index, value_mut) in buffer.iter_mut().enumerate() {
+      let value = Frame::Sample::from_sample(self.value (start + index as FrameTime, sample_hz));
+      *value_mut = value_mut.add_amp(Frame::from_fn(|_| value).to_signed_frame());
+    }
+  }
+}
+
+impl Nudgable for Oscillator {
+  fn nudge(&mut self, distance: NoteTime) {
+    self.start += distance;
+  }
+}
+
+impl Dilatable for Oscillator {
+  fn dilate(&mut self, amount: f64, origin: f64) {
+    self.start = origin + (self.start-origin)*amount;
+    self.duration *= amount;
+  }
+}
+
+impl Retimable for Oscillator {
+  fn retime(&mut self, map: &Fn(NoteTime)->NoteTime) {
+    let end = map(self.start + self.duration);
+    self.start = map(self.start);
+    self.duration = end - self.start;
+  }
+}
+
+impl Placeable for Oscillator {
+  fn place(&mut self, start: NoteTime, duration: NoteTime) {
+    self.start = start;
+    self.duration = duration;
+  }
+}
+
+impl Pitched for Oscillator {
+  fn frequency(&self)->f64 {self.frequency}
+}
+
+impl PitchShiftable for Oscillator {
+  fn pitch_shift(&mut self, frequency_ratio: f64) {
+    self.frequency *= frequency_ratio;
+  }
+}
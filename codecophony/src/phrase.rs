@@ -3,7 +3,7 @@ use super::*;
 
 use std::collections::HashSet;
 
-#[derive (Clone, Serialize, Deserialize, Debug)]
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
 pub struct PhraseNote {
   pub start: NoteTime,
   pub end: NoteTime,
@@ -32,6 +32,20 @@ impl Dilatable for PhraseNote {
   }
 }
 
+impl Retimable for PhraseNote {
+  fn retime(&mut self, map: &Fn(NoteTime)->NoteTime) {
+    self.start = map(self.start);
+    self.end = map(self.end);
+  }
+}
+
+impl Placeable for PhraseNote {
+  fn place(&mut self, start: NoteTime, duration: NoteTime) {
+    self.start = start;
+    self.end = start + duration;
+  }
+}
+
 impl Pitched for PhraseNote {
   fn frequency(&self)->f64 {self.frequency}
 }
@@ -43,17 +57,29 @@ impl PitchShiftable for PhraseNote {
 }
 
 
-#[derive (Clone, Serialize, Deserialize, Debug)]
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
 pub struct Phrase {
   pub notes: Vec<PhraseNote>,
 }
 
 
+impl Retimable for Phrase {
+  fn retime(&mut self, map: &Fn(NoteTime)->NoteTime) {
+    self.notes.retime(map);
+  }
+}
+
 impl Phrase {
+  /// Combines MIDI notes into one phrase, with the notes tagged "pitched" or "percussion".
+  pub fn from_midi (pitched: &[MIDIPitchedNote], percussion: &[MIDIPercussionNote])->Phrase {
+    Phrase {
+      notes: pitched.iter().map (| note | note.to_phrase_note()).chain (percussion.iter().map (| note | note.to_phrase_note())).collect()
+    }
+  }
   pub fn to_midi_pitched <F: FnMut (&PhraseNote)->(i32, u32)> (&self, mut velocity_and_instrument_picker: F)->Vec<MIDIPitchedNote> {
     self.notes.iter().map(| note | {
       let (velocity, instrument) = velocity_and_instrument_picker (&note);
-      MIDIPitchedNote::new (note.start, note.end - note.start, frequency_to_nearest_midi_pitch (note.frequency), velocity, instrument)
+      MIDIPitchedNote::from_frequency (note.start, note.end - note.start, note.frequency, velocity, instrument)
     }).collect()
   }
   pub fn to_midi_percussion <F: FnMut (&PhraseNote)->(i32, i32)> (&self, mut velocity_and_instrument_picker: F)->Vec<MIDIPercussionNote> {
@@ -82,6 +108,26 @@ impl ToPhraseNote for SineWave {
   }
 }
 
+impl ToPhraseNote for Oscillator {
+  fn to_phrase_note (&self)->PhraseNote {
+    let mut tags = HashSet::new();
+    let waveform = match self.waveform {
+      Waveform::Saw => "saw",
+      Waveform::Square => "square",
+      Waveform::Pulse {..} => "pulse",
+      Waveform::Triangle => "triangle",
+      Waveform::Noise {..} => "noise",
+    };
+    tags.insert (String::from_str (waveform).unwrap());
+    PhraseNote {
+      start: self.start,
+      end: self.start + self.duration,
+      frequency: self.frequency,
+      tags,
+    }
+  }
+}
+
 
 impl ToPhraseNote for MIDIPitchedNote {
   fn to_phrase_note (&self)->PhraseNote {
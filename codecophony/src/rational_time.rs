@@ -0,0 +1,191 @@
+use super::*;
+
+use num_rational::Ratio;
+use num_traits::{CheckedAdd, CheckedSub, CheckedMul};
+
+use phrase::{Phrase, PhraseNote};
+
+
+/// Exact musical time, in quarter notes, for composing without floating-point drift.
+/// A triplet eighth note is exactly `exact_beats (1, 3)`.
+pub type ExactBeats = Ratio<i64>;
+
+pub fn exact_beats (numerator: i64, denominator: i64)->ExactBeats {
+  Ratio::new (numerator, denominator)
+}
+
+pub fn to_beats (beats: ExactBeats)->Beats {
+  *beats.numer() as f64 / *beats.denom() as f64
+}
+
+/// The simplest fraction within floating-point error of `beats`, so that 1.0/3.0 becomes exactly 1/3.
+/// None if `beats` isn't finite, or there's no such fraction that fits in an ExactBeats.
+pub fn exact_from_beats (beats: Beats)->Option<ExactBeats> {
+  if !beats.is_finite() || beats.abs() >= i64::max_value() as f64 { return None; }
+  let tolerance = beats.abs()*1e-12;
+  // the continued fraction convergents of `beats`, until one is close enough or they stop fitting in an i64
+  let (mut previous_numerator, mut numerator) = (0i64, 1i64);
+  let (mut previous_denominator, mut denominator) = (1i64, 0i64);
+  let mut remainder = beats;
+  loop {
+    let whole = remainder.floor();
+    if whole.abs() >= i64::max_value() as f64 { return None; }
+    let next = (whole as i64).checked_mul (numerator).and_then (| product | product.checked_add (previous_numerator))
+      .and_then (| next_numerator | (whole as i64).checked_mul (denominator).and_then (| product | product.checked_add (previous_denominator)).map (| next_denominator | (next_numerator, next_denominator)));
+    let (next_numerator, next_denominator) = next?;
+    previous_numerator = numerator;
+    numerator = next_numerator;
+    previous_denominator = denominator;
+    denominator = next_denominator;
+    if (numerator as f64/denominator as f64 - beats).abs() <= tolerance || remainder == whole {
+      return Some(Ratio::new (numerator, denominator));
+    }
+    remainder = 1.0/(remainder - whole);
+  }
+}
+
+/// A note whose position is kept in exact beats while composing.
+///
+/// The note's own times are ignored; to_seconds() replaces them with the exact position, mapped through a TempoMap,
+/// so nudging and dilating a Scored note any number of times never builds up error.
+///
+/// Nudgable and Dilatable work in beats, and stay exact for distances and amounts that are simple fractions.
+/// If a result would overflow an ExactBeats, it gets rounded to the nearest fraction with a power of 2 denominator that fits.
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Scored<N> {
+  pub start: ExactBeats,
+  pub duration: ExactBeats,
+  pub note: N,
+}
+
+impl<N> Scored<N> {
+  pub fn new (start: ExactBeats, duration: ExactBeats, note: N)->Scored<N> {
+    Scored {start, duration, note}
+  }
+  pub fn end (&self)->ExactBeats {
+    self.start + self.duration
+  }
+
+  /// Moves the note by exactly `distance`. Returns None, leaving the note unchanged, if the result would overflow.
+  pub fn checked_nudge (&mut self, distance: ExactBeats)->Option<()> {
+    let start = self.start.checked_add (&distance)?;
+    start.checked_add (&self.duration)?;
+    self.start = start;
+    Some(())
+  }
+  /// Stretches the note's position around `origin` by exactly `amount`. Returns None, leaving the note unchanged, if the result would overflow.
+  pub fn checked_dilate (&mut self, amount: ExactBeats, origin: ExactBeats)->Option<()> {
+    let start = origin.checked_add (&self.start.checked_sub (&origin)?.checked_mul (&amount)?)?;
+    let duration = self.duration.checked_mul (&amount)?;
+    start.checked_add (&duration)?;
+    self.start = start;
+    self.duration = duration;
+    Some(())
+  }
+
+  // For when exact arithmetic overflows. With a shared power of 2 denominator, the end can't overflow either.
+  fn place_approximately (&mut self, start: Beats, duration: Beats) {
+    let limit = (1u64 << 62) as f64;
+    let largest = start.abs().max ((start + duration).abs());
+    if !largest.is_finite() || largest >= limit || !duration.is_finite() {
+      printlnerr!("codecophony: a Scored note can't be moved to {} beats with duration {}", start, duration);
+      return;
+    }
+    let mut denominator: i64 = 1 << 52;
+    while largest*denominator as f64 >= limit { denominator >>= 1; }
+    let exact = | beats: Beats | Ratio::new ((beats*denominator as f64).round() as i64, denominator);
+    self.start = exact (start);
+    self.duration = exact (duration);
+  }
+}
+
+impl<N> Nudgable for Scored<N> {
+  fn nudge (&mut self, distance: Beats) {
+    if let Some(exact) = exact_from_beats (distance) {
+      if self.checked_nudge (exact).is_some() { return; }
+    }
+    let (start, duration) = (to_beats (self.start) + distance, to_beats (self.duration));
+    self.place_approximately (start, duration);
+  }
+}
+
+impl<N> Dilatable for Scored<N> {
+  fn dilate (&mut self, amount: f64, origin: Beats) {
+    if let (Some(exact_amount), Some(exact_origin)) = (exact_from_beats (amount), exact_from_beats (origin)) {
+      if self.checked_dilate (exact_amount, exact_origin).is_some() { return; }
+    }
+    let (start, duration) = (origin + (to_beats (self.start) - origin)*amount, to_beats (self.duration)*amount);
+    self.place_approximately (start, duration);
+  }
+}
+
+impl<N: Placeable + Clone> Scored<N> {
+  pub fn to_seconds (&self, tempo: &TempoMap)->N {
+    let start = tempo.seconds (to_beats (self.start));
+    let end = tempo.seconds (to_beats (self.end()));
+    let mut note = self.note.clone();
+    note.place (start, end - start);
+    note
+  }
+}
+
+pub fn to_seconds <N: Placeable + Clone> (notes: &[Scored<N>], tempo: &TempoMap)->Vec<N> {
+  notes.iter().map (| note | note.to_seconds (tempo)).collect()
+}
+
+impl<N: Pitched> Pitched for Scored<N> {
+  fn frequency(&self)->f64 {self.note.frequency()}
+}
+
+impl<N: PitchShiftable> PitchShiftable for Scored<N> {
+  fn pitch_shift(&mut self, frequency_ratio: f64) {
+    self.note.pitch_shift(frequency_ratio);
+  }
+}
+
+/// A Phrase whose notes are positioned in exact beats.
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug, Default)]
+pub struct ScoredPhrase {
+  pub notes: Vec<Scored<PhraseNote>>,
+}
+
+impl ScoredPhrase {
+  /// Treats the times of the phrase as beats, and makes them exact.
+  /// Times that aren't simple fractions get rounded, like when nudging a Scored note overflows.
+  pub fn from_phrase (phrase: &Phrase)->ScoredPhrase {
+    ScoredPhrase {
+      notes: phrase.notes.iter().map (| note | {
+        let mut result = Scored::new (Ratio::from_integer (0), Ratio::from_integer (0), note.clone());
+        let (start, duration) = (note.start, note.end - note.start);
+        match (exact_from_beats (start), exact_from_beats (duration)) {
+          (Some(exact_start), Some(exact_duration)) if exact_start.checked_add (&exact_duration).is_some() => {
+            result.start = exact_start;
+            result.duration = exact_duration;
+          },
+          _ => result.place_approximately (start, duration),
+        }
+        result
+      }).collect()
+    }
+  }
+
+  pub fn to_phrase (&self, tempo: &TempoMap)->Phrase {
+    Phrase {notes: to_seconds (&self.notes, tempo)}
+  }
+}
+
+impl Nudgable for ScoredPhrase {
+  fn nudge (&mut self, distance: Beats) {
+    for note in self.notes.iter_mut() {
+      note.nudge (distance);
+    }
+  }
+}
+
+impl Dilatable for ScoredPhrase {
+  fn dilate (&mut self, amount: f64, origin: Beats) {
+    for note in self.notes.iter_mut() {
+      note.dilate (amount, origin);
+    }
+  }
+}
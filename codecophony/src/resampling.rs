@@ -0,0 +1,207 @@
+use super::*;
+
+use std::f64::consts::PI;
+use std::sync::Mutex;
+
+
+/// How a PositionedSequence converts its frames when it gets rendered at a different sample rate.
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Resampling {
+  /// Cheap, but aliases audibly.
+  Linear,
+  /// Band-limited interpolation with a Kaiser-windowed sinc, which reaches `zero_crossings` zero crossings to each side.
+  /// More zero crossings give a sharper filter, at a proportional cost in speed.
+  WindowedSinc {zero_crossings: usize},
+  /// The same filter as WindowedSinc, but with the coefficients precomputed for every phase.
+  /// This is much faster when the ratio between the sample rates is a fraction with a small denominator,
+  /// like 48000/44100 = 160/147. Other ratios fall back to WindowedSinc.
+  Polyphase {zero_crossings: usize},
+}
+
+impl Default for Resampling {
+  fn default()->Self {
+    Resampling::Linear
+  }
+}
+
+/// Enough to keep the whole audible range below 20 kHz when converting between 44.1 kHz and 48 kHz.
+pub const HIGH_QUALITY_ZERO_CROSSINGS: usize = 64;
+
+impl Resampling {
+  pub fn high_quality()->Self {
+    Resampling::Polyphase {zero_crossings: HIGH_QUALITY_ZERO_CROSSINGS}
+  }
+}
+
+// about 80 dB of stopband attenuation
+const KAISER_BETA: f64 = 7.86;
+// Half the width of the transition band, relative to the cutoff, times the number of zero crossings.
+// This comes from the usual Kaiser filter design formula for 80 dB.
+const KAISER_HALF_TRANSITION: f64 = 2.51;
+// more phases than this, and the table costs more than it saves
+const MAX_POLYPHASE_PHASES: i64 = 1024;
+
+fn bessel_i0 (x: f64)->f64 {
+  let mut sum = 1.0;
+  let mut term = 1.0;
+  let mut k = 1.0;
+  loop {
+    term *= (x/(2.0*k))*(x/(2.0*k));
+    sum += term;
+    if term < sum*1e-12 { return sum; }
+    k += 1.0;
+  }
+}
+
+fn sinc (x: f64)->f64 {
+  if x == 0.0 { 1.0 } else { (PI*x).sin()/(PI*x) }
+}
+
+// The cutoff, relative to the Nyquist frequency of the sequence.
+// It's placed so that the stopband begins at the lower of the two Nyquist frequencies.
+pub(crate) fn cutoff (zero_crossings: usize, source_hz: f64, sample_hz: f64)->f64 {
+  (sample_hz/source_hz).min(1.0)/(1.0 + KAISER_HALF_TRANSITION/zero_crossings as f64)
+}
+
+// how far the kernel reaches to each side, in frames of the sequence
+pub(crate) fn half_width (zero_crossings: usize, cutoff: f64)->f64 {
+  zero_crossings as f64/cutoff
+}
+
+// `offset` is in frames of the sequence. The result isn't normalized.
+pub(crate) fn kernel (offset: f64, cutoff: f64, zero_crossings: usize)->f64 {
+  let x = offset*cutoff;
+  let zero_crossings = zero_crossings as f64;
+  if x.abs() >= zero_crossings { return 0.0; }
+  let window_position = x/zero_crossings;
+  sinc (x)*bessel_i0 (KAISER_BETA*(1.0 - window_position*window_position).sqrt())/bessel_i0 (KAISER_BETA)
+}
+
+fn floor_division (numerator: i64, denominator: i64)->i64 {
+  let quotient = numerator/denominator;
+  if numerator % denominator < 0 { quotient - 1 } else { quotient }
+}
+
+fn greatest_common_divisor (a: i64, b: i64)->i64 {
+  if b == 0 { a } else { greatest_common_divisor (b, a % b) }
+}
+
+// Frame n of the output is at frame n*step/phases of the sequence.
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct PolyphaseKey {
+  step: i64,
+  phases: i64,
+  zero_crossings: usize,
+}
+
+impl PolyphaseKey {
+  fn new (zero_crossings: usize, source_hz: f64, sample_hz: f64)->Option<Self> {
+    if source_hz.fract() != 0.0 || sample_hz.fract() != 0.0 || source_hz <= 0.0 || sample_hz <= 0.0 { return None; }
+    let (source_hz, sample_hz) = (source_hz as i64, sample_hz as i64);
+    let divisor = greatest_common_divisor (source_hz, sample_hz);
+    let phases = sample_hz/divisor;
+    if phases > MAX_POLYPHASE_PHASES { return None; }
+    Some(PolyphaseKey {step: source_hz/divisor, phases, zero_crossings})
+  }
+}
+
+struct PolyphaseTable {
+  // the offset of the first tap, relative to the frame just before the output position
+  first_tap: i64,
+  taps: usize,
+  // taps*phases coefficients, each phase normalized to sum to 1
+  coefficients: Vec<f64>,
+}
+
+impl PolyphaseTable {
+  fn new (key: PolyphaseKey)->Self {
+    let cutoff = cutoff (key.zero_crossings, key.step as f64, key.phases as f64);
+    let reach = half_width (key.zero_crossings, cutoff).ceil() as i64;
+    let first_tap = -reach;
+    let taps = (2*reach + 2) as usize;
+    let mut coefficients = Vec::with_capacity (taps*key.phases as usize);
+    for phase in 0..key.phases {
+      let fraction = phase as f64/key.phases as f64;
+      let weights: Vec<f64> = (0..taps).map (| tap | kernel (fraction - (first_tap + tap as i64) as f64, cutoff, key.zero_crossings)).collect();
+      let total: f64 = weights.iter().sum();
+      coefficients.extend (weights.into_iter().map (| weight | weight/total));
+    }
+    PolyphaseTable {first_tap, taps, coefficients}
+  }
+}
+
+lazy_static! {
+  static ref POLYPHASE_TABLES: Mutex<HashMap<PolyphaseKey, Arc<PolyphaseTable>>> = Mutex::new(HashMap::new());
+}
+
+fn polyphase_table (key: PolyphaseKey)->Arc<PolyphaseTable> {
+  POLYPHASE_TABLES.lock().unwrap().entry (key).or_insert_with (|| Arc::new(PolyphaseTable::new (key))).clone()
+}
+
+impl<Frame: dsp::Frame, Frames: Borrow<[Frame]>> PositionedSequence<Frame, Frames>
+  where <Frame::Sample as Sample>::Float: dsp::FromSample<f64> {
+  pub fn with_resampling (mut self, resampling: Resampling)->Self {
+    self.resampling = resampling;
+    self
+  }
+
+  // `weights` pairs indices into self.frames with their weights; indices outside the sequence count as silence
+  fn weighted_sum <I: Iterator<Item = (i64, f64)>> (&self, weights: I)->Frame {
+    let frames = self.frames.borrow();
+    let mut sum = <Frame::Float as dsp::Frame>::equilibrium();
+    for (index, weight) in weights {
+      if index < 0 || index >= frames.len() as i64 || weight == 0.0 { continue; }
+      let term = dsp::Frame::scale_amp (frames [index as usize].to_float_frame(), Sample::from_sample (weight));
+      sum = dsp::Frame::add_amp (sum, term);
+    }
+    dsp::Frame::map (sum, | sample: <Frame::Sample as Sample>::Float | sample.to_sample::<Frame::Sample>())
+  }
+
+  /// Band-limited interpolation at `time`, for rendering at `sample_hz`.
+  pub fn windowed_sinc_sample (&self, time: f64, sample_hz: f64, zero_crossings: usize)->Frame {
+    let cutoff = cutoff (zero_crossings, self.sample_hz, sample_hz);
+    let reach = half_width (zero_crossings, cutoff);
+    let relative_time = time*self.sample_hz - self.start as f64;
+    let first = (relative_time - reach).floor() as i64 + 1;
+    let last = (relative_time + reach).ceil() as i64 - 1;
+    let weights: Vec<(i64, f64)> = (first..=last).map (| index | (index, kernel (relative_time - index as f64, cutoff, zero_crossings))).collect();
+    let total: f64 = weights.iter().map (| &(_, weight) | weight).sum();
+    self.weighted_sum (weights.into_iter().map (| (index, weight) | (index, weight/total)))
+  }
+
+  fn render_windowed_sinc (&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64, zero_crossings: usize) {
+    for (index, value_mut) in buffer.iter_mut().enumerate() {
+      let time = (start as f64 + index as f64)/sample_hz;
+      *value_mut = value_mut.add_amp(self.windowed_sinc_sample (time, sample_hz, zero_crossings).to_signed_frame());
+    }
+  }
+
+  pub(crate) fn render_resampled (&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    match self.resampling {
+      Resampling::Linear => {
+        for (index, value_mut) in buffer.iter_mut().enumerate() {
+          let time = (start as f64 + index as f64)/sample_hz;
+          *value_mut = value_mut.add_amp(self.interpolate_sample (time).to_signed_frame());
+        }
+      },
+      Resampling::WindowedSinc {zero_crossings} => self.render_windowed_sinc (buffer, start, sample_hz, zero_crossings),
+      Resampling::Polyphase {zero_crossings} => {
+        let key = match PolyphaseKey::new (zero_crossings, self.sample_hz, sample_hz) {
+          Some(a) => a,
+          None => return self.render_windowed_sinc (buffer, start, sample_hz, zero_crossings),
+        };
+        let table = polyphase_table (key);
+        for (index, value_mut) in buffer.iter_mut().enumerate() {
+          // exact integer arithmetic, so the phase never drifts
+          let position = (start + index as FrameTime)*key.step;
+          let previous = floor_division (position, key.phases);
+          let phase = (position - previous*key.phases) as usize;
+          let coefficients = &table.coefficients [phase*table.taps..(phase + 1)*table.taps];
+          let first = previous - self.start + table.first_tap;
+          let frame = self.weighted_sum (coefficients.iter().enumerate().map (| (tap, &weight) | (first + tap as i64, weight)));
+          *value_mut = value_mut.add_amp(frame.to_signed_frame());
+        }
+      },
+    }
+  }
+}
@@ -0,0 +1,297 @@
+//! A terse text notation for sketching MIDI notes, one command per word.
+//!
+//! For example, `transpose 57 velocity 100 instrument 61  0 and 4 and 7  step 0.5 2 4 5  strong 7` plays
+//! an A major chord, then a quick run up to a stressed E.
+//!
+//! The grammar, where words are separated by any whitespace and `#` starts a comment that lasts to the end of the line:
+//!
+//! ```text
+//! scrawl     := command*
+//! command    := integer                 start a note `integer` semitones above the transposition,
+//!                                       after the current chord has lasted one step
+//!             | "and" integer           add a note to the current chord
+//!             | "sustain" integer       start a note that lasts until it's released
+//!             | "release" [integer]     end a sustained note, or all of them if no number follows
+//!             | "finish"                end the current chord, once it has lasted one step
+//!             | "step" number           how long each chord lasts, in beats (initially 1)
+//!             | "advance" number        move forward, leaving a gap (or extending the current chord)
+//!             | "at" number             move to a time; moving backwards ends the current chord first
+//!             | "transpose" integer     the MIDI pitch that note 0 plays (initially 0)
+//!             | "velocity" integer      1 to 127 (initially 64)
+//!             | "instrument" integer    a General MIDI program, 1 to 128 (initially 88)
+//!             | "percussion"            play percussion; each note's pitch picks the drum
+//!             | "strong" | "quiet"      make the next note louder or softer; these stack
+//! ```
+//!
+//! Times are in beats, starting at 0. At the end, the current chord and any sustained notes end.
+
+use super::*;
+
+use std::fmt;
+use std::error::Error;
+
+use phrase::{Phrase, PhraseNote, ToPhraseNote};
+
+
+#[derive (Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum ScrawledInstrument {
+  Pitched (u32),
+  Percussion,
+}
+
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct ScrawledNote {
+  pub start: NoteTime,
+  pub duration: NoteTime,
+  /// The MIDI pitch, or the drum for percussion.
+  pub pitch: i32,
+  pub velocity: i32,
+  pub instrument: ScrawledInstrument,
+}
+
+impl ScrawledNote {
+  pub fn is_percussion (&self)->bool {
+    self.instrument == ScrawledInstrument::Percussion
+  }
+}
+
+impl ToPhraseNote for ScrawledNote {
+  fn to_phrase_note (&self)->PhraseNote {
+    let mut note = PhraseNote::new (self.start, self.start + self.duration, midi_pitch_to_frequency (self.pitch));
+    note.tags.insert (String::from_str (if self.is_percussion() {"percussion"} else {"pitched"}).unwrap());
+    note
+  }
+}
+
+/// Where a scrawl went wrong. Lines and columns count from 1.
+#[derive (Clone, PartialEq, Eq, Debug)]
+pub struct ScrawlError {
+  pub line: usize,
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for ScrawlError {
+  fn fmt (&self, formatter: &mut fmt::Formatter)->fmt::Result {
+    write!(formatter, "line {}, column {}: {}", self.line, self.column, self.message)
+  }
+}
+
+impl Error for ScrawlError {
+  fn description (&self)->&str {
+    &self.message
+  }
+}
+
+#[derive (Clone, Copy, Debug)]
+struct Word<'a> {
+  text: &'a str,
+  line: usize,
+  column: usize,
+}
+
+impl<'a> Word<'a> {
+  fn error (&self, message: String)->ScrawlError {
+    ScrawlError {line: self.line, column: self.column, message}
+  }
+}
+
+fn words <'a> (scrawl: &'a str)->Vec<Word<'a>> {
+  let mut result = Vec::new();
+  for (line_index, line) in scrawl.lines().enumerate() {
+    let line = line.split ('#').next().unwrap();
+    let mut word_start = None;
+    for (column_index, (byte_index, character)) in line.char_indices().chain (iter::once ((line.len(), ' '))).enumerate() {
+      match (word_start, character.is_whitespace()) {
+        (None, false) => word_start = Some((byte_index, column_index)),
+        (Some((start, column)), true) => {
+          result.push (Word {text: &line [start..byte_index], line: line_index + 1, column: column + 1});
+          word_start = None;
+        },
+        _ => (),
+      }
+    }
+  }
+  result
+}
+
+struct Scrawler {
+  notes: Vec<ScrawledNote>,
+  now: NoteTime,
+  step: NoteTime,
+  // the notes of the current chord and the sustained notes, by the number they were written as
+  chord: Vec<(i32, ScrawledNote)>,
+  sustained: Vec<(i32, ScrawledNote)>,
+  transposition: i32,
+  velocity: i32,
+  instrument: ScrawledInstrument,
+  emphasis: i32,
+}
+
+impl Scrawler {
+  fn create_note (&mut self, word: Word, semitones: i32)->Result<ScrawledNote, ScrawlError> {
+    let pitch = match self.transposition.checked_add (semitones) {
+      Some(pitch) if pitch >= 0 && pitch <= 127 => pitch,
+      _ => return Err(word.error (format!("note {} transposed by {} is outside the MIDI pitches 0 to 127", semitones, self.transposition))),
+    };
+    let mut velocity = self.velocity;
+    for _ in 0..self.emphasis {velocity = (velocity*2 + 128)/3;}
+    for _ in self.emphasis..0 {velocity = (velocity*2)/3;}
+    self.emphasis = 0;
+    Ok(ScrawledNote {
+      start: self.now,
+      duration: 0.0,
+      pitch,
+      velocity: max (velocity, 1),
+      instrument: self.instrument,
+    })
+  }
+
+  fn end_note (&mut self, mut note: ScrawledNote) {
+    note.duration = self.now - note.start;
+    self.notes.push (note);
+  }
+
+  fn add_to (&mut self, word: Word, semitones: i32, sustained: bool)->Result<(), ScrawlError> {
+    let note = self.create_note (word, semitones)?;
+    let notes = if sustained {&mut self.sustained} else {&mut self.chord};
+    notes.retain (| &(existing, _) | existing != semitones);
+    notes.push ((semitones, note));
+    Ok(())
+  }
+
+  fn finish_chord (&mut self) {
+    if !self.chord.is_empty() {
+      let last_start = self.chord.iter().map (| &(_, ref note) | note.start).fold (::std::f64::NEG_INFINITY, f64::max);
+      self.now = self.now.max (last_start + self.step);
+    }
+    for (_, note) in ::std::mem::replace (&mut self.chord, Vec::new()) {
+      self.end_note (note);
+    }
+  }
+
+  fn release_all (&mut self) {
+    for (_, note) in ::std::mem::replace (&mut self.sustained, Vec::new()) {
+      self.end_note (note);
+    }
+  }
+}
+
+fn argument <'a, T: FromStr, I: Iterator<Item=Word<'a>>> (words: &mut iter::Peekable<I>, command: Word<'a>, description: &str)->Result<(Word<'a>, T), ScrawlError> {
+  let word = words.next().ok_or_else (|| command.error (format!("expected {} after '{}'", description, command.text)))?;
+  let value = word.text.parse().map_err (| _ | word.error (format!("expected {} after '{}', found '{}'", description, command.text, word.text)))?;
+  Ok((word, value))
+}
+
+/// Reads a scrawl into notes, ordered by start time.
+pub fn parse (scrawl: &str)->Result<Vec<ScrawledNote>, ScrawlError> {
+  let mut scrawler = Scrawler {
+    notes: Vec::new(),
+    now: 0.0,
+    step: 1.0,
+    chord: Vec::new(),
+    sustained: Vec::new(),
+    transposition: 0,
+    velocity: 64,
+    instrument: ScrawledInstrument::Pitched (88),
+    emphasis: 0,
+  };
+  let mut words = words (scrawl).into_iter().peekable();
+  while let Some(word) = words.next() {
+    match word.text {
+      "finish" => scrawler.finish_chord(),
+      "and" => {
+        let (argument_word, semitones) = argument (&mut words, word, "a note")?;
+        scrawler.add_to (argument_word, semitones, false)?;
+      },
+      "sustain" => {
+        let (argument_word, semitones) = argument (&mut words, word, "a note")?;
+        scrawler.add_to (argument_word, semitones, true)?;
+      },
+      "release" => {
+        match words.peek().and_then (| next | i32::from_str (next.text).ok()) {
+          Some(semitones) => {
+            let next = words.next().unwrap();
+            let index = scrawler.sustained.iter().position (| &(existing, _) | existing == semitones)
+              .ok_or_else (|| next.error (format!("note {} isn't sustained", semitones)))?;
+            let (_, note) = scrawler.sustained.remove (index);
+            scrawler.end_note (note);
+          },
+          None => scrawler.release_all(),
+        }
+      },
+      "step" => {
+        let (argument_word, step): (_, f64) = argument (&mut words, word, "a number of beats")?;
+        if !step.is_finite() || step < 0.0 {
+          return Err(argument_word.error (format!("the step must be a nonnegative number of beats, not {}", step)));
+        }
+        scrawler.step = step;
+      },
+      "advance" => {
+        let (argument_word, distance): (_, f64) = argument (&mut words, word, "a number of beats")?;
+        if !distance.is_finite() || distance < 0.0 {
+          return Err(argument_word.error (format!("can only advance by a nonnegative number of beats, not {}", distance)));
+        }
+        scrawler.now += distance;
+      },
+      "at" => {
+        let (argument_word, time): (_, f64) = argument (&mut words, word, "a time in beats")?;
+        if !time.is_finite() {
+          return Err(argument_word.error (format!("can't move to {}", time)));
+        }
+        if time < scrawler.now {
+          scrawler.finish_chord();
+        }
+        scrawler.now = time;
+      },
+      "transpose" => scrawler.transposition = argument (&mut words, word, "a MIDI pitch")?.1,
+      "velocity" => {
+        let (argument_word, velocity) = argument (&mut words, word, "a velocity")?;
+        if velocity < 1 || velocity > 127 {
+          return Err(argument_word.error (format!("velocity must be from 1 to 127, not {}", velocity)));
+        }
+        scrawler.velocity = velocity;
+      },
+      "instrument" => {
+        let (argument_word, program) = argument (&mut words, word, "an instrument number")?;
+        if program < 1 || program > 128 {
+          return Err(argument_word.error (format!("instrument must be from 1 to 128, not {}", program)));
+        }
+        scrawler.instrument = ScrawledInstrument::Pitched (program);
+      },
+      "percussion" => scrawler.instrument = ScrawledInstrument::Percussion,
+      "strong" => scrawler.emphasis += 1,
+      "quiet" => scrawler.emphasis -= 1,
+      other => match i32::from_str (other) {
+        Ok(semitones) => {
+          scrawler.finish_chord();
+          scrawler.add_to (word, semitones, false)?;
+        },
+        Err(_) => return Err(word.error (format!("unknown command '{}'", other))),
+      },
+    }
+  }
+  scrawler.finish_chord();
+  scrawler.release_all();
+  let mut notes = scrawler.notes;
+  notes.sort_by (| a, b | a.start.partial_cmp (&b.start).unwrap());
+  Ok(notes)
+}
+
+/// The pitched notes, as MIDI notes. Times stay in beats; use a TempoMap to convert them to seconds.
+pub fn pitched_notes (notes: &[ScrawledNote])->Vec<MIDIPitchedNote> {
+  notes.iter().filter_map (| note | match note.instrument {
+    ScrawledInstrument::Pitched (program) => Some(MIDIPitchedNote::new (note.start, note.duration, note.pitch, note.velocity, program)),
+    ScrawledInstrument::Percussion => None,
+  }).collect()
+}
+
+/// The percussion notes, as MIDI notes. Times stay in beats; use a TempoMap to convert them to seconds.
+pub fn percussion_notes (notes: &[ScrawledNote])->Vec<MIDIPercussionNote> {
+  notes.iter().filter (| note | note.is_percussion()).map (| note | MIDIPercussionNote::new (note.start, note.duration, note.velocity, note.pitch)).collect()
+}
+
+/// Parses a scrawl into a Phrase, with each note tagged "pitched" or "percussion".
+pub fn scrawl_phrase (scrawl: &str)->Result<Phrase, ScrawlError> {
+  Ok(parse (scrawl)?.iter().collect())
+}
@@ -0,0 +1,547 @@
+use super::*;
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use resampling::{kernel, cutoff, half_width};
+use effects::{pan_gains, decibels_to_amplitude};
+use envelope::{Curve, EnvelopeSegment};
+
+
+#[derive (Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LoopMode {
+  NoLoop,
+  /// Plays the whole sample no matter how long the note is held.
+  OneShot,
+  Continuous,
+  /// Loops until the note is released, then plays on through the rest of the sample.
+  Sustain,
+}
+
+/// One sample, and the notes it plays for. Sample positions are in frames of the sample file.
+#[derive (Clone, Debug)]
+pub struct SfzRegion {
+  pub sample: PathBuf,
+  pub lokey: i32,
+  pub hikey: i32,
+  pub lovel: i32,
+  pub hivel: i32,
+  pub pitch_keycenter: i32,
+  /// cents per key
+  pub pitch_keytrack: f64,
+  pub transpose: i32,
+  /// cents
+  pub tune: f64,
+  /// decibels
+  pub volume: f64,
+  /// -100 to 100
+  pub pan: f64,
+  /// percent
+  pub amp_veltrack: f64,
+  pub offset: usize,
+  pub end: Option<usize>,
+  pub loop_mode: LoopMode,
+  /// inclusive, like in SFZ files
+  pub loop_start: usize,
+  pub loop_end: usize,
+  pub seq_length: usize,
+  pub seq_position: usize,
+  pub envelope: Envelope,
+  sample_index: usize,
+}
+
+struct SfzSample {
+  sample_hz: f64,
+  frames: Vec<[f32;2]>,
+}
+
+/// A multi-sampled instrument from an SFZ file, with all of its samples loaded.
+///
+/// Supports the common opcodes for key and velocity ranges, pitch, volume, pan, loops, the amplitude envelope (ampeg_*)
+/// and round robin (seq_length/seq_position). Other opcodes are ignored.
+pub struct SfzInstrument {
+  pub path: PathBuf,
+  pub regions: Vec<SfzRegion>,
+  /// How samples are interpolated when they're played at a different pitch or sample rate.
+  /// The default is WindowedSinc with 8 zero crossings; Polyphase is treated the same as WindowedSinc.
+  pub resampling: Resampling,
+  samples: Vec<SfzSample>,
+}
+
+impl ::std::fmt::Debug for SfzInstrument {
+  fn fmt (&self, formatter: &mut ::std::fmt::Formatter)->::std::fmt::Result {
+    write!(formatter, "SfzInstrument {{ path: {:?}, regions: {}, samples: {} }}", self.path, self.regions.len(), self.samples.len())
+  }
+}
+
+fn invalid_data (path: &Path, message: &str)->io::Error {
+  io::Error::new (io::ErrorKind::InvalidData, format!("{:?}: {}", path, message))
+}
+
+/// Reads a key number or a note name like "c#4" (where c4 is 60).
+fn parse_key (value: &str)->Option<i32> {
+  if let Ok(key) = value.parse() { return Some(key); }
+  let lowercase = value.to_lowercase();
+  let mut characters = lowercase.chars();
+  let mut key = match characters.next()? {
+    'c' => 0, 'd' => 2, 'e' => 4, 'f' => 5, 'g' => 7, 'a' => 9, 'b' => 11,
+    _ => return None,
+  };
+  let mut rest = characters.as_str();
+  if rest.starts_with ('#') { key += 1; rest = &rest [1..]; }
+  else if rest.starts_with ('b') { key -= 1; rest = &rest [1..]; }
+  let octave: i32 = rest.parse().ok()?;
+  Some(key + (octave + 1)*12)
+}
+
+// Reads the file, following #include and substituting #define, and removes comments.
+fn preprocess (path: &Path, defines: &mut Vec<(String, String)>, depth: usize)->io::Result<String> {
+  if depth > 16 {
+    return Err(invalid_data (path, "#include nested too deeply"));
+  }
+  let mut text = String::new();
+  File::open (path)?.read_to_string (&mut text)?;
+  let directory = path.parent().unwrap_or (Path::new (""));
+  let mut result = String::new();
+  for line in text.lines() {
+    let mut line = line.split ("//").next().unwrap().to_string();
+    for &(ref name, ref value) in defines.iter() {
+      line = line.replace (&name [..], value);
+    }
+    let trimmed = line.trim();
+    if trimmed.starts_with ("#define") {
+      let mut parts = trimmed ["#define".len()..].split_whitespace();
+      if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+        defines.push ((name.to_string(), value.to_string()));
+      }
+    }
+    else if trimmed.starts_with ("#include") {
+      let included = trimmed ["#include".len()..].trim().trim_matches ('"');
+      result.push_str (&preprocess (&directory.join (included), defines, depth + 1)?);
+    }
+    else {
+      result.push_str (&line);
+    }
+    result.push ('\n');
+  }
+  Ok(result)
+}
+
+enum Token {
+  Header (String),
+  Opcode (String, String),
+}
+
+fn tokenize (text: &str)->Vec<Token> {
+  let mut tokens = Vec::new();
+  for line in text.lines() {
+    let spaced = line.replace ('<', " <").replace ('>', "> ");
+    for word in spaced.split_whitespace() {
+      if word.starts_with ('<') && word.ends_with ('>') {
+        tokens.push (Token::Header (word [1..word.len() - 1].to_string()));
+      }
+      else if let Some(index) = word.find ('=') {
+        tokens.push (Token::Opcode (word [..index].to_string(), word [index + 1..].to_string()));
+      }
+      // values can contain spaces, mostly in sample paths
+      else if let Some(&mut Token::Opcode (_, ref mut value)) = tokens.last_mut() {
+        value.push (' ');
+        value.push_str (word);
+      }
+    }
+  }
+  tokens
+}
+
+// The opcodes that apply to a region, from outermost (<global>) to innermost (<region>), so later ones take precedence.
+fn region_from_opcodes (opcodes: &[(String, String)], directory: &Path, path: &Path)->io::Result<SfzRegion> {
+  let mut region = SfzRegion {
+    sample: PathBuf::new(),
+    lokey: 0, hikey: 127, lovel: 1, hivel: 127,
+    pitch_keycenter: 60, pitch_keytrack: 100.0,
+    transpose: 0, tune: 0.0, volume: 0.0, pan: 0.0, amp_veltrack: 100.0,
+    offset: 0, end: None,
+    loop_mode: LoopMode::NoLoop, loop_start: 0, loop_end: 0,
+    seq_length: 1, seq_position: 1,
+    envelope: Envelope::default(),
+    sample_index: 0,
+  };
+  let (mut loop_mode, mut loop_start, mut loop_end) = (None, None, None);
+  // delay, attack, hold, decay, sustain (percent), release
+  let mut ampeg = [0.0, 0.0, 0.0, 0.0, 100.0, 0.001];
+  let mut default_path = String::new();
+  for &(ref opcode, ref value) in opcodes {
+    let number = || value.parse::<f64>().map_err (| _ | invalid_data (path, &format!("bad value for {}: {:?}", opcode, value)));
+    let key = || parse_key (value).ok_or_else (|| invalid_data (path, &format!("bad key for {}: {:?}", opcode, value)));
+    match &opcode [..] {
+      "default_path" => default_path = value.replace ('\\', "/"),
+      "sample" => region.sample = directory.join (format!("{}{}", default_path, value.replace ('\\', "/"))),
+      "lokey" => region.lokey = key()?,
+      "hikey" => region.hikey = key()?,
+      "key" => {
+        let key = key()?;
+        region.lokey = key;
+        region.hikey = key;
+        region.pitch_keycenter = key;
+      },
+      "pitch_keycenter" => region.pitch_keycenter = key()?,
+      "lovel" => region.lovel = number()? as i32,
+      "hivel" => region.hivel = number()? as i32,
+      "pitch_keytrack" => region.pitch_keytrack = number()?,
+      "transpose" => region.transpose = number()? as i32,
+      "tune" => region.tune = number()?,
+      "volume" => region.volume = number()?,
+      "pan" => region.pan = number()?,
+      "amp_veltrack" => region.amp_veltrack = number()?,
+      "offset" => region.offset = number()? as usize,
+      "end" => region.end = Some(number()? as usize),
+      "loop_mode" | "loopmode" => loop_mode = Some(match &value [..] {
+        "no_loop" => LoopMode::NoLoop,
+        "one_shot" => LoopMode::OneShot,
+        "loop_continuous" => LoopMode::Continuous,
+        "loop_sustain" => LoopMode::Sustain,
+        _ => return Err(invalid_data (path, &format!("unknown loop_mode {:?}", value))),
+      }),
+      "loop_start" | "loopstart" => loop_start = Some(number()? as usize),
+      "loop_end" | "loopend" => loop_end = Some(number()? as usize),
+      "seq_length" => region.seq_length = max(1, number()? as usize),
+      "seq_position" => region.seq_position = max(1, number()? as usize),
+      "ampeg_delay" => ampeg [0] = number()?,
+      "ampeg_attack" => ampeg [1] = number()?,
+      "ampeg_hold" => ampeg [2] = number()?,
+      "ampeg_decay" => ampeg [3] = number()?,
+      "ampeg_sustain" => ampeg [4] = number()?,
+      "ampeg_release" => ampeg [5] = number()?,
+      _ => (),
+    }
+  }
+  if region.sample == PathBuf::new() {
+    return Err(invalid_data (path, "region without a sample"));
+  }
+
+  // samples can have their own loops, which SFZ uses unless the file says otherwise
+  let embedded_loop = wav_loop (&region.sample);
+  region.loop_mode = loop_mode.unwrap_or (if embedded_loop.is_some() || loop_end.is_some() {LoopMode::Continuous} else {LoopMode::NoLoop});
+  region.loop_start = loop_start.or (embedded_loop.map (| (start, _) | start)).unwrap_or (0);
+  region.loop_end = loop_end.or (embedded_loop.map (| (_, end) | end)).unwrap_or (0);
+  if region.loop_end <= region.loop_start && (region.loop_mode == LoopMode::Continuous || region.loop_mode == LoopMode::Sustain) {
+    region.loop_mode = LoopMode::NoLoop;
+  }
+
+  let curve = Curve::Exponential {curvature: -5.0};
+  region.envelope = Envelope {
+    start_level: 0.0,
+    segments: vec![
+      EnvelopeSegment::linear (ampeg [0], 0.0),
+      EnvelopeSegment::linear (ampeg [1], 1.0),
+      EnvelopeSegment::linear (ampeg [2], 1.0),
+      EnvelopeSegment::new (ampeg [3], ampeg [4]/100.0, curve),
+      EnvelopeSegment::new (ampeg [5], 0.0, curve),
+    ],
+    sustain_segment: Some(3),
+    fit_within_held: None,
+  };
+  Ok(region)
+}
+
+// the first loop in a WAV file's smpl chunk, if it has one
+fn wav_loop (path: &Path)->Option<(usize, usize)> {
+  let mut file = File::open (path).ok()?;
+  let mut header = [0u8; 12];
+  file.read_exact (&mut header).ok()?;
+  if &header [0..4] != b"RIFF" || &header [8..12] != b"WAVE" { return None; }
+  let little_endian = | bytes: &[u8] | bytes.iter().rev().fold (0usize, | value, &byte | (value << 8) | byte as usize);
+  loop {
+    let mut chunk_header = [0u8; 8];
+    file.read_exact (&mut chunk_header).ok()?;
+    let size = little_endian (&chunk_header [4..8]);
+    if &chunk_header [0..4] == b"smpl" {
+      let mut chunk = vec![0u8; size];
+      file.read_exact (&mut chunk).ok()?;
+      if chunk.len() < 36 + 24 || little_endian (&chunk [28..32]) == 0 { return None; }
+      return Some((little_endian (&chunk [44..48]), little_endian (&chunk [48..52])));
+    }
+    file.seek (SeekFrom::Current ((size + size % 2) as i64)).ok()?;
+  }
+}
+
+impl SfzInstrument {
+  /// Reads the SFZ file and every sample it uses. Paths in the file are relative to the file.
+  pub fn load <P: AsRef<Path>> (path: P)->io::Result<Arc<SfzInstrument>> {
+    let path = path.as_ref();
+    let directory = path.parent().unwrap_or (Path::new (""));
+    let text = preprocess (path, &mut Vec::new(), 0)?;
+
+    // opcodes for <control>, <global>, <master>, <group> and <region>
+    let mut scopes: [Vec<(String, String)>; 5] = Default::default();
+    let mut current = None;
+    let mut regions = Vec::new();
+    let finish_region = | scopes: &[Vec<(String, String)>; 5], current: Option<usize> | -> io::Result<Option<SfzRegion>> {
+      if current != Some(4) { return Ok(None); }
+      let opcodes: Vec<(String, String)> = scopes.iter().flat_map (| scope | scope.iter().cloned()).collect();
+      region_from_opcodes (&opcodes, directory, path).map (Some)
+    };
+    for token in tokenize (&text) {
+      match token {
+        Token::Header (name) => {
+          regions.extend (finish_region (&scopes, current)?);
+          let level = match &name [..] {
+            "control" => 0, "global" => 1, "master" => 2, "group" => 3, "region" => 4,
+            _ => { current = None; continue; },
+          };
+          // starting a scope replaces it and everything inside it
+          for scope in scopes [level..].iter_mut() { scope.clear(); }
+          current = Some(level);
+        },
+        Token::Opcode (opcode, value) => if let Some(level) = current {
+          scopes [level].push ((opcode, value));
+        },
+      }
+    }
+    regions.extend (finish_region (&scopes, current)?);
+
+    let mut samples = Vec::new();
+    let mut sample_paths: Vec<PathBuf> = Vec::new();
+    for region in regions.iter_mut() {
+      region.sample_index = match sample_paths.iter().position (| existing | existing == &region.sample) {
+        Some(index) => index,
+        None => {
+          let sequence: PositionedSequence<[f32;2], Vec<[f32;2]>> = audio_file::load_audio (&region.sample, 0.0)
+            .map_err (| error | io::Error::new (error.kind(), format!("{:?}, used by {:?}: {}", region.sample, path, error)))?;
+          samples.push (SfzSample {sample_hz: sequence.sample_hz, frames: sequence.frames});
+          sample_paths.push (region.sample.clone());
+          samples.len() - 1
+        },
+      };
+    }
+
+    Ok(Arc::new (SfzInstrument {
+      path: fs::canonicalize (path).unwrap_or (path.to_path_buf()),
+      regions,
+      resampling: Resampling::WindowedSinc {zero_crossings: 8},
+      samples,
+    }))
+  }
+}
+
+/// A note played by an SfzInstrument, at any frequency.
+#[derive (Clone, Debug)]
+pub struct SfzNote {
+  pub start: NoteTime,
+  pub duration: NoteTime,
+  pub frequency: f64,
+  pub velocity: i32,
+  /// Picks between regions with the same seq_length but different seq_positions. See assign_round_robin().
+  pub round_robin: usize,
+  pub instrument: Arc<SfzInstrument>,
+}
+
+// where in the sample a region is playing, at `time` seconds after the note starts
+struct Playback<'a> {
+  region: &'a SfzRegion,
+  sample: &'a SfzSample,
+  // sample frames per second
+  speed: f64,
+  gains: (f64, f64),
+  sample_end: usize,
+}
+
+impl<'a> Playback<'a> {
+  fn looping (&self, time: NoteTime, held: NoteTime)->bool {
+    match self.region.loop_mode {
+      LoopMode::Continuous => true,
+      LoopMode::Sustain => time < held,
+      _ => false,
+    }
+  }
+
+  fn wrap (&self, position: f64)->f64 {
+    let loop_start = self.region.loop_start as f64;
+    let loop_length = (self.region.loop_end + 1 - self.region.loop_start) as f64;
+    if position < loop_start + loop_length { position }
+    else { loop_start + (position - loop_start) % loop_length }
+  }
+
+  fn position (&self, time: NoteTime, held: NoteTime)->f64 {
+    let linear = | time: NoteTime | self.region.offset as f64 + time*self.speed;
+    match self.region.loop_mode {
+      LoopMode::Continuous => self.wrap (linear (time)),
+      LoopMode::Sustain if time < held => self.wrap (linear (time)),
+      LoopMode::Sustain => self.wrap (linear (held)) + (time - held)*self.speed,
+      _ => linear (time),
+    }
+  }
+
+  // how long the note has to be held for the envelope to be released
+  fn held (&self, duration: NoteTime)->NoteTime {
+    if self.region.loop_mode == LoopMode::OneShot { ::std::f64::INFINITY } else { duration }
+  }
+
+  fn end (&self, duration: NoteTime)->NoteTime {
+    let held = self.held (duration);
+    let through_sample = | from: f64 | (self.sample_end as f64 - from)/self.speed;
+    match self.region.loop_mode {
+      LoopMode::Continuous => self.region.envelope.duration (held),
+      LoopMode::Sustain => self.region.envelope.duration (held).min (held + through_sample (self.position (held, held))),
+      LoopMode::OneShot => through_sample (self.region.offset as f64),
+      LoopMode::NoLoop => self.region.envelope.duration (held).min (through_sample (self.region.offset as f64)),
+    }
+  }
+
+  fn frame (&self, index: i64, looping: bool)->[f32;2] {
+    let mut index = index;
+    if looping && index > self.region.loop_end as i64 {
+      let loop_length = (self.region.loop_end + 1 - self.region.loop_start) as i64;
+      index = self.region.loop_start as i64 + (index - self.region.loop_start as i64) % loop_length;
+    }
+    if index < 0 || index >= self.sample_end as i64 { [0.0, 0.0] } else { self.sample.frames [index as usize] }
+  }
+
+  fn value (&self, time: NoteTime, held: NoteTime, sample_hz: f64, resampling: Resampling)->(f64, f64) {
+    let position = self.position (time, held);
+    let looping = self.looping (time, held);
+    let (mut left, mut right) = (0.0, 0.0);
+    match resampling {
+      Resampling::Linear => {
+        let previous = position.floor();
+        let factor = position - previous;
+        for &(index, weight) in [(previous as i64, 1.0 - factor), (previous as i64 + 1, factor)].iter() {
+          let frame = self.frame (index, looping);
+          left += frame [0] as f64*weight;
+          right += frame [1] as f64*weight;
+        }
+      },
+      Resampling::WindowedSinc {zero_crossings} | Resampling::Polyphase {zero_crossings} => {
+        let cutoff = cutoff (zero_crossings, self.speed, sample_hz);
+        let reach = half_width (zero_crossings, cutoff);
+        let mut total = 0.0;
+        for index in (position - reach).floor() as i64 + 1..(position + reach).ceil() as i64 {
+          let weight = kernel (position - index as f64, cutoff, zero_crossings);
+          let frame = self.frame (index, looping);
+          left += frame [0] as f64*weight;
+          right += frame [1] as f64*weight;
+          total += weight;
+        }
+        left /= total;
+        right /= total;
+      },
+    }
+    let envelope = self.region.envelope.level (time, self.held (held));
+    (left*envelope*self.gains.0, right*envelope*self.gains.1)
+  }
+}
+
+impl SfzNote {
+  pub fn new (start: NoteTime, duration: NoteTime, frequency: f64, velocity: i32, instrument: &Arc<SfzInstrument>)->SfzNote {
+    SfzNote {start, duration, frequency, velocity, round_robin: 0, instrument: instrument.clone()}
+  }
+  pub fn with_round_robin (mut self, round_robin: usize)->SfzNote {
+    self.round_robin = round_robin;
+    self
+  }
+
+  fn playbacks<'a> (&'a self)->Vec<Playback<'a>> {
+    let exact_key = 69.0 + 12.0*(self.frequency/440.0).log2();
+    let key = exact_key.round() as i32;
+    self.instrument.regions.iter().filter (| region | {
+      key >= region.lokey && key <= region.hikey &&
+      self.velocity >= region.lovel && self.velocity <= region.hivel &&
+      self.round_robin % region.seq_length + 1 == region.seq_position
+    }).map (| region | {
+      let sample = &self.instrument.samples [region.sample_index];
+      let semitones = (exact_key - region.pitch_keycenter as f64)*region.pitch_keytrack/100.0 + region.transpose as f64 + region.tune/100.0;
+      let velocity = self.velocity.max (0).min (127) as f64/127.0;
+      let velocity_gain = 1.0 - region.amp_veltrack/100.0*(1.0 - velocity*velocity);
+      let gain = decibels_to_amplitude (region.volume)*velocity_gain;
+      let (left, right) = pan_gains (region.pan/100.0);
+      Playback {
+        region, sample,
+        speed: sample.sample_hz*2f64.powf (semitones/12.0),
+        gains: (left*gain, right*gain),
+        sample_end: min(sample.frames.len(), region.end.map_or (usize::max_value(), | end | end + 1)),
+      }
+    }).collect()
+  }
+}
+
+/// Numbers each instrument's notes on each key in order of when they start, so repeated notes cycle through the round robin.
+pub fn assign_round_robin (notes: &mut [SfzNote]) {
+  let mut order: Vec<usize> = (0..notes.len()).collect();
+  // OrderedFloat puts NaN starts last instead of panicking
+  order.sort_by_key (| &index | OrderedFloat (notes [index].start));
+  let mut counters: HashMap<(usize, i32), usize> = HashMap::new();
+  for index in order {
+    let note = &mut notes [index];
+    let counter = counters.entry ((&*note.instrument as *const SfzInstrument as usize, frequency_to_nearest_midi_pitch (note.frequency))).or_insert (0);
+    note.round_robin = *counter;
+    *counter += 1;
+  }
+}
+
+impl Windowed for SfzNote {
+  fn start (&self)->NoteTime {self.start}
+  fn end (&self)->NoteTime {
+    self.start + self.playbacks().iter().map (| playback | playback.end (self.duration)).fold (0.0, NoteTime::max)
+  }
+}
+impl<Frame: dsp::Frame> Renderable<Frame> for SfzNote
+    where Frame::Sample: dsp::FromSample<f64> {
+  fn render(&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    let stereo = Frame::n_channels() == 2;
+    for playback in self.playbacks() {
+      let end = playback.end (self.duration);
+      for (index, value_mut) in buffer.iter_mut().enumerate() {
+        let time = (start + index as FrameTime) as f64/sample_hz - self.start;
+        if time < 0.0 || time > end { continue; }
+        let (left, right) = playback.value (time, self.duration, sample_hz, self.instrument.resampling);
+        let frame = if stereo {
+          Frame::from_fn(|channel| Frame::Sample::from_sample(if channel == 0 {left} else {right}))
+        }
+        else {
+          let value = Frame::Sample::from_sample((left + right)*0.5);
+          Frame::from_fn(|_| value)
+        };
+        *value_mut = value_mut.add_amp(frame.to_signed_frame());
+      }
+    }
+  }
+}
+
+impl Nudgable for SfzNote {
+  fn nudge(&mut self, distance: NoteTime) {
+    self.start += distance;
+  }
+}
+
+impl Dilatable for SfzNote {
+  fn dilate(&mut self, amount: f64, origin: f64) {
+    self.start = origin + (self.start-origin)*amount;
+    self.duration *= amount;
+  }
+}
+
+impl Retimable for SfzNote {
+  fn retime(&mut self, map: &Fn(NoteTime)->NoteTime) {
+    let end = map(self.start + self.duration);
+    self.start = map(self.start);
+    self.duration = end - self.start;
+  }
+}
+
+impl Placeable for SfzNote {
+  fn place(&mut self, start: NoteTime, duration: NoteTime) {
+    self.start = start;
+    self.duration = duration;
+  }
+}
+
+impl Pitched for SfzNote {
+  fn frequency(&self)->f64 {self.frequency}
+}
+
+impl PitchShiftable for SfzNote {
+  fn pitch_shift(&mut self, frequency_ratio: f64) {
+    self.frequency *= frequency_ratio;
+  }
+}
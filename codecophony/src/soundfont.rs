@@ -0,0 +1,99 @@
+use std::io::{self, Read};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+
+pub const DEFAULT_SOUNDFONT_PATH: &str = "/usr/share/sounds/sf2/FluidR3_GM.sf2";
+
+/// A reference to a soundfont file in the global registry.
+///
+/// The synthesizers are thread-local, so each one loads the file the first time it renders a note that uses it.
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SoundfontId(usize);
+
+#[derive (Default)]
+struct SoundfontRegistry {
+  paths: Vec<PathBuf>,
+  default: Option<SoundfontId>,
+}
+
+impl SoundfontRegistry {
+  fn register (&mut self, path: PathBuf)->SoundfontId {
+    if let Some(index) = self.paths.iter().position (| existing | existing == &path) {
+      return SoundfontId(index);
+    }
+    self.paths.push (path);
+    SoundfontId(self.paths.len() - 1)
+  }
+}
+
+lazy_static! {
+  static ref SOUNDFONTS: Mutex<SoundfontRegistry> = Mutex::new(SoundfontRegistry::default());
+}
+
+fn invalid_data (path: &Path, message: &str)->io::Error {
+  io::Error::new (io::ErrorKind::InvalidData, format!("{:?}: {}", path, message))
+}
+
+fn validate (path: &Path)->io::Result<()> {
+  if path.to_str().is_none() {
+    return Err(invalid_data (path, "fluidsynth needs soundfont paths to be valid UTF-8"));
+  }
+  let mut header = [0u8; 12];
+  File::open (path)?.read_exact (&mut header)?;
+  // both .sf2 and .sf3 files are RIFF files with the form type "sfbk"
+  if &header [0..4] != b"RIFF" || &header [8..12] != b"sfbk" {
+    return Err(invalid_data (path, "not a soundfont file"));
+  }
+  Ok(())
+}
+
+/// Registers a .sf2 or .sf3 file, after checking that it exists and looks like a soundfont.
+///
+/// Registering the same file twice returns the same id, even through a different path.
+pub fn load_soundfont <P: AsRef<Path>> (path: P)->io::Result<SoundfontId> {
+  let path = path.as_ref().canonicalize()?;
+  validate (&path)?;
+  Ok(SOUNDFONTS.lock().unwrap().register (path))
+}
+
+/// The soundfont used by FluidsynthDirectlyRenderableMIDIInstrument::pitched() and percussion().
+///
+/// Unless set_default_soundfont() has been called, this is DEFAULT_SOUNDFONT_PATH,
+/// which isn't checked until a note using it gets rendered; use check_soundfont() to check it sooner.
+pub fn default_soundfont()->SoundfontId {
+  let mut guard = SOUNDFONTS.lock().unwrap();
+  if let Some(id) = guard.default {
+    return id;
+  }
+  // canonicalized like load_soundfont() does, so that loading the same file explicitly gives the same id
+  let path = PathBuf::from (DEFAULT_SOUNDFONT_PATH);
+  let id = guard.register (path.canonicalize().unwrap_or (path));
+  guard.default = Some(id);
+  id
+}
+
+/// Changes the soundfont used by instruments that are created afterwards. Existing instruments keep their soundfont.
+pub fn set_default_soundfont (id: SoundfontId) {
+  SOUNDFONTS.lock().unwrap().default = Some(id);
+}
+
+pub fn soundfont_path (id: SoundfontId)->PathBuf {
+  SOUNDFONTS.lock().unwrap().paths [id.0].clone()
+}
+
+/// Checks that the soundfont's file exists and looks like a soundfont, in the same way as load_soundfont().
+pub fn check_soundfont (id: SoundfontId)->io::Result<()> {
+  validate (&soundfont_path (id))
+}
+
+/// Describes the file well enough to notice if it changes, so that rendered notes can be cached between runs.
+/// Returns None if the file can't be read.
+pub fn soundfont_identity (id: SoundfontId)->Option<String> {
+  let path = soundfont_path (id);
+  let metadata = fs::metadata (&path).ok()?;
+  let modified = metadata.modified().ok()?.duration_since (UNIX_EPOCH).ok()?;
+  Some(format!("soundfont {:?} length {} modified {}.{:09}", path, metadata.len(), modified.as_secs(), modified.subsec_nanos()))
+}
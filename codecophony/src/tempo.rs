@@ -0,0 +1,247 @@
+use super::*;
+
+use std::cmp::Ordering;
+
+
+/// Musical time, in quarter notes, like MIDI's beats per minute.
+pub type Beats = f64;
+
+/// How the tempo gets from the previous change to a new one.
+#[derive (Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum TempoCurve {
+  /// Jumps to the new tempo at the change.
+  Step,
+  /// Changes steadily, beat by beat, from the previous tempo to arrive at the new one at the change.
+  Linear,
+}
+
+#[derive (Clone, Copy, PartialEq, Serialize, Deserialize, Debug)]
+pub struct TempoChange {
+  pub beat: Beats,
+  pub beats_per_minute: f64,
+  pub curve: TempoCurve,
+}
+
+#[derive (Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct TimeSignature {
+  pub numerator: u32,
+  pub denominator: u32,
+}
+
+impl TimeSignature {
+  pub fn new (numerator: u32, denominator: u32)->TimeSignature {
+    TimeSignature {numerator, denominator}
+  }
+  pub fn beats_per_bar (&self)->Beats {
+    self.numerator as f64*4.0/self.denominator as f64
+  }
+}
+
+#[derive (Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct MeterChange {
+  pub bar: i64,
+  pub time_signature: TimeSignature,
+}
+
+/// Converts between musical time (beats and bars) and seconds.
+///
+/// There is always a tempo change at beat 0 and a meter change at bar 0, and bar 0 starts at beat 0.
+/// Times before those use the first tempo and time signature.
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+#[serde (from = "TempoMapChanges")]
+pub struct TempoMap {
+  tempo_changes: Vec<TempoChange>,
+  meter_changes: Vec<MeterChange>,
+  // worked out from tempo_changes whenever they change
+  #[serde (skip)]
+  segments: Vec<Segment>,
+}
+
+// what gets serialized; the segments are rebuilt after deserializing
+#[derive (Deserialize)]
+struct TempoMapChanges {
+  tempo_changes: Vec<TempoChange>,
+  meter_changes: Vec<MeterChange>,
+}
+
+impl From<TempoMapChanges> for TempoMap {
+  fn from (changes: TempoMapChanges)->TempoMap {
+    let mut result = TempoMap {tempo_changes: changes.tempo_changes, meter_changes: changes.meter_changes, segments: Vec::new()};
+    result.rebuild_segments();
+    result
+  }
+}
+
+impl Default for TempoMap {
+  fn default()->Self {
+    TempoMap::new (120.0)
+  }
+}
+
+// a stretch between tempo changes, where the tempo is constant or changes linearly
+#[derive (Clone, Copy, PartialEq, Debug)]
+struct Segment {
+  beat: Beats,
+  seconds: NoteTime,
+  beats_per_minute: f64,
+  // change in beats per minute per beat
+  slope: f64,
+}
+
+impl Segment {
+  fn seconds_after (&self, beats: Beats)->NoteTime {
+    if self.slope.abs() < 1e-9 {
+      beats*60.0/self.beats_per_minute
+    }
+    else {
+      (60.0/self.slope)*((self.beats_per_minute + self.slope*beats)/self.beats_per_minute).ln()
+    }
+  }
+  fn beats_after (&self, seconds: NoteTime)->Beats {
+    if self.slope.abs() < 1e-9 {
+      seconds*self.beats_per_minute/60.0
+    }
+    else {
+      self.beats_per_minute*((seconds*self.slope/60.0).exp() - 1.0)/self.slope
+    }
+  }
+}
+
+impl TempoMap {
+  /// A constant tempo in 4/4.
+  pub fn new (beats_per_minute: f64)->TempoMap {
+    let mut result = TempoMap {
+      tempo_changes: vec![TempoChange {beat: 0.0, beats_per_minute, curve: TempoCurve::Step}],
+      meter_changes: vec![MeterChange {bar: 0, time_signature: TimeSignature::new (4, 4)}],
+      segments: Vec::new(),
+    };
+    result.rebuild_segments();
+    result
+  }
+
+  pub fn tempo_changes (&self)->&[TempoChange] {&self.tempo_changes}
+  pub fn meter_changes (&self)->&[MeterChange] {&self.meter_changes}
+
+  /// Adds a tempo change, replacing any other change at the same beat. Changes before beat 0 are ignored.
+  pub fn add_tempo_change (&mut self, change: TempoChange) {
+    if change.beat < 0.0 { return; }
+    self.tempo_changes.retain (| existing | existing.beat != change.beat);
+    let index = self.tempo_changes.iter().position (| existing | existing.beat > change.beat).unwrap_or (self.tempo_changes.len());
+    self.tempo_changes.insert (index, change);
+    self.rebuild_segments();
+  }
+  pub fn with_tempo (mut self, beat: Beats, beats_per_minute: f64)->TempoMap {
+    self.add_tempo_change (TempoChange {beat, beats_per_minute, curve: TempoCurve::Step});
+    self
+  }
+  /// Changes gradually from the previous tempo change, reaching `beats_per_minute` at `beat`.
+  pub fn with_ramp (mut self, beat: Beats, beats_per_minute: f64)->TempoMap {
+    self.add_tempo_change (TempoChange {beat, beats_per_minute, curve: TempoCurve::Linear});
+    self
+  }
+
+  /// Changes the time signature from `bar` on, replacing any other change at the same bar. Changes before bar 0 are ignored.
+  pub fn add_meter_change (&mut self, change: MeterChange) {
+    if change.bar < 0 { return; }
+    self.meter_changes.retain (| existing | existing.bar != change.bar);
+    let index = self.meter_changes.iter().position (| existing | existing.bar > change.bar).unwrap_or (self.meter_changes.len());
+    self.meter_changes.insert (index, change);
+  }
+  pub fn with_time_signature (mut self, bar: i64, numerator: u32, denominator: u32)->TempoMap {
+    self.add_meter_change (MeterChange {bar, time_signature: TimeSignature::new (numerator, denominator)});
+    self
+  }
+
+  fn rebuild_segments (&mut self) {
+    let mut segments: Vec<Segment> = Vec::with_capacity (self.tempo_changes.len());
+    for (index, change) in self.tempo_changes.iter().enumerate() {
+      let seconds = match segments.last() {
+        None => 0.0,
+        Some(previous) => previous.seconds + previous.seconds_after (change.beat - previous.beat),
+      };
+      let slope = match self.tempo_changes.get (index + 1) {
+        Some(next) if next.curve == TempoCurve::Linear => (next.beats_per_minute - change.beats_per_minute)/(next.beat - change.beat),
+        _ => 0.0,
+      };
+      segments.push (Segment {beat: change.beat, seconds, beats_per_minute: change.beats_per_minute, slope});
+    }
+    self.segments = segments;
+  }
+
+  // the last segment where `position` is at most `value`, or the first segment if there isn't one
+  fn segment_at <F: Fn(&Segment)->f64> (&self, position: F, value: f64)->&Segment {
+    let after = match self.segments.binary_search_by (| segment | if position (segment) <= value {Ordering::Less} else {Ordering::Greater}) {
+      Ok(index) | Err(index) => index,
+    };
+    &self.segments [after.saturating_sub (1)]
+  }
+
+  /// The tempo at `beat`, in beats per minute.
+  pub fn tempo_at (&self, beat: Beats)->f64 {
+    let segment = self.segment_at (| segment | segment.beat, beat);
+    segment.beats_per_minute + segment.slope*(beat - segment.beat).max (0.0)
+  }
+
+  pub fn seconds (&self, beat: Beats)->NoteTime {
+    let segment = self.segment_at (| segment | segment.beat, beat);
+    if beat < segment.beat {
+      return beat*60.0/segment.beats_per_minute;
+    }
+    segment.seconds + segment.seconds_after (beat - segment.beat)
+  }
+
+  pub fn beat (&self, seconds: NoteTime)->Beats {
+    let segment = self.segment_at (| segment | segment.seconds, seconds);
+    if seconds < segment.seconds {
+      return seconds*segment.beats_per_minute/60.0;
+    }
+    segment.beat + segment.beats_after (seconds - segment.seconds)
+  }
+
+  pub fn time_signature_at_bar (&self, bar: i64)->TimeSignature {
+    self.meter_changes.iter().rev().find (| change | change.bar <= bar).unwrap_or (&self.meter_changes [0]).time_signature
+  }
+
+  /// The beat that `bar` starts on.
+  pub fn bar_start (&self, bar: i64)->Beats {
+    let mut beat = 0.0;
+    for (index, change) in self.meter_changes.iter().enumerate() {
+      let length = change.time_signature.beats_per_bar();
+      match self.meter_changes.get (index + 1) {
+        Some(next) if bar > next.bar => beat += (next.bar - change.bar) as f64*length,
+        _ => return beat + (bar - change.bar) as f64*length,
+      }
+    }
+    unreachable!()
+  }
+
+  /// Which bar `beat` is in, and how many beats into that bar it is.
+  pub fn bar_and_beat (&self, beat: Beats)->(i64, Beats) {
+    let mut bar_start = 0.0;
+    for (index, change) in self.meter_changes.iter().enumerate() {
+      let length = change.time_signature.beats_per_bar();
+      let bars_until_next = self.meter_changes.get (index + 1).map (| next | next.bar - change.bar);
+      let bars = ((beat - bar_start)/length).floor();
+      if bars_until_next.map_or (true, | until_next | bars < until_next as f64) {
+        let bar = change.bar + bars as i64;
+        return (bar, beat - bar_start - bars*length);
+      }
+      bar_start += bars_until_next.unwrap() as f64*length;
+    }
+    unreachable!()
+  }
+
+  /// The time of `beat` beats into `bar`, in seconds.
+  pub fn bar_seconds (&self, bar: i64, beat: Beats)->NoteTime {
+    self.seconds (self.bar_start (bar) + beat)
+  }
+
+  /// Treats the times of the notes as beats, and moves them to the corresponding seconds.
+  pub fn beats_to_seconds <N: Retimable + ?Sized> (&self, notes: &mut N) {
+    notes.retime (&| beat | self.seconds (beat));
+  }
+  /// Treats the times of the notes as seconds, and moves them to the corresponding beats.
+  pub fn seconds_to_beats <N: Retimable + ?Sized> (&self, notes: &mut N) {
+    notes.retime (&| seconds | self.beat (seconds));
+  }
+}
@@ -0,0 +1,544 @@
+use super::*;
+
+use phrase::{Phrase, PhraseNote};
+use tuning::Tuning;
+
+
+/// Pitches here are MIDI pitches, so 60 is middle C, and a tonic or root can be in any octave.
+pub type Pitch = i32;
+
+fn pitch_class (pitch: Pitch)->i32 {
+  ((pitch % 12) + 12) % 12
+}
+
+/// Reads a note name like "C", "F#" or "Bb" as a pitch class from 0 (C) to 11.
+pub fn parse_pitch_class (name: &str)->Option<i32> {
+  let mut characters = name.chars();
+  let natural = match characters.next()?.to_ascii_uppercase() {
+    'C' => 0, 'D' => 2, 'E' => 4, 'F' => 5, 'G' => 7, 'A' => 9, 'B' => 11,
+    _ => return None,
+  };
+  let mut offset = 0;
+  for character in characters {
+    match character {
+      '#' | '♯' => offset += 1,
+      'b' | '♭' => offset -= 1,
+      _ => return None,
+    }
+  }
+  Some(pitch_class (natural + offset))
+}
+
+
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub enum Mode {
+  Ionian,
+  Dorian,
+  Phrygian,
+  Lydian,
+  Mixolydian,
+  Aeolian,
+  Locrian,
+  HarmonicMinor,
+  MelodicMinor,
+  MajorPentatonic,
+  MinorPentatonic,
+  Blues,
+  Chromatic,
+}
+
+impl Mode {
+  pub const MAJOR: Mode = Mode::Ionian;
+  pub const MINOR: Mode = Mode::Aeolian;
+
+  /// The pitches of one octave, in semitones above the tonic.
+  pub fn intervals (&self)->&'static [i32] {
+    match *self {
+      Mode::Ionian => &[0, 2, 4, 5, 7, 9, 11],
+      Mode::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+      Mode::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+      Mode::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+      Mode::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+      Mode::Aeolian => &[0, 2, 3, 5, 7, 8, 10],
+      Mode::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+      Mode::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+      Mode::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+      Mode::MajorPentatonic => &[0, 2, 4, 7, 9],
+      Mode::MinorPentatonic => &[0, 3, 5, 7, 10],
+      Mode::Blues => &[0, 3, 5, 6, 7, 10],
+      Mode::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+    }
+  }
+}
+
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub struct Scale {
+  pub tonic: Pitch,
+  pub mode: Mode,
+}
+
+impl Scale {
+  pub fn new (tonic: Pitch, mode: Mode)->Scale {
+    Scale {tonic, mode}
+  }
+  pub fn major (tonic: Pitch)->Scale {Scale::new (tonic, Mode::MAJOR)}
+  pub fn minor (tonic: Pitch)->Scale {Scale::new (tonic, Mode::MINOR)}
+
+  /// The pitch `step` scale steps above the tonic; negative steps go below it.
+  /// In a seven-note scale, step 0 is the first degree, step 4 is the fifth degree and step 7 is the tonic an octave up.
+  pub fn pitch (&self, step: i32)->Pitch {
+    let intervals = self.mode.intervals();
+    let length = intervals.len() as i32;
+    let octave = if step < 0 { (step + 1)/length - 1 } else { step/length };
+    self.tonic + octave*12 + intervals [(step - octave*length) as usize]
+  }
+
+  /// The pitch of the `degree`th degree, counting the tonic as 1 like musicians do.
+  pub fn degree (&self, degree: i32)->Pitch {
+    self.pitch (degree - 1)
+  }
+
+  pub fn frequency (&self, step: i32)->f64 {
+    midi_pitch_to_frequency (self.pitch (step))
+  }
+  /// The frequency of the pitch, with the pitch played as a key of `tuning`.
+  pub fn tuned_frequency (&self, step: i32, tuning: &Tuning)->f64 {
+    tuning.pitch_to_frequency (self.pitch (step))
+  }
+
+  pub fn contains (&self, pitch: Pitch)->bool {
+    self.mode.intervals().contains (&pitch_class (pitch - self.tonic))
+  }
+
+  /// The step that gives `pitch`, if it's in the scale.
+  pub fn step_of (&self, pitch: Pitch)->Option<i32> {
+    let length = self.mode.intervals().len() as i32;
+    let relative = pitch - self.tonic;
+    let octave = if relative < 0 { (relative + 1)/12 - 1 } else { relative/12 };
+    self.mode.intervals().iter().position (| &interval | interval == relative - octave*12).map (| index | octave*length + index as i32)
+  }
+
+  /// The nearest pitch in the scale, preferring the lower one in a tie.
+  pub fn snap (&self, pitch: Pitch)->Pitch {
+    (0..12).flat_map (| distance | vec![pitch - distance, pitch + distance]).find (| &candidate | self.contains (candidate)).unwrap()
+  }
+
+  /// A melody that plays the given steps one after another, each lasting `note_duration`.
+  pub fn phrase (&self, steps: &[i32], start: NoteTime, note_duration: NoteTime)->Phrase {
+    Phrase {
+      notes: steps.iter().enumerate().map (| (index, &step) | {
+        let note_start = start + index as f64*note_duration;
+        PhraseNote::new (note_start, note_start + note_duration, self.frequency (step))
+      }).collect()
+    }
+  }
+}
+
+
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub enum ChordQuality {
+  Major,
+  Minor,
+  Diminished,
+  Augmented,
+  Suspended2,
+  Suspended4,
+  /// just the root and fifth
+  Power,
+}
+
+impl ChordQuality {
+  fn intervals (&self)->&'static [i32] {
+    match *self {
+      ChordQuality::Major => &[0, 4, 7],
+      ChordQuality::Minor => &[0, 3, 7],
+      ChordQuality::Diminished => &[0, 3, 6],
+      ChordQuality::Augmented => &[0, 4, 8],
+      ChordQuality::Suspended2 => &[0, 2, 7],
+      ChordQuality::Suspended4 => &[0, 5, 7],
+      ChordQuality::Power => &[0, 7],
+    }
+  }
+}
+
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub enum Seventh {
+  Major,
+  /// as in a dominant or minor seventh chord
+  Minor,
+  /// as in a fully diminished seventh chord
+  Diminished,
+}
+
+impl Seventh {
+  fn interval (&self)->i32 {
+    match *self {
+      Seventh::Major => 11,
+      Seventh::Minor => 10,
+      Seventh::Diminished => 9,
+    }
+  }
+}
+
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub enum Extension {
+  Sixth,
+  FlatNinth,
+  Ninth,
+  SharpNinth,
+  Eleventh,
+  SharpEleventh,
+  FlatThirteenth,
+  Thirteenth,
+}
+
+impl Extension {
+  fn interval (&self)->i32 {
+    match *self {
+      Extension::Sixth => 9,
+      Extension::FlatNinth => 13,
+      Extension::Ninth => 14,
+      Extension::SharpNinth => 15,
+      Extension::Eleventh => 17,
+      Extension::SharpEleventh => 18,
+      Extension::FlatThirteenth => 20,
+      Extension::Thirteenth => 21,
+    }
+  }
+}
+
+fn chord_notes <F: Fn(Pitch)->f64> (pitches: &[Pitch], start: NoteTime, end: NoteTime, frequency: F)->Vec<PhraseNote> {
+  pitches.iter().map (| &pitch | {
+    let mut note = PhraseNote::new (start, end, frequency (pitch));
+    note.tags.insert (String::from_str ("chord").unwrap());
+    note
+  }).collect()
+}
+
+#[derive (Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub struct Chord {
+  pub root: Pitch,
+  pub quality: ChordQuality,
+  pub seventh: Option<Seventh>,
+  pub extensions: Vec<Extension>,
+  /// 0 puts the root in the bass, 1 puts the next chord tone (usually the third) in the bass, and so on.
+  pub inversion: usize,
+}
+
+impl Chord {
+  pub fn new (root: Pitch, quality: ChordQuality)->Chord {
+    Chord {root, quality, seventh: None, extensions: Vec::new(), inversion: 0}
+  }
+  pub fn major (root: Pitch)->Chord {Chord::new (root, ChordQuality::Major)}
+  pub fn minor (root: Pitch)->Chord {Chord::new (root, ChordQuality::Minor)}
+  pub fn with_seventh (mut self, seventh: Seventh)->Chord {
+    self.seventh = Some(seventh);
+    self
+  }
+  pub fn with_extension (mut self, extension: Extension)->Chord {
+    self.extensions.push (extension);
+    self
+  }
+  pub fn with_inversion (mut self, inversion: usize)->Chord {
+    self.inversion = inversion;
+    self
+  }
+
+  /// Semitones above the root, in root position.
+  pub fn intervals (&self)->Vec<i32> {
+    let mut intervals = self.quality.intervals().to_vec();
+    intervals.extend (self.seventh.map (| seventh | seventh.interval()));
+    intervals.extend (self.extensions.iter().map (| extension | extension.interval()));
+    intervals.sort();
+    intervals.dedup();
+    intervals
+  }
+
+  /// The chord tones, lowest first, with the inversion applied by moving the lowest tones up an octave.
+  pub fn pitches (&self)->Vec<Pitch> {
+    let mut pitches: Vec<Pitch> = self.intervals().into_iter().map (| interval | self.root + interval).collect();
+    for _ in 0..self.inversion % pitches.len() {
+      let lowest = pitches.remove (0);
+      let above = pitches.last().cloned().unwrap_or (lowest);
+      pitches.push (lowest + 12*((above - lowest)/12 + 1));
+    }
+    pitches
+  }
+
+  /// A close voicing with the bass at or above `low`, leaving out any tones that would go above `high`.
+  pub fn voiced_within (&self, low: Pitch, high: Pitch)->Vec<Pitch> {
+    let mut result = Vec::new();
+    let mut floor = low;
+    for pitch in self.pitches() {
+      let placed = floor + pitch_class (pitch - floor);
+      if placed > high { break; }
+      result.push (placed);
+      floor = placed + 1;
+    }
+    result
+  }
+
+  /// Of the close voicings of every inversion within the range, the one that moves least from `previous`.
+  pub fn voiced_near (&self, previous: &[Pitch], low: Pitch, high: Pitch)->Vec<Pitch> {
+    let length = self.intervals().len();
+    let movement = | voicing: &Vec<Pitch> | -> i32 {
+      voicing.iter().map (| pitch | previous.iter().map (| other | (pitch - other).abs()).min().unwrap_or (0)).sum::<i32>()
+        + previous.iter().map (| other | voicing.iter().map (| pitch | (pitch - other).abs()).min().unwrap_or (0)).sum::<i32>()
+    };
+    let mut best: Option<Vec<Pitch>> = None;
+    for inversion in 0..length {
+      let inverted = self.clone().with_inversion (inversion);
+      // try the bass in each octave within the range, not just the lowest
+      let mut bass = low + pitch_class (inverted.pitches() [0] - low);
+      while bass <= high {
+        let voicing = inverted.voiced_within (bass, high);
+        if voicing.len() == length && best.as_ref().map_or (true, | best | movement (&voicing) < movement (best)) {
+          best = Some(voicing);
+        }
+        bass += 12;
+      }
+    }
+    best.unwrap_or_else (|| self.voiced_within (low, high))
+  }
+
+  pub fn frequencies (&self)->Vec<f64> {
+    self.pitches().into_iter().map (midi_pitch_to_frequency).collect()
+  }
+  pub fn tuned_frequencies (&self, tuning: &Tuning)->Vec<f64> {
+    self.pitches().into_iter().map (| pitch | tuning.pitch_to_frequency (pitch)).collect()
+  }
+
+  /// One note for each pitch, all lasting from `start` to `end`, tagged "chord".
+  pub fn phrase_notes (pitches: &[Pitch], start: NoteTime, end: NoteTime)->Vec<PhraseNote> {
+    chord_notes (pitches, start, end, midi_pitch_to_frequency)
+  }
+  /// Like phrase_notes(), with the pitches played as keys of `tuning`.
+  pub fn tuned_phrase_notes (pitches: &[Pitch], start: NoteTime, end: NoteTime, tuning: &Tuning)->Vec<PhraseNote> {
+    chord_notes (pitches, start, end, | pitch | tuning.pitch_to_frequency (pitch))
+  }
+}
+
+
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub struct Key {
+  pub tonic: Pitch,
+  pub mode: Mode,
+}
+
+impl Key {
+  pub fn new (tonic: Pitch, mode: Mode)->Key {
+    Key {tonic, mode}
+  }
+  pub fn major (tonic: Pitch)->Key {Key::new (tonic, Mode::MAJOR)}
+  pub fn minor (tonic: Pitch)->Key {Key::new (tonic, Mode::MINOR)}
+
+  pub fn scale (&self)->Scale {
+    Scale::new (self.tonic, self.mode)
+  }
+
+  /// The chord built in thirds from the scale on the `degree`th degree (counting the tonic as 1), optionally with its seventh.
+  pub fn diatonic_chord (&self, degree: i32, seventh: bool)->Chord {
+    let scale = self.scale();
+    let root = scale.degree (degree);
+    let third = scale.degree (degree + 2) - root;
+    let fifth = scale.degree (degree + 4) - root;
+    let quality = match (third, fifth) {
+      (3, 6) => ChordQuality::Diminished,
+      (4, 8) => ChordQuality::Augmented,
+      (3, _) => ChordQuality::Minor,
+      _ => ChordQuality::Major,
+    };
+    let mut chord = Chord::new (root, quality);
+    if seventh {
+      chord.seventh = Some(match scale.degree (degree + 6) - root {
+        11 => Seventh::Major,
+        9 => Seventh::Diminished,
+        _ => Seventh::Minor,
+      });
+    }
+    chord
+  }
+
+  /// Reads a Roman numeral like "I", "ii7", "V65", "viio7", "viiø7", "bVII", "IV+", "Imaj7" or "vi6".
+  ///
+  /// Upper case is major and lower case is minor; "o" or "°" makes it diminished, "+" augmented, and "ø" half-diminished.
+  /// A plain 7 uses the seventh from the key. Figured-bass inversions (6, 64, 65, 43, 42 or 2) are understood.
+  pub fn chord (&self, numeral: &str)->Option<Chord> {
+    let mut rest = numeral.trim();
+    let mut alteration = 0;
+    loop {
+      if rest.starts_with ('b') { alteration -= 1; rest = &rest [1..]; }
+      else if rest.starts_with ('#') { alteration += 1; rest = &rest [1..]; }
+      else { break; }
+    }
+    let numerals = ["vii", "iii", "vi", "iv", "ii", "v", "i"];
+    let (degree, upper) = numerals.iter().filter_map (| candidate | {
+      if rest.len() < candidate.len() { return None; }
+      let prefix = &rest [..candidate.len()];
+      if prefix == *candidate { Some((candidate, false)) }
+      else if prefix == candidate.to_uppercase() { Some((candidate, true)) }
+      else { None }
+    }).next().map (| (candidate, upper) | {
+      rest = &rest [candidate.len()..];
+      (match *candidate { "i" => 1, "ii" => 2, "iii" => 3, "iv" => 4, "v" => 5, "vi" => 6, _ => 7 }, upper)
+    })?;
+
+    let mut quality = if upper {ChordQuality::Major} else {ChordQuality::Minor};
+    let mut seventh = None;
+    let mut fully_diminished = false;
+    if rest.starts_with ('o') || rest.starts_with ('°') {
+      quality = ChordQuality::Diminished;
+      fully_diminished = true;
+      rest = &rest [rest.chars().next().unwrap().len_utf8()..];
+    }
+    else if rest.starts_with ('ø') {
+      quality = ChordQuality::Diminished;
+      seventh = Some(Seventh::Minor);
+      rest = &rest ['ø'.len_utf8()..];
+    }
+    else if rest.starts_with ('+') {
+      quality = ChordQuality::Augmented;
+      rest = &rest [1..];
+    }
+    if rest.starts_with ("maj7") || rest.starts_with ("M7") {
+      seventh = Some(Seventh::Major);
+      rest = &rest [if rest.starts_with ('M') {1} else {3}..];
+    }
+
+    let mut root = self.scale().degree (degree) + alteration;
+    // in minor keys, vii° means the chord on the raised leading tone, as in harmonic minor
+    if self.mode == Mode::Aeolian && degree == 7 && quality == ChordQuality::Diminished && alteration == 0 {
+      root += 1;
+    }
+    let diatonic_seventh = || {
+      let candidate = self.scale().degree (degree + 6) + alteration;
+      if self.scale().contains (candidate) {
+        match candidate - root { 11 => Seventh::Major, 9 => Seventh::Diminished, _ => Seventh::Minor }
+      }
+      else { Seventh::Minor }
+    };
+    let (has_seventh, inversion) = match rest {
+      "" => (false, 0),
+      "6" => (false, 1),
+      "64" => (false, 2),
+      "7" => (true, 0),
+      "65" => (true, 1),
+      "43" => (true, 2),
+      "42" | "2" => (true, 3),
+      _ => return None,
+    };
+    if has_seventh && seventh.is_none() {
+      seventh = Some(if fully_diminished {Seventh::Diminished} else {diatonic_seventh()});
+    }
+    let mut chord = Chord::new (root, quality).with_inversion (inversion);
+    chord.seventh = seventh;
+    Some(chord)
+  }
+
+  // where the tonic is on the line of fifths, relative to the tonic of the major key with the same signature
+  fn tonic_fifths_offset (&self)->i32 {
+    match self.mode {
+      Mode::Ionian | Mode::MajorPentatonic | Mode::Chromatic => 0,
+      Mode::Dorian => 2,
+      Mode::Phrygian => 4,
+      Mode::Lydian => -1,
+      Mode::Mixolydian => 1,
+      Mode::Aeolian | Mode::HarmonicMinor | Mode::MelodicMinor | Mode::MinorPentatonic | Mode::Blues => 3,
+      Mode::Locrian => 5,
+    }
+  }
+
+  /// The number of sharps in the key signature, or minus the number of flats.
+  /// Keys that could be written either way get flats, so F# major is written as Gb major.
+  pub fn fifths (&self)->i32 {
+    let offset = self.tonic_fifths_offset();
+    line_of_fifths (self.tonic, offset - 6) - offset
+  }
+
+  /// Writes `pitch` the way it would appear in this key.
+  ///
+  /// Notes of a seven-note scale get consecutive letters, so the leading tone of A harmonic minor is G#, not Ab.
+  /// Other notes get the spelling closest to the tonic on the line of fifths, avoiding double sharps and flats.
+  pub fn spell (&self, pitch: Pitch)->SpelledPitch {
+    let tonic_position = self.fifths() + self.tonic_fifths_offset();
+    let intervals = self.mode.intervals();
+    let interval = pitch_class (pitch - self.tonic);
+    let (letter_index, alter) = match intervals.iter().position (| &candidate | candidate == interval) {
+      Some(step) if intervals.len() == 7 => {
+        let letter_index = (position_letter_index (tonic_position) + step) % 7;
+        let alter = pitch_class (pitch - NATURAL_PITCH_CLASSES [letter_index] + 6) - 6;
+        (letter_index, alter)
+      },
+      _ => {
+        let mut position = line_of_fifths (pitch, tonic_position - 5);
+        // in remote keys, prefer a plain sharp or flat to a double one
+        if position > 12 { position -= 12; }
+        if position < -8 { position += 12; }
+        (position_letter_index (position), (position + 1 - ((position + 1) % 7 + 7) % 7)/7)
+      },
+    };
+    SpelledPitch {
+      letter: LETTERS [letter_index],
+      alter,
+      octave: (pitch - alter - NATURAL_PITCH_CLASSES [letter_index])/12 - 1,
+    }
+  }
+}
+
+const LETTERS: [char; 7] = ['C', 'D', 'E', 'F', 'G', 'A', 'B'];
+const NATURAL_PITCH_CLASSES: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+// the position of a pitch class on the line of fifths (F = -1, C = 0, G = 1, ... F# = 6), choosing the spelling from lowest to lowest + 11
+fn line_of_fifths (pitch: Pitch, lowest: i32)->i32 {
+  lowest + pitch_class (pitch_class (pitch)*7 - lowest)
+}
+
+fn position_letter_index (position: i32)->usize {
+  // F C G D A E B, as indices into LETTERS
+  [3, 0, 4, 1, 5, 2, 6] [(((position + 1) % 7 + 7) % 7) as usize]
+}
+
+/// A pitch written as a letter with sharps or flats, as it appears in a score.
+#[derive (Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub struct SpelledPitch {
+  pub letter: char,
+  /// The number of sharps, or minus the number of flats.
+  pub alter: i32,
+  /// The octave in scientific pitch notation, so middle C is C4. It goes by the letter, so B#3 is the same pitch as C4.
+  pub octave: i32,
+}
+
+impl SpelledPitch {
+  pub fn pitch (&self)->Pitch {
+    let letter_index = LETTERS.iter().position (| &letter | letter == self.letter).unwrap();
+    (self.octave + 1)*12 + NATURAL_PITCH_CLASSES [letter_index] + self.alter
+  }
+}
+
+
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Progression {
+  pub key: Key,
+  pub chords: Vec<Chord>,
+}
+
+impl Progression {
+  /// Reads whitespace-separated Roman numerals, like "I vi IV V7". Returns the first numeral it can't read as the error.
+  pub fn parse (key: Key, numerals: &str)->Result<Progression, String> {
+    let chords = numerals.split_whitespace().map (| numeral | key.chord (numeral).ok_or_else (|| numeral.to_string())).collect::<Result<Vec<_>, _>>()?;
+    Ok(Progression {key, chords})
+  }
+
+  /// Plays each chord for `chord_duration` starting at `start`, voiced within the range to move as little as possible from one chord to the next.
+  pub fn phrase (&self, start: NoteTime, chord_duration: NoteTime, low: Pitch, high: Pitch)->Phrase {
+    let mut notes = Vec::new();
+    let mut previous: Vec<Pitch> = Vec::new();
+    for (index, chord) in self.chords.iter().enumerate() {
+      let voicing = if previous.is_empty() { chord.voiced_within (low, high) } else { chord.voiced_near (&previous, low, high) };
+      let chord_start = start + index as f64*chord_duration;
+      notes.extend (Chord::phrase_notes (&voicing, chord_start, chord_start + chord_duration));
+      previous = voicing;
+    }
+    Phrase {notes}
+  }
+}
@@ -0,0 +1,269 @@
+use super::*;
+
+use std::io::{self, Read};
+use std::fs::File;
+use std::path::Path;
+
+
+/// Which scale degree each key plays, like a Scala .kbm file.
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct KeyboardMapping {
+  /// Keys outside this range don't play anything.
+  pub first_key: i32,
+  pub last_key: i32,
+  /// The key that plays degree 0 of the scale.
+  pub middle_key: i32,
+  /// The key that plays `reference_frequency`. It must be mapped, or no key has a frequency.
+  pub reference_key: i32,
+  pub reference_frequency: f64,
+  /// The degree that the mapping repeats at. Ignored if `degrees` is empty.
+  pub formal_octave_degree: usize,
+  /// The degree for each key in the repeating pattern, starting from the middle key; None means the key is unmapped.
+  /// If it's empty, each key plays the next degree of the scale.
+  pub degrees: Vec<Option<usize>>,
+}
+
+impl Default for KeyboardMapping {
+  /// Every key maps to the next degree, with key 60 on degree 0 and A4 (key 69) at 440 Hz.
+  fn default()->Self {
+    KeyboardMapping {
+      first_key: 0,
+      last_key: 127,
+      middle_key: 60,
+      reference_key: 69,
+      reference_frequency: 440.0,
+      formal_octave_degree: 0,
+      degrees: Vec::new(),
+    }
+  }
+}
+
+/// A tuning system: a scale that repeats at some period (usually an octave), and a mapping from keys to degrees of the scale.
+///
+/// Keys are numbered like MIDI pitches. With the default 12-tone equal temperament, key 60 is middle C and key 69 is A440.
+#[derive (Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Tuning {
+  pub description: String,
+  /// The degrees above the first one, in cents, like the lines of a Scala .scl file. The last one is the period.
+  pub cents: Vec<f64>,
+  pub mapping: KeyboardMapping,
+}
+
+impl Default for Tuning {
+  fn default()->Self {
+    Tuning::equal_temperament (12).unwrap()
+  }
+}
+
+fn ratio_to_cents (ratio: f64)->f64 {
+  1200.0*ratio.log2()
+}
+
+fn octave_reduce (cents: f64)->f64 {
+  let remainder = cents % 1200.0;
+  if remainder < 0.0 { remainder + 1200.0 } else { remainder }
+}
+
+fn invalid_data (message: String)->io::Error {
+  io::Error::new (io::ErrorKind::InvalidData, message)
+}
+
+// the lines of a Scala file that aren't comments
+fn scala_lines (text: &str)->Vec<&str> {
+  text.lines().filter (| line | !line.starts_with ('!')).collect()
+}
+
+fn read_text <P: AsRef<Path>> (path: P)->io::Result<String> {
+  let mut text = String::new();
+  File::open (path)?.read_to_string (&mut text)?;
+  Ok(text)
+}
+
+impl Tuning {
+  /// Divides the octave into `divisions` equal steps, one per key. Fails if `divisions` is 0.
+  pub fn equal_temperament (divisions: usize)->io::Result<Tuning> {
+    if divisions == 0 {
+      return Err(io::Error::new (io::ErrorKind::InvalidInput, "equal temperament needs at least 1 step per octave"));
+    }
+    Ok(Tuning {
+      description: format!("{}-tone equal temperament", divisions),
+      cents: (1..divisions + 1).map (| step | 1200.0*step as f64/divisions as f64).collect(),
+      mapping: KeyboardMapping::default(),
+    })
+  }
+
+  /// A scale with the given frequency ratios above its first degree, which repeats at the octave.
+  /// Ratios outside the octave are moved into it, and duplicates are removed. Fails if a ratio isn't a positive, finite number.
+  pub fn from_ratios (description: &str, ratios: &[f64])->io::Result<Tuning> {
+    if let Some(ratio) = ratios.iter().find (| ratio | !(ratio.is_finite() && **ratio > 0.0)) {
+      return Err(io::Error::new (io::ErrorKind::InvalidInput, format!("{} isn't a valid frequency ratio", ratio)));
+    }
+    let mut cents: Vec<f64> = ratios.iter().map (| &ratio | octave_reduce (ratio_to_cents (ratio))).filter (| &cents | cents > 1e-9).collect();
+    cents.sort_by (| a, b | a.partial_cmp (b).unwrap());
+    cents.dedup_by (| a, b | (*a - *b).abs() < 1e-9);
+    cents.push (1200.0);
+    Ok(Tuning {description: description.to_string(), cents, mapping: KeyboardMapping::default()})
+  }
+
+  /// A just intonation scale made from every product of the generators, with each generator's exponent in its range.
+  /// For example, `[(3.0, -1, 1), (5.0, -1, 1)]` gives a 5-limit scale with 9 notes.
+  pub fn just_lattice (generators: &[(f64, i32, i32)])->io::Result<Tuning> {
+    let mut ratios = vec![1.0];
+    for &(generator, lowest, highest) in generators {
+      ratios = ratios.iter().flat_map (| &ratio | (lowest..highest + 1).map (move | exponent | ratio*generator.powi (exponent))).collect();
+    }
+    let description = format!("just intonation lattice of {:?}", generators);
+    Tuning::from_ratios (&description, &ratios)
+  }
+
+  /// Reads the text of a Scala .scl file. The tuning gets the default keyboard mapping.
+  pub fn parse_scl (text: &str)->io::Result<Tuning> {
+    let lines = scala_lines (text);
+    let description = lines.get (0).ok_or_else (|| invalid_data ("empty .scl file".to_string()))?.trim().to_string();
+    let count_line = lines.get (1).ok_or_else (|| invalid_data ("missing note count in .scl file".to_string()))?;
+    let count: usize = count_line.trim().parse().map_err (| _ | invalid_data (format!("bad note count in .scl file: {:?}", count_line)))?;
+    let mut cents = Vec::with_capacity (count);
+    for line in lines.iter().skip (2).filter (| line | !line.trim().is_empty()).take (count) {
+      let value = line.split_whitespace().next().unwrap();
+      let bad = || invalid_data (format!("bad pitch in .scl file: {:?}", line));
+      cents.push (if value.contains ('.') {
+        value.parse().map_err (| _ | bad())?
+      }
+      else {
+        let mut parts = value.splitn (2, '/');
+        let numerator: f64 = parts.next().unwrap().parse().map_err (| _ | bad())?;
+        let denominator: f64 = match parts.next() {
+          Some(denominator) => denominator.parse().map_err (| _ | bad())?,
+          None => 1.0,
+        };
+        if numerator <= 0.0 || denominator <= 0.0 { return Err(bad()); }
+        ratio_to_cents (numerator/denominator)
+      });
+    }
+    if cents.len() < count || count == 0 {
+      return Err(invalid_data (format!(".scl file promises {} notes but has {}", count, cents.len())));
+    }
+    Ok(Tuning {description, cents, mapping: KeyboardMapping::default()})
+  }
+
+  pub fn load_scl <P: AsRef<Path>> (path: P)->io::Result<Tuning> {
+    Tuning::parse_scl (&read_text (path)?)
+  }
+
+  pub fn with_mapping (mut self, mapping: KeyboardMapping)->Tuning {
+    self.mapping = mapping;
+    self
+  }
+
+  fn period (&self)->f64 {
+    *self.cents.last().unwrap()
+  }
+
+  // cents above degree 0, for any degree, including ones beyond the period
+  fn degree_cents (&self, degree: i64)->f64 {
+    let length = self.cents.len() as i64;
+    let periods = if degree < 0 { (degree + 1)/length - 1 } else { degree/length };
+    let within = (degree - periods*length) as usize;
+    periods as f64*self.period() + if within == 0 { 0.0 } else { self.cents [within - 1] }
+  }
+
+  // cents above the middle key, or None if the key is unmapped
+  fn key_cents (&self, key: i32)->Option<f64> {
+    let mapping = &self.mapping;
+    if key < mapping.first_key || key > mapping.last_key { return None; }
+    let relative = (key - mapping.middle_key) as i64;
+    if mapping.degrees.is_empty() {
+      return Some(self.degree_cents (relative));
+    }
+    let size = mapping.degrees.len() as i64;
+    let repeats = if relative < 0 { (relative + 1)/size - 1 } else { relative/size };
+    let degree = mapping.degrees [(relative - repeats*size) as usize]?;
+    Some(repeats as f64*self.degree_cents (mapping.formal_octave_degree as i64) + self.degree_cents (degree as i64))
+  }
+
+  /// The frequency that `key` plays, or None if it's unmapped.
+  pub fn frequency (&self, key: i32)->Option<f64> {
+    let reference = self.key_cents (self.mapping.reference_key)?;
+    self.key_cents (key).map (| cents | self.mapping.reference_frequency*2f64.powf ((cents - reference)/1200.0))
+  }
+
+  /// The mapped key whose frequency is closest to `frequency`, if any key near it is mapped.
+  /// None if `frequency` isn't a positive, finite number.
+  pub fn nearest_key (&self, frequency: f64)->Option<i32> {
+    let reference = self.key_cents (self.mapping.reference_key)?;
+    let target = ratio_to_cents (frequency/self.mapping.reference_frequency) + reference;
+    if !target.is_finite() { return None; }
+    // guess from the average size of a step, then look around the guess
+    let keys_per_period = if self.mapping.degrees.is_empty() {self.cents.len()} else {self.mapping.degrees.len()} as f64;
+    let period = if self.mapping.degrees.is_empty() {self.period()} else {self.degree_cents (self.mapping.formal_octave_degree as i64)};
+    // only keys in the mapping's range can be mapped, so the nearest one is never further out than its ends
+    let guess = (self.mapping.middle_key as f64 + (target*keys_per_period/period).round())
+      .max (self.mapping.first_key as f64).min (self.mapping.last_key as f64) as i32;
+    let reach = 2*keys_per_period as i32 + 2;
+    (guess.saturating_sub (reach)..guess.saturating_add (reach + 1))
+      .filter_map (| key | self.key_cents (key).map (| cents | (key, OrderedFloat ((cents - target).abs()))))
+      .min_by_key (| &(_, distance) | distance)
+      .map (| (key, _) | key)
+  }
+
+  /// The frequency of the nearest mapped key, or the frequency itself if no key near it is mapped.
+  pub fn nearest_frequency (&self, frequency: f64)->f64 {
+    self.nearest_key (frequency).and_then (| key | self.frequency (key)).unwrap_or (frequency)
+  }
+
+  /// Like frequency(), but unmapped keys play the nearest mapped key below them,
+  /// or the 12-tone equal temperament frequency if there isn't one.
+  pub fn pitch_to_frequency (&self, pitch: i32)->f64 {
+    (0..128).filter_map (| offset | self.frequency (pitch - offset)).next().unwrap_or_else (|| midi_pitch_to_frequency (pitch))
+  }
+
+  /// Like nearest_key(), but gives the nearest MIDI pitch if no key near `frequency` is mapped.
+  pub fn nearest_pitch (&self, frequency: f64)->i32 {
+    self.nearest_key (frequency).unwrap_or_else (|| frequency_to_nearest_midi_pitch (frequency))
+  }
+}
+
+impl KeyboardMapping {
+  /// Reads the text of a Scala .kbm file.
+  pub fn parse_kbm (text: &str)->io::Result<KeyboardMapping> {
+    let lines: Vec<&str> = scala_lines (text).into_iter().map (| line | line.trim()).filter (| line | !line.is_empty()).collect();
+    let field = | index: usize, name: &str | -> io::Result<f64> {
+      let line = lines.get (index).ok_or_else (|| invalid_data (format!("missing {} in .kbm file", name)))?;
+      line.split_whitespace().next().unwrap().parse().map_err (| _ | invalid_data (format!("bad {} in .kbm file: {:?}", name, line)))
+    };
+    let size = field (0, "map size")? as usize;
+    let mut mapping = KeyboardMapping {
+      first_key: field (1, "first key")? as i32,
+      last_key: field (2, "last key")? as i32,
+      middle_key: field (3, "middle key")? as i32,
+      reference_key: field (4, "reference key")? as i32,
+      reference_frequency: field (5, "reference frequency")?,
+      formal_octave_degree: field (6, "formal octave degree")? as usize,
+      degrees: Vec::with_capacity (size),
+    };
+    for index in 0..size {
+      // the file may leave off entries at the end, which are unmapped
+      mapping.degrees.push (match lines.get (7 + index) {
+        None => None,
+        Some(line) if line.starts_with ('x') => None,
+        Some(_) => Some(field (7 + index, "key mapping")? as usize),
+      });
+    }
+    if !mapping.is_mapped (mapping.reference_key) {
+      return Err(invalid_data (format!("the reference key {} is unmapped in .kbm file", mapping.reference_key)));
+    }
+    Ok(mapping)
+  }
+
+  /// Whether `key` plays anything.
+  pub fn is_mapped (&self, key: i32)->bool {
+    if key < self.first_key || key > self.last_key { return false; }
+    if self.degrees.is_empty() { return true; }
+    let size = self.degrees.len() as i32;
+    self.degrees [(((key - self.middle_key) % size + size) % size) as usize].is_some()
+  }
+
+  pub fn load_kbm <P: AsRef<Path>> (path: P)->io::Result<KeyboardMapping> {
+    KeyboardMapping::parse_kbm (&read_text (path)?)
+  }
+}
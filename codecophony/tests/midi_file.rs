@@ -0,0 +1,114 @@
+extern crate codecophony;
+
+use codecophony::*;
+use codecophony::midi_file::*;
+
+fn write (notes: &Vec<Box<ToMIDIFileNotes>>, parameters: &MIDIFileParameters)->Vec<u8> {
+  let mut output = Vec::new();
+  write_midi_file (notes, parameters, &mut output).unwrap();
+  output
+}
+
+fn sorted_notes (contents: MIDIFileContents)->Vec<MIDIFileNote> {
+  let mut notes = contents.notes;
+  notes.sort_by (| a, b | a.start.partial_cmp (&b.start).unwrap().then (a.pitch.cmp (&b.pitch)));
+  notes
+}
+
+fn assert_close (actual: f64, expected: f64) {
+  assert!((actual - expected).abs() < 0.002, "{} should be {}", actual, expected);
+}
+
+#[test]
+fn pitched_and_percussion_notes_round_trip() {
+  let frequencies = [440.0, 452.0, 300.0];
+  let notes: Vec<Box<ToMIDIFileNotes>> = vec![
+    Box::new (vec![
+      MIDIPitchedNote::from_frequency (0.0, 0.5, frequencies [0], 100, 1),
+      // a different bend at the same time needs its own channel
+      MIDIPitchedNote::from_frequency (0.0, 0.5, frequencies [1], 90, 1),
+      MIDIPitchedNote::from_frequency (0.75, 1.0, frequencies [2], 80, 41),
+    ]),
+    Box::new (vec![MIDIPercussionNote::new (0.0, 0.25, 100, 36), MIDIPercussionNote::new (0.5, 0.25, 70, 38)]),
+  ];
+
+  for &format in [MIDIFileFormat::SingleTrack, MIDIFileFormat::MultipleTracks].iter() {
+    // a tempo other than the default, which the reader has to apply to get the times back
+    let parameters = MIDIFileParameters {format, beats_per_minute: 90.0, ..Default::default()};
+    let notes = sorted_notes (read_midi_file (&write (&notes, &parameters) [..]).unwrap());
+    assert_eq!(notes.len(), 5);
+
+    let pitched: Vec<&MIDIFileNote> = notes.iter().filter (| note | !note.is_percussion()).collect();
+    assert_eq!(pitched.len(), 3);
+    let mut read_frequencies: Vec<f64> = pitched.iter().map (| note | midi_pitch_and_bend_to_frequency (note.pitch, note.pitch_bend)).collect();
+    read_frequencies.sort_by (| a, b | a.partial_cmp (b).unwrap());
+    let mut expected = frequencies.to_vec();
+    expected.sort_by (| a, b | a.partial_cmp (b).unwrap());
+    for (frequency, expected) in read_frequencies.iter().zip (expected.iter()) {
+      assert!((frequency/expected - 1.0).abs() < 1e-4, "{} should be {}", frequency, expected);
+    }
+    let bent: Vec<&&MIDIFileNote> = pitched.iter().filter (| note | note.start == 0.0).collect();
+    assert_ne!(bent [0].channel, bent [1].channel);
+    let last = pitched.iter().find (| note | note.program == 40).unwrap();
+    assert_close (last.start, 0.75);
+    assert_close (last.duration, 1.0);
+    assert_eq!(last.velocity, 80);
+
+    let percussion: Vec<&MIDIFileNote> = notes.iter().filter (| note | note.is_percussion()).collect();
+    assert_eq!(percussion.iter().map (| note | (note.pitch, note.velocity)).collect::<Vec<_>>(), vec![(36, 100), (38, 70)]);
+    assert_close (percussion [1].start, 0.5);
+    assert_close (percussion [1].duration, 0.25);
+  }
+}
+
+#[test]
+fn tempo_changes_and_running_status_are_read() {
+  // 96 ticks per quarter note; the tempo halves after the first quarter note.
+  // The note offs are note ons with velocity 0, using running status.
+  let file: Vec<u8> = vec![
+    0x4d, 0x54, 0x68, 0x64, 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+    0x4d, 0x54, 0x72, 0x6b, 0, 0, 0, 32,
+    0, 0xff, 0x51, 3, 0x07, 0xa1, 0x20,
+    0, 0x90, 60, 100,
+    96, 60, 0,
+    0, 0xff, 0x51, 3, 0x0f, 0x42, 0x40,
+    0, 0x90, 62, 100,
+    96, 62, 0,
+    0, 0xff, 0x2f, 0,
+  ];
+  let notes = sorted_notes (read_midi_file (&file [..]).unwrap());
+  assert_eq!(notes.len(), 2);
+  assert_close (notes [0].start, 0.0);
+  assert_close (notes [0].duration, 0.5);
+  assert_close (notes [1].start, 0.5);
+  assert_close (notes [1].duration, 1.0);
+}
+
+#[test]
+fn channels_run_out_only_when_every_one_is_sounding() {
+  let overlapping = | count: u32 | -> Vec<Box<ToMIDIFileNotes>> {
+    vec![Box::new ((1..count + 1).map (| program | MIDIPitchedNote::new (0.0, 1.0, 60, 100, program)).collect::<Vec<_>>())]
+  };
+  // channel 10 is for percussion, which leaves 15 for pitched instruments.
+  // Programs are numbered from 1 like General MIDI, but from 0 in the file.
+  let notes = sorted_notes (read_midi_file (&write (&overlapping (15), &MIDIFileParameters::default()) [..]).unwrap());
+  assert_eq!(notes.len(), 15);
+  let mut programs: Vec<u32> = notes.iter().map (| note | note.program).collect();
+  programs.sort();
+  assert_eq!(programs, (0..15).collect::<Vec<_>>());
+
+  let mut output = Vec::new();
+  assert!(write_midi_file (&overlapping (17), &MIDIFileParameters::default(), &mut output).is_err());
+
+  // once the first notes have ended, their channels can be reused by other instruments
+  let mut sequential: Vec<MIDIPitchedNote> = (1..16).map (| program | MIDIPitchedNote::new (0.0, 1.0, 60, 100, program)).collect();
+  sequential.extend ((16..31).map (| program | MIDIPitchedNote::new (1.0, 1.0, 62, 100, program)));
+  let notes: Vec<Box<ToMIDIFileNotes>> = vec![Box::new (sequential)];
+  let read = sorted_notes (read_midi_file (&write (&notes, &MIDIFileParameters::default()) [..]).unwrap());
+  assert_eq!(read.len(), 30);
+  for note in read.iter() {
+    assert_eq!(note.pitch, if note.program < 15 {60} else {62});
+    assert_close (note.start, if note.program < 15 {0.0} else {1.0});
+    assert_close (note.duration, 1.0);
+  }
+}
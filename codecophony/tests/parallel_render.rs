@@ -0,0 +1,46 @@
+extern crate codecophony;
+
+use codecophony::*;
+
+type Frame = [f32; 2];
+type Rendered = PositionedSequence<Frame, Vec<Frame>>;
+
+fn notes()->Vec<Box<Renderable<Frame> + Send>> {
+  let mut notes: Vec<Box<Renderable<Frame> + Send>> = Vec::new();
+  for index in 0..300 {
+    let start = index as f64*0.037;
+    notes.push (Box::new (SineWave::new (start, 0.3, 220.0 + index as f64*7.0, 0.05)));
+    notes.push (Box::new (Oscillator::saw (start + 0.01, 0.2, 110.0 + index as f64*3.0, 0.03)));
+    notes.push (Box::new (Oscillator::noise (start + 0.02, 0.05, 0.02, index as u32)));
+  }
+  notes
+}
+
+fn assert_identical (serial: &Rendered, parallel: &Rendered) {
+  assert_eq!(serial.start, parallel.start);
+  assert_eq!(serial.frames.len(), parallel.frames.len());
+  for (index, (serial_frame, parallel_frame)) in serial.frames.iter().zip (parallel.frames.iter()).enumerate() {
+    for channel in 0..2 {
+      assert_eq!(serial_frame [channel].to_bits(), parallel_frame [channel].to_bits(), "frame {} differs", index);
+    }
+  }
+}
+
+#[test]
+fn parallel_render_matches_serial() {
+  let serial: Rendered = PositionedSequence::rendered_from (&notes(), 44100.0);
+  let parallel: Rendered = PositionedSequence::rendered_from_parallel (notes(), 44100.0);
+  assert!(serial.frames.iter().any (| frame | frame [0] != 0.0));
+  assert_identical (&serial, &parallel);
+
+  // the worker threads get reused by later renders
+  let again: Rendered = PositionedSequence::rendered_from_parallel (notes(), 44100.0);
+  assert_identical (&serial, &again);
+}
+
+#[test]
+fn parallel_render_of_nothing_is_empty() {
+  let notes: Vec<SineWave> = Vec::new();
+  let rendered: Rendered = PositionedSequence::rendered_from_parallel (notes, 44100.0);
+  assert!(rendered.frames.is_empty());
+}
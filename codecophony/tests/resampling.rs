@@ -0,0 +1,86 @@
+extern crate codecophony;
+
+use codecophony::*;
+use codecophony::resampling::HIGH_QUALITY_ZERO_CROSSINGS;
+
+// Renders tones and sweeps at 48 kHz, resamples them to 44.1 kHz, and measures what comes out.
+// Anything above 22.05 kHz can't be represented at 44.1 kHz, so whatever comes out of those is aliasing.
+
+type Frame = [f32; 1];
+type Rendered = PositionedSequence<Frame, Vec<Frame>>;
+
+const SOURCE_HZ: f64 = 48000.0;
+const SAMPLE_HZ: f64 = 44100.0;
+const AMPLITUDE: f64 = 0.5;
+const DURATION: f64 = 0.2;
+
+const SINC: Resampling = Resampling::WindowedSinc {zero_crossings: HIGH_QUALITY_ZERO_CROSSINGS};
+const POLYPHASE: Resampling = Resampling::Polyphase {zero_crossings: HIGH_QUALITY_ZERO_CROSSINGS};
+
+// a sine wave whose frequency rises linearly
+struct Sweep {
+  from: f64,
+  to: f64,
+}
+
+impl Windowed for Sweep {
+  fn start (&self)->NoteTime {0.0}
+  fn end (&self)->NoteTime {DURATION}
+}
+
+impl Renderable<Frame> for Sweep {
+  fn render (&self, buffer: &mut [Frame], start: FrameTime, sample_hz: f64) {
+    for (index, frame) in buffer.iter_mut().enumerate() {
+      let time = (start + index as FrameTime) as f64/sample_hz;
+      let phase = self.from*time + (self.to - self.from)*time*time/(2.0*DURATION);
+      frame [0] += (AMPLITUDE*(phase*std::f64::consts::PI*2.0).sin()) as f32;
+    }
+  }
+}
+
+// the power of the resampled signal, in dB relative to the power of the original
+fn output_level <N: Renderable<Frame>> (note: &N, resampling: Resampling)->f64 {
+  let source = Rendered::rendered_from (note, SOURCE_HZ).with_resampling (resampling);
+  let output = Rendered::rendered_from (&source, SAMPLE_HZ);
+  // stay away from the edges, where the signal starts and stops abruptly
+  let first = (0.05*SAMPLE_HZ) as usize;
+  let last = (0.15*SAMPLE_HZ) as usize;
+  let power = output.frames [first..last].iter().map (| frame | (frame [0] as f64)*(frame [0] as f64)).sum::<f64>()/(last - first) as f64;
+  10.0*(power/(AMPLITUDE*AMPLITUDE/2.0)).log10()
+}
+
+fn tone (frequency: f64)->SineWave {
+  SineWave::new (0.0, DURATION, frequency, AMPLITUDE)
+}
+
+#[test]
+fn band_limited_resampling_rejects_tones_above_nyquist() {
+  for &frequency in [22500.0, 23000.0, 23500.0].iter() {
+    let linear = output_level (&tone (frequency), Resampling::Linear);
+    for &resampling in [SINC, POLYPHASE].iter() {
+      let level = output_level (&tone (frequency), resampling);
+      assert!(level < -70.0, "{:?} let a {} Hz tone through at {:.1} dB", resampling, frequency, level);
+      assert!(linear > level + 40.0, "linear resampling of a {} Hz tone was only {:.1} dB, against {:.1} dB for {:?}", frequency, linear, level, resampling);
+    }
+  }
+}
+
+#[test]
+fn band_limited_resampling_rejects_sweep_above_nyquist() {
+  let sweep = Sweep {from: 22400.0, to: 23800.0};
+  let linear = output_level (&sweep, Resampling::Linear);
+  assert!(linear > -20.0, "linear resampling was expected to alias, but the sweep came out at {:.1} dB", linear);
+  for &resampling in [SINC, POLYPHASE].iter() {
+    let level = output_level (&sweep, resampling);
+    assert!(level < -70.0, "{:?} let the sweep through at {:.1} dB", resampling, level);
+  }
+}
+
+#[test]
+fn band_limited_resampling_keeps_the_audible_range() {
+  let sweep = Sweep {from: 1000.0, to: 19000.0};
+  for &resampling in [SINC, POLYPHASE].iter() {
+    let level = output_level (&sweep, resampling);
+    assert!(level.abs() < 0.1, "{:?} changed the level of the sweep by {:.2} dB", resampling, level);
+  }
+}
@@ -34,9 +34,13 @@ fn note_frames(note: & Note)->Arc <[Frame]> {
   codecophony::with_rendered_midi_note (& FluidsynthDirectlyRenderableMIDINote {
     duration: NotNaN::new(note.duration).unwrap(),
     pitch: note.pitch,
+    pitch_bend: 0,
     velocity: 100,
     instrument: FluidsynthDirectlyRenderableMIDIInstrument::pitched (1),
-  }, SAMPLE_HZ, | frames| frames.clone())
+  }, SAMPLE_HZ, | frames| frames.clone()).unwrap_or_else (| error | {
+    eprintln!("Couldn't render note: {}", error);
+    Vec::new().into()
+  })
 }
 
 
@@ -1,7 +1,6 @@
 #[macro_use] extern crate serde_derive;
 
 use codecophony::*;
-use dsp::Frame;
 
 //mod sandbox;
 mod procedural_generation_1;
@@ -18,22 +17,9 @@ fn main() {
   //let (notes,_) = sandbox::current_playground();
   let notes = procedural_generation_1::generate_music();
   
-  let spec = hound::WavSpec {
-      channels: CHANNELS as u16,
-      sample_rate: SAMPLE_HZ as u32,
-      bits_per_sample: 16,
-      sample_format: hound::SampleFormat::Int,
-    };
-    let data = PositionedSequence::<[Output;CHANNELS],Vec<[Output;CHANNELS]>>::rendered_from(&*notes, SAMPLE_HZ);
-    let mut writer = hound::WavWriter::create("interval_optimized.wav", spec).unwrap();
-    {
-    for frame in data.frames.iter() {
-      for sample in frame.channels() {
-        writer.write_sample((sample*2f32.powi(15)) as i16).unwrap();
-      }
-    }
-    }
-    writer.finalize().unwrap();
+    let mut data = PositionedSequence::<[Output;CHANNELS],Vec<[Output;CHANNELS]>>::rendered_from(&*notes, SAMPLE_HZ);
+    mastering::master(&mut data.frames, SAMPLE_HZ, &mastering::MasteringParameters::default());
+    data.write_wav("interval_optimized.wav", &audio_file::WavParameters::default()).unwrap();
   //*/
     
   //sandbox::current_watcher();
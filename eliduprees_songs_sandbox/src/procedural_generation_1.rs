@@ -46,7 +46,7 @@ fn random_timbre <G: Rng> (generator: &mut G)->Timbre {
     /*let frequency: f64 = ((generator.gen::<f64>()*2f64-1f64)+(220f64).ln()).exp();
     let mut amplitude = 0.2*volume*220.0/frequency;
     if amplitude > 0.5*volume { amplitude = 0.5*volume.sqrt(); } 
-    Rc::new(move |time| vec![Box::new(codecophony::SineWave { start: time, duration, frequency, amplitude})])*/
+    Rc::new(move |time| vec![Box::new(codecophony::SineWave::new (time, duration, frequency, amplitude))])*/
     let instrument = generator.gen_range(1, 120);
     let pitch = generator.gen_range(33, 81);
     Timbre::Pitched {instrument, pitch}
@@ -125,15 +125,9 @@ pub fn current_input_playground (input: & HashMap <String, Phrase>) -> (Box<Rend
 
 pub fn current_playground() -> (Box<Renderable<[Output; CHANNELS]> + Send>, Vec<Phrase>) {
   
-  /*let note = codecophony::SineWave {
-    start:0.0, duration:1.0,
-    frequency: 265.0, amplitude: 0.25,
-  };*/
+  /*let note = codecophony::SineWave::new (0.0, 1.0, 265.0, 0.25);*/
   
-  /*let mut notes: Vec<_> = (0..100u32).map(|index| codecophony::SineWave {
-    start: index as f64 * 0.3, duration:1.0,
-    frequency: 220.0, amplitude: 0.1,
-  }).collect();
+  /*let mut notes: Vec<_> = (0..100u32).map(|index| codecophony::SineWave::new (index as f64 * 0.3, 1.0, 220.0, 0.1)).collect();
   
   
   codecophony::interval_optimizer::optimize_notes (&mut notes,
@@ -230,10 +224,7 @@ pub fn current_playground() -> (Box<Renderable<[Output; CHANNELS]> + Send>, Vec<
     while freq > 220.0*(1.0f64 + generator.gen::<f64>() * 5.0f64) { freq /= 2.0; }
     let mut amplitude = timeadvance*0.2*220.0/freq;
     if amplitude > timeadvance*0.5 { amplitude = timeadvance*0.5; } 
-    codecophony::SineWave {
-      start: index as f64 * timeadvance, duration:1.0,
-      frequency: freq, amplitude,
-    }
+    codecophony::SineWave::new (index as f64 * timeadvance, 1.0, freq, amplitude)
   }).collect();*/
   
   
@@ -302,7 +293,7 @@ pub fn current_playground() -> (Box<Renderable<[Output; CHANNELS]> + Send>, Vec<
       let mut amplitude = 0.1*220.0/frequency;
       if amplitude > 0.25 { amplitude = 0.25; } 
       notes.push (
-        Box::new(codecophony::SineWave { start: time as f64, duration: 1.05, frequency, amplitude})
+        Box::new(codecophony::SineWave::new (time as f64, 1.05, frequency, amplitude))
       );
     }
   }*/
@@ -384,7 +375,7 @@ fn random_pattern_timbre (generator: &mut ChaChaRng)->PatternTimbre {
     /*let frequency: f64 = ((generator.gen::<f64>()*2f64-1f64)+(220f64).ln()).exp();
     let mut amplitude = 0.2*volume*220.0/frequency;
     if amplitude > 0.5*volume { amplitude = 0.5*volume.sqrt(); } 
-    Rc::new(move |time| vec![Box::new(codecophony::SineWave { start: time, duration, frequency, amplitude})])*/
+    Rc::new(move |time| vec![Box::new(codecophony::SineWave::new (time, duration, frequency, amplitude))])*/
     let instrument = generator.gen_range(1, 120);
     let pitch = generator.gen_range(33, 81);
     PatternTimbre::Pitched {instrument, pitch}